@@ -0,0 +1,98 @@
+// --check-protocol: log messages that break a few protocol invariants, for compositor developers
+// using the proxy as a test harness. Nothing is enforced, the messages are forwarded as they are.
+//
+// Checked are:
+//
+//   - every wl_display.sync gets its wl_callback.done, in the order the syncs were sent, and
+//     before the callback's wl_display.delete_id. Syncs still unanswered when the connection
+//     closes are reported then, which is only a violation if the client waited for them.
+//   - no message targets an object after its wl_display.delete_id, until the id gets reused for
+//     a new object.
+//
+// Reuse is seen through the tracker's signatures. A message we have no signature for may create
+// an object we can't see, so every deleted id that appears as a word in one is taken as reused.
+// That can hide a violation but doesn't report a false one.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::protocol;
+use crate::stats::DirectionStats;
+use crate::wire::{self, Header, DISPLAY_DELETE_ID, DISPLAY_ID, DISPLAY_SYNC, HEADER_SIZE};
+use crate::{CloseReason, Direction};
+
+#[derive(Default)]
+pub struct Checks {
+    // callbacks of wl_display.sync requests without a done yet, oldest first
+    syncs: VecDeque<u32>,
+    // ids freed by wl_display.delete_id and not reused since
+    deleted: HashSet<u32>,
+}
+
+impl Checks {
+    /// Checks a message before the tracker processes it, `interface` is that of the target object
+    /// if it's known.
+    pub fn inspect(&mut self, conn: u64, dir: Direction, header: Header, interface: Option<&str>, msg: &[u8], stats: &mut DirectionStats) {
+        let mut report = |what: String| {
+            stats.violations += 1;
+            log!("protocol check conn {}: {}", conn, what);
+        };
+        let (sender, kind) = match dir {
+            Direction::ToParent => ("client", "request"),
+            Direction::ToChild => ("compositor", "event"),
+        };
+        if self.deleted.contains(&header.object) {
+            report(format!("{} sent {} #{} to object {} after its wl_display.delete_id", sender, kind, header.opcode, header.object));
+        }
+
+        let arg = wire::arg_u32(msg, HEADER_SIZE);
+        match (dir, interface, header.opcode) {
+            (Direction::ToParent, Some("wl_display"), DISPLAY_SYNC) if header.object == DISPLAY_ID => self.syncs.extend(arg),
+            (Direction::ToChild, Some("wl_callback"), 0) => {
+                if let Some(pos) = self.syncs.iter().position(|&id| id == header.object) {
+                    if pos > 0 {
+                        report(format!("wl_callback.done for sync {} before the one for the earlier sync {}", header.object, self.syncs[0]));
+                    }
+                    self.syncs.remove(pos);
+                }
+            }
+            (Direction::ToChild, Some("wl_display"), DISPLAY_DELETE_ID) => {
+                if let Some(id) = arg {
+                    if let Some(pos) = self.syncs.iter().position(|&sync| sync == id) {
+                        report(format!("wl_display.delete_id of sync callback {}, which never got a done", id));
+                        self.syncs.remove(pos);
+                    }
+                    self.deleted.insert(id);
+                }
+            }
+            _ => {}
+        }
+
+        if self.deleted.is_empty() {
+            return;
+        }
+        let message = interface.and_then(protocol::interface).and_then(|interface| interface.message(dir, header.opcode));
+        match message {
+            Some(message) => {
+                if let Some((id, ..)) = message.created_object(msg) {
+                    self.deleted.remove(&id);
+                }
+            }
+            None => {
+                for word in msg[HEADER_SIZE..].chunks_exact(4) {
+                    self.deleted.remove(&u32::from_ne_bytes(word.try_into().unwrap()));
+                }
+            }
+        }
+    }
+
+    /// Reports the syncs the connection closed without an answer to.
+    pub fn closed(&self, conn: u64, reason: Option<CloseReason>, stats: &mut DirectionStats) {
+        if self.syncs.is_empty() {
+            return;
+        }
+        stats.violations += 1;
+        let reason = reason.map_or("unknown".to_owned(), |reason| reason.to_string());
+        let ids: Vec<String> = self.syncs.iter().map(|id| id.to_string()).collect();
+        log!("protocol check conn {}: closed ({}) with {} wl_display.sync callbacks unanswered: {}", conn, reason, self.syncs.len(), ids.join(", "));
+    }
+}
@@ -0,0 +1,61 @@
+// Where the proxy gets the current time from, so embedders can drive the timeouts, rate limits and
+// backoffs of a Proxy with time they control, e.g. in tests that shouldn't sleep.
+//
+// Every deadline and wait goes through the clock: the connection handling, the exit conditions in
+// `run`, the watchdog, the startup wait for the compositor and the --spin-limit sleeps. Poll still
+// blocks for real, a MockClock wants `Proxy::step` called with a timeout of 0. The --stats time
+// accounting measures how long we actually spent polling and always uses the system clock.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Waits for `duration`, for the few waits that don't go through poll.
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The real time, what the proxy uses unless given another clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to.
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    /// Starts at the current real time, only differences between instants mean anything anyway.
+    pub fn new() -> MockClock {
+        MockClock { now: Mutex::new(Instant::now()) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns right away, having moved the clock along.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,122 @@
+// Config file support for --config.
+//
+// The file is a flat subset of TOML where every key is the name of a command line option:
+//
+//     strict-handshake = true
+//     queue-flush-timeout = 2.5
+//     display-name = "wayland-sandbox"
+//
+// Entries are translated into `--key=value` arguments and parsed in front of the actual command
+// line, so flags given on the command line take precedence. `true` turns into a plain `--key`,
+// `false` is skipped and arrays repeat the option once per element.
+
+use std::path::Path;
+
+pub fn load(path: &Path) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+    to_args(&contents).map_err(|(line, msg)| format!("{}:{}: {}", path.display(), line, msg))
+}
+
+fn to_args(contents: &str) -> Result<Vec<String>, (usize, String)> {
+    let mut args = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err((line_no, "tables are not supported, options go at the top level".to_owned()));
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err((line_no, "expected key = value".to_owned()));
+        };
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err((line_no, format!("invalid key {:?}", key)));
+        }
+        let option = format!("--{}", key.replace('_', "-"));
+
+        let mut values = Vec::new();
+        let rest = parse_value(value.trim(), &mut values).map_err(|msg| (line_no, msg))?;
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err((line_no, format!("unexpected trailing characters {:?}", rest)));
+        }
+
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(option.clone()),
+                Value::Bool(false) => {}
+                Value::Other(v) => args.push(format!("{}={}", option, v)),
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+enum Value {
+    Bool(bool),
+    Other(String),
+}
+
+/// Parses one value (or array of values) from the start of `s` and returns the unparsed rest.
+fn parse_value<'a>(s: &'a str, out: &mut Vec<Value>) -> Result<&'a str, String> {
+    if let Some(mut rest) = s.strip_prefix('[') {
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Ok(rest);
+            }
+            if rest.starts_with('[') {
+                return Err("nested arrays are not supported".to_owned());
+            }
+            rest = parse_value(rest, out)?.trim_start();
+            if let Some(r) = rest.strip_prefix(',') {
+                rest = r;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in array".to_owned());
+            }
+        }
+    }
+
+    if let Some(quoted) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    out.push(Value::Other(value));
+                    return Ok(&quoted[i + 1..]);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c @ ('"' | '\\'))) => value.push(c),
+                    _ => return Err("invalid escape in string".to_owned()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_owned());
+    }
+
+    // bare value: boolean or number
+    let end = s.find(|c: char| c == ',' || c == ']' || c == '#' || c.is_whitespace()).unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    match word {
+        "true" => out.push(Value::Bool(true)),
+        "false" => out.push(Value::Bool(false)),
+        w => {
+            let number = w.replace('_', "");
+            if number.parse::<f64>().is_err() {
+                return Err(format!("invalid value {:?}, strings must be quoted", w));
+            }
+            out.push(Value::Other(number))
+        }
+    }
+    Ok(rest)
+}
@@ -0,0 +1,305 @@
+// --control-socket: a unix socket accepting line based commands to inspect and poke at the
+// running proxy, e.g. with `socat - UNIX-CONNECT:PATH`.
+//
+//     list          one line per connection
+//     connections   the number of open connections, e.g. for a liveness probe
+//     closes        how many connections were closed for which reason
+//     times         seconds spent in poll and handling what it returned, see Stats::polling
+//     metrics       the --latency-histogram in the OpenMetrics text format, which ends in `# EOF`
+//     pause ID      stop reading from both sides of connection ID, queued data is still delivered
+//     resume ID     undo pause
+//     close ID      stop reading from connection ID, deliver what's queued, then shut it down. Gives
+//                   up on the queues after EVICT_TIMEOUT in the main module
+//     objects ID    the live objects of connection ID as `id interface version`, needs message
+//                   framing, e.g. --message-framed
+//
+// Every command is answered with `ok` or `error: ...` as its last line, except for a successful
+// `metrics`: OpenMetrics allows nothing after its `# EOF`, which ends the reply instead.
+//
+// Control clients can't hold up the proxy: lines are limited to MAX_LINE bytes, clients that send
+// nothing for IDLE_TIMEOUT or don't take their replies for as long are dropped, and at most
+// MAX_CLIENTS are served at once. A reply that doesn't fit into the socket buffer is queued and
+// sent as the client reads, further commands wait until it went out in full.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use rustix::event::PollFlags;
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::fs::unlink;
+use rustix::io::{read, retry_on_intr, write, Errno};
+use rustix::net::{accept_with, bind_unix, listen, socket_with, AddressFamily, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::ProxyError;
+
+const MAX_LINE: usize = 4096;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_CLIENTS: usize = 16;
+
+pub enum Command {
+    List,
+    Connections,
+    Closes,
+    Times,
+    Metrics,
+    Pause(u64),
+    Resume(u64),
+    Close(u64),
+    Objects(u64),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let mut id = || -> Result<u64, String> {
+            let id = words.next().ok_or_else(|| format!("{} requires a connection id", command))?;
+            id.parse().map_err(|_| format!("invalid connection id {}", id))
+        };
+        let parsed = match command {
+            "list" => Command::List,
+            "connections" => Command::Connections,
+            "closes" => Command::Closes,
+            "times" => Command::Times,
+            "metrics" => Command::Metrics,
+            "pause" => Command::Pause(id()?),
+            "resume" => Command::Resume(id()?),
+            "close" => Command::Close(id()?),
+            "objects" => Command::Objects(id()?),
+            _ => return Err(format!("unknown command {:?}", command)),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected argument {:?}", extra)),
+            None => Ok(parsed),
+        }
+    }
+
+    /// Whether a successful reply ends in a marker of its own rather than `ok`.
+    fn ends_itself(&self) -> bool {
+        matches!(self, Command::Metrics)
+    }
+}
+
+pub struct Control {
+    socket: OwnedFd,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    fd: OwnedFd,
+    // bytes of an incomplete line, or of lines waiting for the output to drain
+    input: Vec<u8>,
+    // the unsent part of the replies
+    output: Vec<u8>,
+    // when the client last sent something or took some of its output
+    last_active: Instant,
+}
+
+impl Control {
+    pub fn bind(path: &Path) -> Result<Control, ProxyError> {
+        let socket = listen_unix(path, "control socket")?;
+        Ok(Control { socket, path: path.to_owned(), clients: Vec::new() })
+    }
+
+    /// The listening socket followed by one entry per control client, polled for writing while
+    /// it has output queued and for reading otherwise.
+    pub fn poll_fds(&self) -> impl Iterator<Item = (BorrowedFd<'_>, PollFlags)> {
+        let clients = self.clients.iter().map(|c| (c.fd.as_fd(), if c.output.is_empty() { PollFlags::IN } else { PollFlags::OUT }));
+        std::iter::once((self.socket.as_fd(), PollFlags::IN)).chain(clients)
+    }
+
+    /// When the next idle client should be dropped, for the poll timeout.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.clients.iter().map(|c| c.last_active + IDLE_TIMEOUT).min()
+    }
+
+    /// Reads and answers commands, `flags` are the revents for [`Control::poll_fds`].
+    pub fn handle(&mut self, flags: &[PollFlags], now: Instant, mut execute: impl FnMut(Command) -> Result<String, String>) {
+        let mut buf = [0u8; 1024];
+        let mut closed = Vec::new();
+
+        for (i, (client, flags)) in self.clients.iter_mut().zip(&flags[1..]).enumerate() {
+            if !client.output.is_empty() {
+                if !flags.intersects(PollFlags::OUT | PollFlags::HUP | PollFlags::ERR) {
+                    if now >= client.last_active + IDLE_TIMEOUT {
+                        closed.push(i);
+                    }
+                    continue;
+                }
+                match client.flush() {
+                    Ok(true) => client.last_active = now,
+                    Ok(false) => {}
+                    Err(_) => {
+                        closed.push(i);
+                        continue;
+                    }
+                }
+            } else if flags.intersects(PollFlags::IN | PollFlags::HUP | PollFlags::ERR) {
+                match retry_on_intr(|| read(&client.fd, &mut buf)) {
+                    Ok(n) if n > 0 => client.input.extend_from_slice(&buf[..n]),
+                    Err(Errno::AGAIN) => continue,
+                    _ => {
+                        closed.push(i);
+                        continue;
+                    }
+                }
+                client.last_active = now;
+            } else {
+                if now >= client.last_active + IDLE_TIMEOUT {
+                    client.last_words("error: idle for too long\n");
+                    closed.push(i);
+                }
+                continue;
+            }
+
+            // one reply at a time, the rest waits in the input until it went out
+            while client.output.is_empty() {
+                let Some(end) = client.input.iter().position(|&b| b == b'\n') else {
+                    break;
+                };
+                let line: Vec<u8> = client.input.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let reply = Command::parse(line.trim()).and_then(|command| match command.ends_itself() {
+                    true => execute(command),
+                    false => execute(command).map(|output| format!("{}ok\n", output)),
+                });
+                let reply = reply.unwrap_or_else(|msg| format!("error: {}\n", msg));
+                client.output = reply.into_bytes();
+                if client.flush().is_err() {
+                    closed.push(i);
+                    break;
+                }
+            }
+            if client.input.len() > MAX_LINE && !client.input.contains(&b'\n') && closed.last() != Some(&i) {
+                client.last_words(&format!("error: line longer than {} bytes\n", MAX_LINE));
+                closed.push(i);
+            }
+        }
+
+        for i in closed.into_iter().rev() {
+            self.clients.remove(i);
+        }
+
+        if flags[0].contains(PollFlags::IN) {
+            while let Ok(fd) = accept_with(&self.socket, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK) {
+                let client = Client { fd, input: Vec::new(), output: Vec::new(), last_active: now };
+                if self.clients.len() >= MAX_CLIENTS {
+                    client.last_words(&format!("error: more than {} control clients\n", MAX_CLIENTS));
+                    continue;
+                }
+                self.clients.push(client);
+            }
+        }
+    }
+}
+
+// unlinks the socket like a Listener does
+impl Drop for Control {
+    fn drop(&mut self) {
+        if let Err(e) = unlink(&self.path) {
+            log!("warning: failed to unlink {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A non-blocking listening socket at `path` for a few local tools, `what` it is for messages.
+pub fn listen_unix(path: &Path, what: &str) -> Result<OwnedFd, ProxyError> {
+    let context = || path.display().to_string();
+    let socket = socket_with(AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None).map_err(|errno| ProxyError::Bind { context: context(), errno })?;
+    let addr = SocketAddrUnix::new(path).map_err(|_| ProxyError::Parse { context: format!("invalid {} path {}", what, context()) })?;
+    bind_unix(&socket, &addr).map_err(|errno| ProxyError::Bind { context: context(), errno })?;
+    listen(&socket, 8).map_err(|errno| ProxyError::Listen { context: context(), errno })?;
+    Ok(socket)
+}
+
+impl Client {
+    /// Sends as much of the queued output as the socket takes without blocking, returns whether
+    /// any of it went out.
+    fn flush(&mut self) -> Result<bool, Errno> {
+        let mut sent = 0;
+        while sent < self.output.len() {
+            match retry_on_intr(|| write(&self.fd, &self.output[sent..])) {
+                Ok(n) => sent += n,
+                Err(Errno::AGAIN) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.output.drain(..sent);
+        Ok(sent > 0)
+    }
+
+    /// A last message before the client is dropped, whatever doesn't fit into the socket buffer is
+    /// lost.
+    fn last_words(&self, message: &str) {
+        let _ = write(&self.fd, message.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    use rustix::net::sockopt::set_socket_send_buffer_size;
+
+    use super::*;
+
+    #[test]
+    fn reply_larger_than_the_socket_buffer() {
+        let path = std::env::temp_dir().join(format!("p5wl-control-test-{}", std::process::id()));
+        let mut control = Control::bind(&path).expect("bind");
+        let mut client = UnixStream::connect(&path).expect("connect");
+        let now = Instant::now();
+        control.handle(&[PollFlags::IN], now, |_| unreachable!());
+        set_socket_send_buffer_size(&control.clients[0].fd, 4096).expect("SO_SNDBUF");
+
+        // the second command has to wait for the first reply to go out
+        let big = "x".repeat(1 << 20) + "\n";
+        client.write_all(b"list\nconnections\n").expect("write");
+        let mut execute = |command| match command {
+            Command::List => Ok(big.clone()),
+            Command::Connections => Ok("0\n".to_owned()),
+            _ => unreachable!(),
+        };
+        control.handle(&[PollFlags::empty(), PollFlags::IN], now, &mut execute);
+        assert_eq!(control.poll_fds().nth(1).map(|(_, flags)| flags), Some(PollFlags::OUT));
+
+        client.set_nonblocking(true).expect("nonblocking");
+        let expected = format!("{}ok\n0\nok\n", big);
+        let mut received = Vec::new();
+        let mut buf = vec![0; 64 << 10];
+        while received.len() < expected.len() {
+            match client.read(&mut buf) {
+                Ok(0) => panic!("control client dropped after {} bytes", received.len()),
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock),
+            }
+            let flags: Vec<PollFlags> = control.poll_fds().map(|(_, flags)| flags & !PollFlags::IN).collect();
+            control.handle(&flags, now, &mut execute);
+            assert_eq!(control.clients.len(), 1);
+        }
+        assert!(received == expected.as_bytes());
+        assert_eq!(control.poll_fds().nth(1).map(|(_, flags)| flags), Some(PollFlags::IN));
+    }
+
+    #[test]
+    fn metrics_end_at_eof() {
+        let path = std::env::temp_dir().join(format!("p5wl-control-metrics-test-{}", std::process::id()));
+        let mut control = Control::bind(&path).expect("bind");
+        let mut client = UnixStream::connect(&path).expect("connect");
+        let now = Instant::now();
+        control.handle(&[PollFlags::IN], now, |_| unreachable!());
+
+        client.write_all(b"metrics\nmetrics\n").expect("write");
+        let mut histogram = true;
+        control.handle(&[PollFlags::empty(), PollFlags::IN], now, |command| {
+            assert!(matches!(command, Command::Metrics));
+            std::mem::replace(&mut histogram, false).then(|| "# EOF\n".to_owned()).ok_or_else(|| "off".to_owned())
+        });
+        drop(control);
+        let mut received = String::new();
+        client.read_to_string(&mut received).expect("read");
+        assert_eq!(received, "# EOF\nerror: off\n");
+    }
+}
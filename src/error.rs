@@ -0,0 +1,81 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use rustix::io::Errno;
+
+#[derive(Debug)]
+pub enum ProxyError {
+    /// A required environment variable is not set.
+    Env { var: &'static str },
+    Bind { context: String, errno: Errno },
+    Listen { context: String, errno: Errno },
+    Accept { context: String, errno: Errno },
+    /// The compositor, or a --parent-failover or --route target, couldn't be reached.
+    Connect { context: String, errno: Errno },
+    /// Connecting to a local socket other than the compositor's failed: journald, /dev/log or our
+    /// own for --socket-env.
+    ConnectLocal { context: String, errno: Errno },
+    /// The compositor path exists but isn't a socket.
+    NotASocket { path: PathBuf, kind: &'static str },
+    Recv { context: String, errno: Errno },
+    Send { context: String, errno: Errno },
+    Poll { errno: Errno },
+    Spawn { command: String, error: std::io::Error },
+    /// Setting up the pseudo-terminal for --pty failed.
+    Pty { errno: Errno },
+    /// Invalid input such as an unusable socket path.
+    Parse { context: String },
+    /// Something the options point at couldn't be used, e.g. a --log-file that can't be opened.
+    Setup { context: String, errno: Errno },
+    /// Preparing --unshare or --cgroup for the child failed.
+    Isolate { context: String, errno: Errno },
+    /// Watching the child for --deny-new-connections-after-child-exit failed.
+    Pidfd { errno: Errno },
+    /// Setting up the SIGHUP signalfd failed.
+    Signal { errno: Errno },
+    /// Setting up or writing to --status-fd failed.
+    Status { errno: Errno },
+    /// --expect-connection-fatal: nobody connected within --expect-connection-timeout.
+    NoConnection { timeout: std::time::Duration },
+    /// --self-test got back something other than what it sent.
+    SelfTest { reason: String },
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Env { var } => write!(f, "{} not set", var),
+            ProxyError::Bind { context, errno } => write!(f, "failed to bind {}: {}", context, errno),
+            ProxyError::Listen { context, errno } => write!(f, "failed to listen on {}: {}", context, errno),
+            ProxyError::Accept { context, errno } => write!(f, "failed to accept on {}: {}", context, errno),
+            ProxyError::Connect { context, errno } | ProxyError::ConnectLocal { context, errno } => write!(f, "failed to connect to {}: {}", context, errno),
+            ProxyError::NotASocket { path, kind } => write!(f, "compositor path {} is a {}, not a socket", path.display(), kind),
+            ProxyError::Recv { context, errno } => write!(f, "failed to receive from {}: {}", context, errno),
+            ProxyError::Send { context, errno } => write!(f, "failed to send to {}: {}", context, errno),
+            ProxyError::Poll { errno } => write!(f, "poll failed: {}", errno),
+            ProxyError::Spawn { command, error } => match error.kind() {
+                std::io::ErrorKind::NotFound => write!(f, "command not found: {}", command),
+                std::io::ErrorKind::PermissionDenied => write!(f, "permission denied executing {}: {}", command, error),
+                _ => write!(f, "failed to execute {}: {}", command, error),
+            },
+            ProxyError::Pty { errno } => write!(f, "failed to set up pty: {}", errno),
+            ProxyError::Parse { context } => write!(f, "{}", context),
+            ProxyError::Setup { context, errno } => write!(f, "failed to set up {}: {}", context, errno),
+            ProxyError::Isolate { context, errno } => write!(f, "{}: {}", context, errno),
+            ProxyError::Pidfd { errno } => write!(f, "failed to open a pidfd for the child: {}", errno),
+            ProxyError::Signal { errno } => write!(f, "failed to set up SIGHUP handling: {}", errno),
+            ProxyError::Status { errno } => write!(f, "failed to write the --status-fd line: {}", errno),
+            ProxyError::NoConnection { timeout } => write!(f, "the child did not connect within {:?}", timeout),
+            ProxyError::SelfTest { reason } => write!(f, "self-test failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::Spawn { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// Describing fds passed over a connection, for --trace-fds.
+
+use std::os::fd::{AsRawFd, BorrowedFd};
+
+use rustix::fs::{fstat, FileType};
+
+/// Short human readable description of the fd type and size, e.g. `memfd 65536 bytes`.
+pub fn describe(fd: BorrowedFd<'_>) -> String {
+    let stat = match fstat(fd) {
+        Ok(stat) => stat,
+        Err(e) => return format!("fstat failed: {}", e),
+    };
+    // memfds are regular files on tmpfs and anonymous inodes have no file type at all, the
+    // /proc link target is the only way to tell them apart
+    let target = std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).map(|t| t.to_string_lossy().into_owned()).unwrap_or_default();
+
+    if is_dmabuf(&target) {
+        return describe_dmabuf(fd, stat.st_size);
+    }
+
+    let kind = match FileType::from_raw_mode(stat.st_mode) {
+        FileType::RegularFile if target.starts_with("/memfd:") => "memfd",
+        FileType::RegularFile => "file",
+        FileType::Socket => "socket",
+        FileType::Fifo => "pipe",
+        FileType::CharacterDevice | FileType::BlockDevice => "device",
+        FileType::Directory => "directory",
+        _ => "other",
+    };
+
+    format!("{} {} bytes ({})", kind, stat.st_size, target)
+}
+
+// dmabufs are anonymous inodes, named `/dmabuf:NAME` since Linux 5.3 and `anon_inode:dmabuf`
+// before
+fn is_dmabuf(target: &str) -> bool {
+    target.starts_with("/dmabuf:") || target == "anon_inode:dmabuf"
+}
+
+/// The size and the exporting driver and buffer name, which the kernel only exposes through
+/// /proc/self/fdinfo, e.g. `dmabuf 8294400 bytes, exporter i915, name "cursor"`.
+fn describe_dmabuf(fd: BorrowedFd<'_>, size: i64) -> String {
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd())).unwrap_or_default();
+    let field = |name: &str| fdinfo.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':')).map(str::trim).filter(|value| !value.is_empty());
+
+    let mut description = format!("dmabuf {} bytes", field("size").unwrap_or(&size.to_string()));
+    if let Some(exporter) = field("exp_name") {
+        description += &format!(", exporter {}", exporter);
+    }
+    if let Some(name) = field("name") {
+        description += &format!(", name {:?}", name);
+    }
+    description
+}
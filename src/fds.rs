@@ -0,0 +1,106 @@
+// Accounting of fds received over SCM_RIGHTS that haven't been passed on yet.
+//
+// Every received fd has to end up either forwarded or explicitly discarded, debug builds assert that
+// none get lost by simply dropping them. Closing our copy after sendmsg is fine since the
+// receiver has its own by then, so the fds are never duplicated.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rustix::fd::OwnedFd;
+
+/// The kernel's limit of fds per SCM_RIGHTS message, which all control buffers are sized for.
+///
+/// Linux has had 253 for a long time. Should a kernel ever accept more, a sender passing that many
+/// at once gets its connection closed, see [`truncated`].
+pub const SCM_MAX_FD: usize = 253;
+
+/// Whether the kernel had to cut off the control data of a recvmsg. The fds that didn't fit are
+/// closed by the kernel, so the messages they belong to can't be forwarded intact.
+pub fn truncated(flags: rustix::net::RecvFlags) -> bool {
+    // rustix doesn't name MSG_CTRUNC but keeps the bit
+    flags.bits() & libc::MSG_CTRUNC as u32 != 0
+}
+
+// process-global so every place fds get dropped is covered without threading a counter around
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of fds currently held by the proxy on behalf of its connections.
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/// Received fds, counted towards [`in_flight`] until they're dropped.
+#[derive(Default)]
+pub struct InFlightFds(Vec<OwnedFd>);
+
+impl InFlightFds {
+    pub fn extend(&mut self, fds: impl IntoIterator<Item = OwnedFd>) {
+        let before = self.0.len();
+        self.0.extend(fds);
+        IN_FLIGHT.fetch_add(self.0.len() - before, Ordering::Relaxed);
+    }
+
+    /// Moves all of `other`'s fds to the end of this set.
+    pub fn append(&mut self, other: &mut InFlightFds) {
+        // the fds stay in flight, no need to touch the counter
+        self.0.append(&mut other.0);
+    }
+
+    /// The fds went out with sendmsg, closes our copies.
+    pub fn forwarded(&mut self) {
+        self.release();
+    }
+
+    /// The fds won't be delivered, e.g. because the connection closed.
+    pub fn discard(&mut self) {
+        self.release();
+    }
+
+    fn release(&mut self) {
+        IN_FLIGHT.fetch_sub(self.0.len(), Ordering::Relaxed);
+        self.0.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, OwnedFd> {
+        self.0.iter()
+    }
+}
+
+impl Drop for InFlightFds {
+    fn drop(&mut self) {
+        debug_assert!(self.0.is_empty() || std::thread::panicking(), "{} received fds dropped without being forwarded or discarded", self.0.len());
+        self.release();
+    }
+}
+
+/// Lets one warning through per interval and counts the ones it swallowed in between.
+pub struct RateLimit {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    pub fn new(interval: Duration) -> RateLimit {
+        RateLimit { interval, last: None, suppressed: 0 }
+    }
+
+    /// `Some(n)` if the caller may log now, with `n` warnings suppressed since the last one.
+    pub fn allow(&mut self, now: Instant) -> Option<u64> {
+        if self.last.is_some_and(|last| now.duration_since(last) < self.interval) {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
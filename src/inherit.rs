@@ -0,0 +1,118 @@
+// Which of our fds the child inherits. Everything the proxy opens is CLOEXEC, fds that should
+// reach the child are collected in a ChildFds and only made inheritable in the forked child, by a
+// single pre_exec hook, so this is the one place to check what leaks into it. With --verbose the
+// full list is logged before spawning, including fds we inherited without CLOEXEC ourselves and
+// pass on untouched.
+//
+// --socket-env VAR[=VALUE]: hand the child a connection to the proxy socket as an inherited fd
+// named in its environment, instead of pointing WAYLAND_DISPLAY at the proxy. WAYLAND_DISPLAY is
+// left as we got it, so whatever doesn't use VAR, like programs the child starts, talks to the
+// compositor directly.
+//
+// VAR is set to VALUE with {fd} replaced by the fd number, just the number by default.
+// `--socket-env WAYLAND_SOCKET` works with every libwayland client, which covers GTK, Qt, SDL,
+// Xwayland and most others. libwayland unsets it once it took over the fd, keeping it from the
+// client's own children. Any other name is for clients or toolkit wrappers documented to take a
+// wayland connection from that variable, everything else ignores it.
+//
+// We connect to our own proxy socket for the child, so the connection gets accepted and forwarded
+// like any other. Its peer credentials are then ours rather than the child's, which --route and
+// --max-connections-per-uid see.
+//
+// --no-cloexec-for-child N[,N...]: the child also inherits our fds N, even those an option like
+// --status-fd took over and marked CLOEXEC, for testing fd inheritance.
+
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use rustix::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use rustix::io::{fcntl_getfd, fcntl_setfd, FdFlags};
+use rustix::net::{connect_unix, socket_with, AddressFamily, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::ProxyError;
+
+/// Parses --socket-env VAR[=VALUE] into the variable and its value template.
+pub fn parse_socket_env(arg: &str) -> Result<(String, String), String> {
+    let (var, value) = arg.split_once('=').unwrap_or((arg, "{fd}"));
+    if var.is_empty() || var.contains('\0') || value.contains('\0') {
+        return Err(format!("invalid --socket-env {}", arg));
+    }
+    Ok((var.to_owned(), value.to_owned()))
+}
+
+/// The fds the child gets beyond stdio and what it gets them for.
+#[derive(Default)]
+pub struct ChildFds {
+    // opened for the child, our copies close when the command is dropped after the spawn
+    owned: Vec<(OwnedFd, &'static str)>,
+    // ours, they stay open for us
+    kept: Vec<(RawFd, &'static str)>,
+}
+
+impl ChildFds {
+    /// Connects to the proxy socket at `path` for the child, with `var` set to `value` naming
+    /// the connection in `cmd`'s environment.
+    pub fn socket_env(&mut self, cmd: &mut Command, path: &Path, socket_type: SocketType, var: &str, value: &str) -> Result<(), ProxyError> {
+        let err = |errno| ProxyError::ConnectLocal { context: format!("{} for --socket-env", path.display()), errno };
+        let fd = socket_with(AddressFamily::UNIX, socket_type, SocketFlags::CLOEXEC, None).map_err(err)?;
+        let addr = SocketAddrUnix::new(path).map_err(err)?;
+        connect_unix(&fd, &addr).map_err(err)?;
+        cmd.env(var, value.replace("{fd}", &fd.as_raw_fd().to_string()));
+        self.owned.push((fd, "--socket-env"));
+        Ok(())
+    }
+
+    /// Has the child inherit our fd `fd` for --no-cloexec-for-child.
+    pub fn keep(&mut self, fd: RawFd) -> Result<(), ProxyError> {
+        // SAFETY: only used to check that it's open
+        if fd < 0 || fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) }).is_err() {
+            return Err(ProxyError::Parse { context: format!("--no-cloexec-for-child {} is not an open fd", fd) });
+        }
+        self.kept.push((fd, "--no-cloexec-for-child"));
+        Ok(())
+    }
+
+    /// Installs the hook clearing CLOEXEC on the collected fds in the child, `verbose` logs every
+    /// fd it will inherit first.
+    pub fn apply(self, cmd: &mut Command, verbose: bool) {
+        let fds: Vec<(RawFd, &str)> = self.owned.iter().map(|(fd, why)| (fd.as_raw_fd(), *why)).chain(self.kept.iter().copied()).collect();
+        if verbose {
+            let mut all: Vec<String> = fds.iter().map(|(fd, why)| format!("{} ({})", fd, why)).collect();
+            all.extend(inheritable().into_iter().filter(|fd| !fds.iter().any(|(ours, _)| ours == fd)).map(|fd| format!("{} (inherited by us without CLOEXEC)", fd)));
+            match all.is_empty() {
+                true => log!("the child inherits no fds beyond stdio"),
+                false => log!("the child inherits fds beyond stdio: {}", all.join(", ")),
+            }
+        }
+        if fds.is_empty() {
+            return;
+        }
+        let raw: Vec<RawFd> = fds.iter().map(|(fd, _)| *fd).collect();
+        // the hook keeps the owned fds open until the command is dropped
+        let owned = self.owned;
+        // SAFETY: fcntl is async-signal-safe and the fds stay open, `owned` by the hook itself and
+        // the kept ones by the rest of the proxy
+        unsafe {
+            cmd.pre_exec(move || {
+                let _ = &owned;
+                for &fd in &raw {
+                    fcntl_setfd(BorrowedFd::borrow_raw(fd), FdFlags::empty())?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Our fds other than stdio without CLOEXEC, which every child gets.
+fn inheritable() -> Vec<RawFd> {
+    let Ok(dir) = std::fs::read_dir("/proc/self/fd") else {
+        return Vec::new();
+    };
+    let mut fds: Vec<RawFd> = dir.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).filter(|&fd| fd > 2).collect();
+    // SAFETY: only used to read the flags, the fd of the directory listing is gone by now
+    fds.retain(|&fd| fcntl_getfd(unsafe { BorrowedFd::borrow_raw(fd) }).is_ok_and(|flags| !flags.contains(FdFlags::CLOEXEC)));
+    fds.sort_unstable();
+    fds
+}
@@ -0,0 +1,96 @@
+// --unshare and --cgroup: start the child in new namespaces and/or a given cgroup.
+//
+// Everything that can fail in an interesting way or needs to allocate happens before the fork,
+// the pre_exec hook only issues syscalls on prepared buffers.
+
+use std::ffi::CStr;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use rustix::fd::OwnedFd;
+use rustix::fs::{open, Mode, OFlags};
+use rustix::io::write;
+use rustix::process::{getgid, getpid, getuid};
+use rustix::thread::{unshare, UnshareFlags};
+
+use crate::ProxyError;
+
+/// Parses the comma separated namespace list of --unshare.
+pub fn parse_namespaces(list: &str) -> Result<UnshareFlags, String> {
+    let mut flags = UnshareFlags::empty();
+    for name in list.split(',') {
+        flags |= match name {
+            "user" => UnshareFlags::NEWUSER,
+            "mount" => UnshareFlags::NEWNS,
+            "ipc" => UnshareFlags::NEWIPC,
+            "uts" => UnshareFlags::NEWUTS,
+            "net" => UnshareFlags::NEWNET,
+            "cgroup" => UnshareFlags::NEWCGROUP,
+            _ => return Err(format!("unknown namespace {:?} for --unshare, expected user, mount, ipc, uts, net or cgroup", name)),
+        };
+    }
+    Ok(flags)
+}
+
+pub fn apply(cmd: &mut Command, namespaces: UnshareFlags, cgroup: Option<&Path>) -> Result<(), ProxyError> {
+    let cgroup_procs = match cgroup {
+        Some(path) => {
+            let procs = path.join("cgroup.procs");
+            let fd = open(&procs, OFlags::WRONLY | OFlags::CLOEXEC, Mode::empty()).map_err(|errno| ProxyError::Isolate { context: format!("failed to open {}", procs.display()), errno })?;
+            Some(fd)
+        }
+        None => None,
+    };
+    // map our own ids into the new user namespace so the child keeps its identity, unprivileged
+    // processes may map exactly that one id
+    let id_maps = namespaces.contains(UnshareFlags::NEWUSER).then(|| (format!("{0} {0} 1", getuid().as_raw()), format!("{0} {0} 1", getgid().as_raw())));
+
+    // SAFETY: only async-signal-safe syscalls on memory prepared before the fork
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(fd) = &cgroup_procs {
+                join_cgroup(fd).map_err(|errno| fail(c"p5wl: failed to move the child into the --cgroup\n", errno))?;
+            }
+            if !namespaces.is_empty() {
+                unshare(namespaces).map_err(|errno| fail(c"p5wl: unshare for --unshare failed\n", errno))?;
+            }
+            if let Some((uid_map, gid_map)) = &id_maps {
+                // gid_map can only be written by unprivileged processes once setgroups is denied
+                write_file(c"/proc/self/setgroups", b"deny")
+                    .and_then(|()| write_file(c"/proc/self/uid_map", uid_map.as_bytes()))
+                    .and_then(|()| write_file(c"/proc/self/gid_map", gid_map.as_bytes()))
+                    .map_err(|errno| fail(c"p5wl: failed to set up the id mapping of the new user namespace\n", errno))?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+fn join_cgroup(procs: &OwnedFd) -> rustix::io::Result<()> {
+    // format our pid without allocating
+    let mut pid = rustix::process::Pid::as_raw(Some(getpid())) as u32;
+    let mut buf = [0u8; 10];
+    let mut start = buf.len();
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (pid % 10) as u8;
+        pid /= 10;
+        if pid == 0 {
+            break;
+        }
+    }
+    write(procs, &buf[start..]).map(drop)
+}
+
+fn write_file(path: &CStr, contents: &[u8]) -> rustix::io::Result<()> {
+    let fd = open(path, OFlags::WRONLY | OFlags::CLOEXEC, Mode::empty())?;
+    write(&fd, contents).map(drop)
+}
+
+/// Says what went wrong before Command reports the bare errno as an exec failure.
+fn fail(msg: &CStr, errno: rustix::io::Errno) -> std::io::Error {
+    let _ = write(rustix::stdio::stderr(), msg.to_bytes());
+    errno.into()
+}
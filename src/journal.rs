@@ -0,0 +1,72 @@
+// --journald: log lines go to the systemd journal as structured entries instead of stderr, using
+// the native protocol on /run/systemd/journal/socket.
+//
+// Each line is one datagram of FIELD=value lines. Besides MESSAGE there is PRIORITY, warning for
+// lines starting with "warning:" and info for the rest, and WEYLAND_CONN for lines about one
+// connection, so `journalctl WEYLAND_CONN=3` shows the history of connection 3. Values containing
+// a newline use the protocol's length prefixed form. Entries too large for a datagram are lost,
+// the journal expects those to be passed in a memfd, which isn't worth it for log lines.
+
+use std::mem;
+
+use rustix::fd::OwnedFd;
+use rustix::net::{connect_unix, send, socket_with, AddressFamily, SendFlags, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::ProxyError;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+// syslog severities
+const PRIORITY_WARNING: u32 = 4;
+const PRIORITY_INFO: u32 = 6;
+
+pub struct Journal {
+    socket: OwnedFd,
+    failed: bool,
+}
+
+impl Journal {
+    pub fn connect() -> Result<Journal, ProxyError> {
+        let err = |errno| ProxyError::ConnectLocal { context: JOURNAL_SOCKET.to_owned(), errno };
+        let socket = socket_with(AddressFamily::UNIX, SocketType::DGRAM, SocketFlags::CLOEXEC, None).map_err(err)?;
+        connect_unix(&socket, &SocketAddrUnix::new(JOURNAL_SOCKET).map_err(err)?).map_err(err)?;
+        Ok(Journal { socket, failed: false })
+    }
+
+    /// Sends one log line, `prefix` is the --log-prefix. Returns false the first time sending
+    /// fails, for a warning on stderr.
+    pub fn log(&mut self, prefix: &str, line: &str) -> bool {
+        let priority = if line.starts_with("warning:") { PRIORITY_WARNING } else { PRIORITY_INFO };
+        let mut entry = Vec::new();
+        field(&mut entry, "MESSAGE", &format!("{}{}", prefix, line));
+        field(&mut entry, "PRIORITY", &priority.to_string());
+        field(&mut entry, "SYSLOG_IDENTIFIER", "p5wl");
+        if let Some(id) = conn_id(line) {
+            field(&mut entry, "WEYLAND_CONN", id);
+        }
+        // like --syslog, a journal that doesn't keep up loses messages rather than blocking us
+        match send(&self.socket, &entry, SendFlags::DONTWAIT | SendFlags::NOSIGNAL) {
+            Ok(_) => true,
+            Err(_) => !mem::replace(&mut self.failed, true),
+        }
+    }
+}
+
+fn field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// The id of the connection a line is about, from the "conn N" all such lines contain.
+fn conn_id(line: &str) -> Option<&str> {
+    let mut words = line.split_whitespace();
+    words.find(|&word| word == "conn")?;
+    let id = words.next()?.trim_end_matches(':');
+    (!id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())).then_some(id)
+}
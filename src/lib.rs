@@ -0,0 +1,2233 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::{HashSet, VecDeque};
+use std::io::IoSlice;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::{path::PathBuf, io::IoSliceMut};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustix::net::{socket_with, SocketFlags, RecvFlags, SocketType, AddressFamily, RecvAncillaryBuffer, sendmsg, SendAncillaryMessage, SendFlags, RecvAncillaryMessage};
+use rustix::fs::{fcntl_getfl, fcntl_setfl, OFlags};
+use rustix::{event::{poll, PollFd, PollFlags}, fd::{OwnedFd, AsFd, BorrowedFd}, net::{SendAncillaryBuffer, recvmsg}, io::{Errno, retry_on_intr}};
+
+pub use clock::{Clock, MockClock, SystemClock};
+pub use error::ProxyError;
+pub use log::prefix as log_prefix;
+pub use options::Options;
+pub use selftest::self_test;
+use control::Control;
+use fds::{InFlightFds, RateLimit, SCM_MAX_FD};
+use listener::{Listener, Protocol};
+use peer::Peer;
+use reconnect::{Outage, Replay};
+use recording::Recorder;
+use stats::{DirectionStats, Stats};
+use syslog::Syslog;
+use tracesocket::TraceSocket;
+use tracker::Tracker;
+use watchdog::Watchdog;
+use wire::{Handshake, HandshakeCheck};
+
+/// eprintln! with the --log-prefix in front and to the --log-file if there is one, for everything
+/// we log.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::write(format_args!($($arg)*))
+    };
+}
+
+mod check;
+mod clock;
+mod config;
+mod control;
+mod error;
+mod fdinfo;
+mod fds;
+mod inherit;
+mod isolate;
+mod journal;
+mod listener;
+mod log;
+mod lz4;
+mod metrics;
+mod options;
+mod parent;
+mod peer;
+mod protocol;
+mod priority;
+mod pty;
+mod reconnect;
+mod recording;
+mod route;
+mod seccomp;
+mod selftest;
+mod signals;
+mod stats;
+mod status;
+mod trace;
+mod syslog;
+mod tracesocket;
+mod tracker;
+mod watchdog;
+mod wire;
+
+/// Binds the proxy socket, spawns the child and forwards wayland (and with `--x11-display` X11)
+/// connections until the child has exited and all connections are closed (or forever with
+/// `--persist`). With `--no-child` connections are served until `--idle-timeout` or forever.
+pub fn run(opts: &Options) -> Result<(), ProxyError> {
+    run_with_clock(opts, Arc::new(SystemClock))
+}
+
+/// Like [`run`], with the timeouts following `clock`.
+pub fn run_with_clock(opts: &Options, clock: Arc<dyn Clock>) -> Result<(), ProxyError> {
+    let program = opts.command.first().map(|command| command.rsplit('/').next().unwrap_or(command));
+    log::set_prefix(opts.log_prefix.as_deref().or(program).unwrap_or(""));
+    signals::ignore_sigpipe();
+    if let Some(path) = &opts.log_file {
+        log::set_file(path).map_err(|e| ProxyError::Setup { context: format!("--log-file {}", path.display()), errno: Errno::from_io_error(&e).unwrap_or(Errno::IO) })?;
+    }
+    if opts.journald {
+        log::set_journal(journal::Journal::connect()?);
+    }
+    let status_fd = opts.status_fd.map(status::StatusFd::adopt).transpose()?;
+    let liveness = opts.liveness_fd.map(|fd| status::adopt_fd(fd, "--liveness-fd", |errno| ProxyError::Setup { context: "--liveness-fd".to_owned(), errno })).transpose()?;
+    if let Some(fd) = &liveness {
+        // a full pipe must not block the event loop
+        let err = |errno| ProxyError::Setup { context: "--liveness-fd".to_owned(), errno };
+        fcntl_setfl(fd, fcntl_getfl(fd).map_err(err)? | OFlags::NONBLOCK).map_err(err)?;
+    }
+    let xdg_runtime_dir = std::env::var("XDG_RUNTIME_DIR").map_err(|_| ProxyError::Env { var: "XDG_RUNTIME_DIR" })?;
+
+    let wayland_wrap = match &opts.display_name {
+        Some(name) => name.clone(),
+        None => format!("wayland-wrap-{}", std::process::id()),
+    };
+
+    let tcp_parent = opts.parent_tcp.as_deref().map(|target| parent::resolve_tcp("--parent-tcp", target)).transpose()?;
+    let playback = opts.replay_to_client.as_deref().map(recording::Recording::load).transpose()?;
+    let mut failover = parent::failover_paths(opts, &xdg_runtime_dir).into_iter();
+    let (wayland_path, inherited) = match tcp_parent.is_some() || playback.is_some() {
+        true => (None, None),
+        // WAYLAND_DISPLAY isn't used at all then
+        false if !opts.parent_failover.is_empty() => (failover.next(), None),
+        false => match parent::resolve(opts, &xdg_runtime_dir, &*clock)? {
+            parent::Target::Path(path) => (Some(path), None),
+            parent::Target::Inherited { fd, peer } => (peer, Some(fd)),
+        },
+    };
+
+    let pool = parent::adopt_pool(&opts.parent_fd_pool)?;
+
+    let mut sock_path = PathBuf::from_str(&xdg_runtime_dir).unwrap();
+    sock_path.push(&wayland_wrap);
+
+    // the wayland socket always comes first
+    let socket_type = if opts.seqpacket { SocketType::SEQPACKET } else { SocketType::STREAM };
+    let mut listeners = vec![Listener::bind(sock_path, wayland_path, Protocol::Wayland, socket_type, opts.force)?];
+    // popped from the back, the pool goes in the order it was given
+    listeners[0].preconnected.extend(pool.into_iter().rev());
+    listeners[0].preconnected.extend(inherited);
+    if let Some(addr) = tcp_parent {
+        listeners[0].set_tcp_parent(addr, opts.tcp_nodelay);
+    }
+    if let (Some(recording), Some(path)) = (playback, &opts.replay_to_client) {
+        listeners[0].set_playback(path, recording);
+    }
+    for path in failover {
+        listeners[0].add_backup(path)?;
+    }
+    for route in &opts.routes {
+        let path = parent::socket_path(&route.target, &xdg_runtime_dir)?;
+        listeners[0].add_route(route.clone(), path)?;
+    }
+
+    if let Some(addr) = &opts.listen_tcp {
+        let addr = parent::resolve_tcp("--listen-tcp", addr)?;
+        let listener = Listener::bind_tcp(addr, opts.tcp_nodelay, &listeners[0])?;
+        log!("warning: accepting wayland clients on {} (--listen-tcp), anyone who can reach it can use the compositor", listener.name());
+        listeners.push(listener);
+    }
+
+    let mut child_env = vec![("WAYLAND_DISPLAY", wayland_wrap)];
+    if let Some(display) = opts.x11_display {
+        let (listener, proxy_display) = Listener::bind_x11(display)?;
+        child_env.push(("DISPLAY", format!(":{}", proxy_display)));
+        listeners.push(listener);
+    }
+
+    let mut child = None;
+    let mut pty = None;
+    let mut pidfd = None;
+    if opts.no_child {
+        // for whoever starts the clients
+        for (var, value) in &child_env {
+            println!("{}={}", var, value);
+        }
+    } else {
+        let mut command = Command::new(&opts.command[0]);
+        // with --socket-env the child gets its connection without WAYLAND_DISPLAY pointing at us
+        let env = child_env.iter().filter(|(var, _)| opts.socket_env.is_none() || *var != "WAYLAND_DISPLAY");
+        command.args(&opts.command[1..]).envs(env.cloned());
+        if let Some(dir) = &opts.chdir {
+            command.current_dir(dir);
+        }
+
+        let mut child_fds = inherit::ChildFds::default();
+        if let Some((var, value)) = &opts.socket_env {
+            child_fds.socket_env(&mut command, &listeners[0].path, socket_type, var, value)?;
+        }
+        for &fd in &opts.no_cloexec_for_child {
+            child_fds.keep(fd)?;
+        }
+        child_fds.apply(&mut command, opts.verbose);
+        if opts.pty {
+            pty = Some(pty::Pty::attach(&mut command)?);
+        }
+        if !opts.unshare.is_empty() || opts.cgroup.is_some() {
+            isolate::apply(&mut command, opts.unshare, opts.cgroup.as_deref())?;
+        }
+        if let Some(profile) = opts.seccomp {
+            seccomp::apply(&mut command, profile);
+        }
+        let spawned = match command.spawn() {
+            Ok(child) => child,
+            Err(error) => {
+                // nobody is going to connect, dropping the listeners removes the sockets
+                drop(listeners);
+                return Err(ProxyError::Spawn { command: opts.command[0].clone(), error });
+            }
+        };
+        // the parent side must not keep the pty slave open or we'd never see the child hang up
+        drop(command);
+
+        // --deny-new-connections-after-child-exit and --post-exit-grace have to notice the exit
+        // right away, not on the next 30 second wakeup
+        if opts.deny_after_exit || opts.post_exit_grace.is_some() {
+            pidfd = Some(rustix::process::pidfd_open(rustix::process::Pid::from_child(&spawned), rustix::process::PidfdFlags::empty()).map_err(|errno| ProxyError::Pidfd { errno })?);
+        }
+        if opts.log_prefix.is_none() {
+            log::set_prefix(&format!("{}[{}]", program.unwrap_or(""), spawned.id()));
+        }
+        child = Some(spawned);
+    }
+
+    // only once the child is running, it shouldn't inherit a limit that breaks select()
+    if opts.raise_rlimit {
+        raise_fd_limit();
+    }
+    let sighup = signals::Sighup::install()?;
+
+    let mut proxy = Proxy::new(opts, listeners, Arc::clone(&clock))?;
+    proxy.liveness = liveness;
+    if let Some(status_fd) = status_fd {
+        let x11_display = child_env.iter().find(|(var, _)| *var == "DISPLAY").map(|(_, display)| display.as_str());
+        status_fd.report(&status::Status {
+            wayland_display: &child_env[0].1,
+            socket: &proxy.listeners[0].path,
+            pid: std::process::id(),
+            child_pid: child.as_ref().map(std::process::Child::id),
+            x11_display,
+            control_socket: opts.control_socket.as_deref(),
+        })?;
+    }
+    if opts.experimental_reconnect {
+        log!("warning: --experimental-reconnect is enabled, replayed clients may misbehave or get disconnected by the new compositor");
+    }
+    // since when there are no connections, for --idle-timeout
+    let mut idle_since = Some(clock.now());
+    // until the first connection arrives, for --expect-connection-timeout
+    let mut expect_connection = opts.expect_connection_timeout.map(|timeout| clock.now() + timeout);
+    // when we noticed the child exiting, for --post-exit-grace
+    let mut exited_at: Option<Instant> = None;
+
+    // wake up every 30 seconds, if we then have no connections and no children at that point we exit
+    loop {
+        let mut extra = pty.as_ref().map_or(Vec::new(), |pty| pty.poll_fds().to_vec());
+        extra.extend(pidfd.as_ref().map(|fd| (fd.as_fd(), PollFlags::IN)));
+        let sighup_index = extra.len();
+        extra.push((sighup.fd(), PollFlags::IN));
+        let mut timeout = 30000;
+        if let (Some(idle_timeout), Some(since)) = (opts.idle_timeout, idle_since) {
+            let remaining = (since + idle_timeout).saturating_duration_since(clock.now());
+            timeout = poll_timeout(timeout, remaining);
+        }
+        if let Some(deadline) = expect_connection {
+            let remaining = deadline.saturating_duration_since(clock.now());
+            timeout = poll_timeout(timeout, remaining);
+        }
+        if let (Some(grace), Some(exited)) = (opts.post_exit_grace, exited_at) {
+            let remaining = (exited + grace).saturating_duration_since(clock.now());
+            timeout = poll_timeout(timeout, remaining);
+        }
+        let extra_flags = proxy.step(timeout, &extra)?;
+        if let Some(pty) = &mut pty {
+            pty.handle(extra_flags[0], extra_flags[1]);
+        }
+        if extra_flags[sighup_index].contains(PollFlags::IN) && sighup.received() {
+            log::reopen();
+            log!("SIGHUP, {} open connections", proxy.connections.len());
+            proxy.stats.dump();
+            if opts.count_messages {
+                proxy.stats.dump_messages();
+            }
+            if opts.filter_summary {
+                proxy.stats.dump_filters(opts);
+            }
+        }
+
+        if proxy.next_id > 0 {
+            expect_connection = None;
+        } else if expect_connection.is_some_and(|deadline| clock.now() >= deadline) {
+            let timeout = opts.expect_connection_timeout.unwrap_or_default();
+            if opts.expect_connection_fatal {
+                proxy.unlink_sockets();
+                return Err(ProxyError::NoConnection { timeout });
+            }
+            log!("warning: the child did not connect within {:?} (--expect-connection-timeout)", timeout);
+            expect_connection = None;
+        }
+
+        let child_exited = child.as_mut().is_some_and(|child| matches!(child.try_wait(), Ok(Some(_))));
+        if child_exited && exited_at.is_none() {
+            exited_at = Some(clock.now());
+            // it stays readable from now on
+            pidfd = None;
+            if opts.deny_after_exit {
+                // whoever else got hold of the socket must not get a new connection, existing ones
+                // are still served until they close
+                log!("child exited, no longer accepting connections");
+                proxy.listeners.clear();
+            }
+            if let Some(grace) = opts.post_exit_grace {
+                log!("child exited, still accepting connections for {:?} (--post-exit-grace)", grace);
+            }
+        }
+        // a restarted child may want to connect again before we go away
+        let grace_over = opts.post_exit_grace.zip(exited_at).is_none_or(|(grace, exited)| clock.now().duration_since(exited) >= grace);
+
+        match proxy.connections.is_empty() {
+            true => idle_since = idle_since.or(Some(clock.now())),
+            false => idle_since = None,
+        }
+        let idle_expired = opts.idle_timeout.zip(idle_since).is_some_and(|(timeout, since)| clock.now().duration_since(since) >= timeout);
+
+        // with --persist the socket stays up for clients launched externally until we get killed
+        let exit_reason = match (child_exited, proxy.connections.len(), opts.persist) {
+            (true, 0, false) if grace_over => Some("child exited and no open connections, exiting"),
+            _ if idle_expired => Some("no connections for --idle-timeout, exiting"),
+            (_, 0, _) if opts.no_child && lifetime_exhausted(opts, &proxy.stats) => Some("served --max-lifetime-connections clients, exiting"),
+            _ => None,
+        };
+        if let Some(reason) = exit_reason {
+            proxy.unlink_sockets();
+            if let Some(pty) = &mut pty {
+                pty.flush_output();
+            }
+            log!("{}", reason);
+            if opts.stats {
+                proxy.stats.dump();
+            }
+            if opts.count_messages {
+                proxy.stats.dump_messages();
+            }
+            if opts.filter_summary {
+                proxy.stats.dump_filters(opts);
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// The event loop state: listening sockets and the connections accepted on them.
+///
+/// Embedders that set up their own sockets can create one without listeners with
+/// [`Proxy::embedded`], hand it connections with [`Proxy::add_connection`] and drive it with
+/// [`Proxy::step`].
+pub struct Proxy<'a> {
+    opts: &'a Options,
+    listeners: Vec<Listener>,
+    connections: Vec<ProxiedConnection>,
+    control: Option<Control>,
+    trace_socket: Option<TraceSocket>,
+    fd_limit: FdLimit,
+    fd_warnings: RateLimit,
+    // --max-connections-per-uid and --on-parent-outage reject rejections
+    reject_warnings: RateLimit,
+    watchdog: Option<Watchdog>,
+    syslog: Option<Syslog>,
+    stats: Stats,
+    next_id: u64,
+    // when the next --heartbeat-interval line is due
+    next_heartbeat: Option<Instant>,
+    // --record-events, until the first wayland connection takes it
+    recorder: Option<Recorder>,
+    // --liveness-fd, written to on every heartbeat
+    liveness: Option<OwnedFd>,
+    clock: Arc<dyn Clock>,
+    // iterations in a row in which connections had events but nothing moved, for --spin-limit
+    spins: u64,
+}
+
+// fds normally pass through within milliseconds
+const FD_HOLD_WARNING: Duration = Duration::from_secs(5);
+// how long a connection closed through the control socket gets to deliver its queues
+const EVICT_TIMEOUT: Duration = Duration::from_secs(10);
+// --spin-limit, short enough to not be noticed if it's a false alarm
+const SPIN_SLEEP: Duration = Duration::from_millis(10);
+
+impl<'a> Proxy<'a> {
+    /// A proxy that doesn't accept connections on its own, only those passed to
+    /// [`Proxy::add_connection`].
+    pub fn embedded(opts: &'a Options) -> Result<Proxy<'a>, ProxyError> {
+        Proxy::new(opts, Vec::new(), Arc::new(SystemClock))
+    }
+
+    /// Like [`Proxy::embedded`], with the timeouts and rate limits following `clock`, e.g. a
+    /// [`MockClock`] in tests.
+    pub fn embedded_with_clock(opts: &'a Options, clock: Arc<dyn Clock>) -> Result<Proxy<'a>, ProxyError> {
+        Proxy::new(opts, Vec::new(), clock)
+    }
+
+    fn new(opts: &'a Options, listeners: Vec<Listener>, clock: Arc<dyn Clock>) -> Result<Proxy<'a>, ProxyError> {
+        Ok(Proxy {
+            opts,
+            listeners,
+            connections: Vec::new(),
+            control: opts.control_socket.as_deref().map(Control::bind).transpose()?,
+            trace_socket: opts.trace_socket.as_deref().map(TraceSocket::bind).transpose()?,
+            fd_limit: FdLimit::new(opts),
+            fd_warnings: RateLimit::new(Duration::from_secs(1)),
+            reject_warnings: RateLimit::new(Duration::from_secs(1)),
+            watchdog: opts.watchdog.map(|timeout| Watchdog::spawn(timeout, opts.watchdog_abort, Arc::clone(&clock))),
+            syslog: opts.syslog.then(Syslog::connect).transpose()?,
+            stats: Stats::new(opts),
+            next_id: 0,
+            next_heartbeat: opts.heartbeat_interval.map(|interval| clock.now() + interval),
+            recorder: opts.record_events.as_deref().map(Recorder::create).transpose()?,
+            liveness: None,
+            clock,
+            spins: 0,
+        })
+    }
+
+    /// Starts forwarding between a wayland client and a compositor connection that's already
+    /// established, no connect is attempted. Both fds are switched to non-blocking mode. Returns
+    /// the connection id used in log messages and on the control socket.
+    pub fn add_connection(&mut self, child: OwnedFd, parent: OwnedFd) -> Result<u64, ProxyError> {
+        for (fd, context) in [(&child, "child connection"), (&parent, "parent connection")] {
+            let err = |errno| ProxyError::Setup { context: context.to_owned(), errno };
+            fcntl_setfl(fd, fcntl_getfl(fd).map_err(err)? | OFlags::NONBLOCK).map_err(err)?;
+        }
+        self.next_id += 1;
+        let mut conn = ProxiedConnection::new(self.next_id, child, parent, true, Protocol::Wayland, self.opts, self.clock.now());
+        conn.recorder = self.recorder.take();
+        if let Some(syslog) = &mut self.syslog {
+            syslog.log(&format!("conn {} added by the embedder, {}", conn.id, peer::describe(conn.peer.as_ref())));
+        }
+        self.connections.push(conn);
+        self.fd_limit.update(self.connections.len());
+        Ok(self.next_id)
+    }
+
+    // also stops accepting connections
+    fn unlink_sockets(&mut self) {
+        self.listeners.clear();
+        self.control = None;
+        self.trace_socket = None;
+    }
+
+    /// Number of open connections.
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Runs one iteration of the event loop, waiting at most `timeout` milliseconds.
+    ///
+    /// `extra` fds are polled along with the proxy's own, their revents are returned in the same
+    /// order (all empty if poll got interrupted).
+    pub fn step(&mut self, mut timeout: i32, extra: &[(BorrowedFd<'_>, PollFlags)]) -> Result<Vec<PollFlags>, ProxyError> {
+        let Proxy { opts, listeners, connections, control, trace_socket, fd_limit, fd_warnings, reject_warnings, watchdog, syslog, stats, next_id, next_heartbeat, recorder, liveness, clock, spins } = self;
+        let (opts, clock): (&Options, &dyn Clock) = (opts, &**clock);
+        let started = Instant::now();
+
+        let mut poll_fds = Vec::with_capacity(1 + connections.len());
+    
+        let can_read = fd_limit.can_read();
+        poll_fds.extend(connections.iter().flat_map(|conn| {
+            let (parent_flags, child_flags) = poll_flags_for(conn, can_read);
+            let child = conn.child.as_ref().unwrap().as_fd();
+            // the old parent socket would keep reporting HUP, the slot gets the child without
+            // any events instead until we reconnected
+            let parent = match conn.reconnecting() {
+                true => child,
+                false => conn.parent.as_ref().unwrap().as_fd(),
+            };
+
+            [
+                PollFd::from_borrowed_fd(parent, parent_flags),
+                PollFd::from_borrowed_fd(child, child_flags)
+            ]
+        }));
+
+        // non-connection fds go after the connection pairs
+        let conn_fds = poll_fds.len();
+        let server_flags = if fd_limit.can_accept() && !waiting_for_parent(opts, connections) { PollFlags::IN } else { PollFlags::empty() };
+        poll_fds.extend(listeners.iter().map(|listener| PollFd::new(&listener.socket, server_flags)));
+        let control_fds = poll_fds.len();
+        if let Some(control) = &control {
+            poll_fds.extend(control.poll_fds().map(|(fd, flags)| PollFd::from_borrowed_fd(fd, flags)));
+        }
+        let trace_fds = poll_fds.len();
+        if let Some(trace_socket) = &trace_socket {
+            poll_fds.extend(trace_socket.poll_fds().map(|(fd, flags)| PollFd::from_borrowed_fd(fd, flags)));
+        }
+        let extra_fds = poll_fds.len();
+        poll_fds.extend(extra.iter().map(|&(fd, flags)| PollFd::from_borrowed_fd(fd, flags)));
+
+        if let Some(flush_timeout) = opts.queue_flush_timeout {
+            let now = clock.now();
+            let stalled = connections.iter().flat_map(|conn| [&conn.to_parent, &conn.to_child]).filter(|queue| !queue.is_empty());
+            for queue in stalled {
+                let remaining = (queue.progress + flush_timeout).saturating_duration_since(now);
+                timeout = poll_timeout(timeout, remaining);
+            }
+        }
+
+        if let Some(connect_timeout) = opts.parent_connect_timeout {
+            let now = clock.now();
+            for conn in connections.iter().filter(|conn| !conn.parent_connected && !conn.reconnecting()) {
+                let remaining = (conn.accepted_at + connect_timeout).saturating_duration_since(now);
+                timeout = poll_timeout(timeout, remaining);
+            }
+        }
+
+        // wake up in time to warn about fds being held for long
+        let now = clock.now();
+        for msg in connections.iter().flat_map(|conn| [&conn.to_parent, &conn.to_child]).filter_map(Queue::held_fds) {
+            let remaining = (msg.queued_at + FD_HOLD_WARNING).saturating_duration_since(now);
+            timeout = poll_timeout(timeout, remaining);
+        }
+
+        if let Some(deadline) = control.as_ref().and_then(Control::next_deadline) {
+            let remaining = deadline.saturating_duration_since(clock.now());
+            timeout = poll_timeout(timeout, remaining);
+        }
+
+        for conn in connections.iter().filter(|conn| conn.evicted.is_some()) {
+            // once drained there's nothing left to wait for, the close happens right away
+            let (since, drained) = (conn.evicted.unwrap(), conn.to_parent.is_empty() && conn.to_child.is_empty());
+            let remaining = if drained { Duration::ZERO } else { (since + EVICT_TIMEOUT).saturating_duration_since(clock.now()) };
+            timeout = poll_timeout(timeout, remaining);
+        }
+
+        for attempt in connections.iter().filter_map(|conn| conn.replay.as_ref()?.next_attempt()) {
+            let remaining = attempt.saturating_duration_since(clock.now());
+            timeout = poll_timeout(timeout, remaining);
+        }
+
+        if let Some(heartbeat) = *next_heartbeat {
+            let remaining = heartbeat.saturating_duration_since(clock.now());
+            timeout = poll_timeout(timeout, remaining);
+        }
+
+        if let Some(watchdog) = &watchdog {
+            let now = clock.now();
+            watchdog.sleeping(connections.iter().map(|conn| conn.describe(now)).collect());
+        }
+        let poll_started = Instant::now();
+        // counted as polling, the point is to spend the time idle
+        if opts.spin_limit.is_some_and(|limit| *spins >= limit) {
+            stats.spin_sleeps += 1;
+            clock.sleep(SPIN_SLEEP);
+        }
+        let polled = poll(poll_fds.as_mut(), timeout);
+        let woke_up = Instant::now();
+        stats.polling += woke_up - poll_started;
+        stats.processing += poll_started - started;
+        if let Some(watchdog) = &watchdog {
+            watchdog.woke_up();
+        }
+        match polled {
+            Ok(_) => {},
+            Err(e) if e == Errno::INTR => return Ok(vec![PollFlags::empty(); poll_fds.len() - extra_fds]),
+            Err(errno) => return Err(ProxyError::Poll { errno })
+        }
+
+        let mut poll_flags: Vec<_> = poll_fds.into_iter().map(|p| p.revents()).collect();
+
+        if let (Some(heartbeat), Some(interval)) = (next_heartbeat.as_mut(), opts.heartbeat_interval) {
+            let now = clock.now();
+            if now >= *heartbeat {
+                log!("heartbeat: {} connections, {} bytes to parent, {} bytes to child", connections.len(), stats.to_parent.bytes, stats.to_child.bytes);
+                if let Some(fd) = liveness {
+                    // a supervisor that doesn't read doesn't need more bytes
+                    match rustix::io::write(&*fd, b".") {
+                        Ok(_) | Err(Errno::AGAIN) => {}
+                        Err(errno) => {
+                            log!("warning: failed to write to --liveness-fd: {}, no longer reporting", errno);
+                            *liveness = None;
+                        }
+                    }
+                }
+                // not catching up on missed beats, e.g. after a suspend
+                *heartbeat = now + interval;
+            }
+        }
+
+        let other_flags = poll_flags.split_off(extra_fds);
+        let trace_flags = poll_flags.split_off(trace_fds);
+        let control_flags = poll_flags.split_off(control_fds);
+        let extra_flags = poll_flags.split_off(conn_fds);
+        let spin_check = opts.spin_limit.is_some().then(|| (activity(connections, stats), poll_flags.iter().any(|flags| !flags.is_empty())));
+
+        if let Some(control) = control {
+            control.handle(&control_flags, clock.now(), |command| control_command(command, connections, stats, clock.now()));
+        }
+        if let Some(trace_socket) = trace_socket.as_mut() {
+            trace_socket.handle(&trace_flags);
+        }
+
+        for (listener, server_flags) in listeners.iter_mut().zip(extra_flags) {
+            if server_flags.intersects(PollFlags::HUP | PollFlags::ERR | PollFlags::NVAL) {
+                // poll would keep reporting this and we'd spin, new clients can't connect anyway
+                let errno = match rustix::net::sockopt::get_socket_error(&listener.socket) {
+                    Ok(Err(errno)) => errno,
+                    _ => Errno::IO,
+                };
+                return Err(ProxyError::Accept { context: listener.name(), errno });
+            }
+
+            if !server_flags.contains(PollFlags::IN) {
+                continue;
+            }
+            if let Some(watchdog) = &watchdog {
+                watchdog.stage("accept", None);
+            }
+            // bounded so a connection storm doesn't delay traffic on existing connections,
+            // poll is level-triggered so we'll pick up the rest in the next iteration
+            for _ in 0..opts.accept_batch {
+                if !fd_limit.can_accept() || waiting_for_parent(opts, connections) || lifetime_exhausted(opts, stats) {
+                    break;
+                }
+                match listener.accept() {
+                    Ok(child) => {
+                        if let Some(limit) = opts.max_connections_per_uid {
+                            // clients we can't identify, like those over TCP, aren't limited
+                            let uid = rustix::net::sockopt::get_socket_peercred(&child).ok().map(|cred| cred.uid);
+                            let open = |uid| connections.iter().filter(|conn| conn.peer.as_ref().is_some_and(|peer| peer.cred.uid == uid)).count();
+                            if let Some(uid) = uid.filter(|&uid| open(uid) >= limit) {
+                                stats.rejected += 1;
+                                let warning = format!("warning: uid {} already has {} connections (--max-connections-per-uid), rejecting new client", uid.as_raw(), limit);
+                                match reject_warnings.allow(clock.now()) {
+                                    Some(0) => log!("{}", warning),
+                                    Some(n) => log!("{} ({} similar warnings suppressed)", warning, n),
+                                    None => {}
+                                }
+                                continue;
+                            }
+                        }
+                        let (parent, parent_connected, replay, failover) = match listener.connect_parent(&child) {
+                            Ok(Some((parent, connected, addr))) => {
+                                let failover = addr.as_ref().filter(|_| !opts.parent_failover.is_empty()).and_then(|addr| Some(addr.path()?.to_string_lossy().into_owned()));
+                                let replay = addr.filter(|_| opts.experimental_reconnect && listener.protocol == Protocol::Wayland).map(|addr| Replay::new(addr, &parent));
+                                (parent, connected, replay, failover)
+                            }
+                            Ok(None) => {
+                                log!("warning: the inherited compositor connection is already in use, closing new client");
+                                continue;
+                            }
+                            Err(ProxyError::Connect { context, errno: errno @ (Errno::NOENT | Errno::CONNREFUSED) }) if opts.on_parent_outage.is_some() && listener.protocol == Protocol::Wayland => {
+                                let Some((addr, socket_type)) = listener.parent_addr(&child).filter(|_| opts.on_parent_outage != Some(Outage::Reject)) else {
+                                    stats.rejected_outage += 1;
+                                    let warning = format!("warning: can't reach the compositor at {}: {}, rejecting new client (--on-parent-outage)", context, errno);
+                                    match reject_warnings.allow(clock.now()) {
+                                        Some(0) => log!("{}", warning),
+                                        Some(n) => log!("{} ({} similar warnings suppressed)", warning, n),
+                                        None => {}
+                                    }
+                                    continue;
+                                };
+                                log!("warning: can't reach the compositor at {}: {}, holding the new client until it's back (--on-parent-outage)", context, errno);
+                                // stands in for the compositor connection until there is one
+                                let placeholder = socket_with(AddressFamily::UNIX, socket_type, SocketFlags::CLOEXEC, None).map_err(|errno| ProxyError::Connect { context, errno })?;
+                                (placeholder, false, Some(Replay::pending(addr, socket_type, clock.now())), None)
+                            }
+                            Err(e) => {
+                                // only this client is affected, the ones already connected are fine
+                                stats.parent_connect_failed += 1;
+                                let warning = format!("warning: {}, closing new client", e);
+                                match reject_warnings.allow(clock.now()) {
+                                    Some(0) => log!("{}", warning),
+                                    Some(n) => log!("{} ({} similar warnings suppressed)", warning, n),
+                                    None => {}
+                                }
+                                continue;
+                            }
+                        };
+                        *next_id += 1;
+                        stats.accepted += 1;
+                        let mut conn = ProxiedConnection::new(*next_id, child, parent, parent_connected, listener.protocol, opts, clock.now());
+                        conn.replay = replay;
+                        if let Some(path) = failover {
+                            log!("conn {}: forwarded to {} (--parent-failover)", conn.id, path);
+                        }
+                        if listener.protocol == Protocol::Wayland {
+                            conn.recorder = recorder.take();
+                        }
+                        if let Some(syslog) = syslog {
+                            syslog.log(&format!("conn {} opened on {}, {}", conn.id, listener.name(), peer::describe(conn.peer.as_ref())));
+                        }
+                        connections.push(conn);
+                        fd_limit.update(connections.len());
+                    }
+                    Err(e) if e == Errno::AGAIN => break,
+                    Err(errno) => return Err(ProxyError::Accept { context: listener.name(), errno })
+                }
+            }
+        }
+        if lifetime_exhausted(opts, stats) && !listeners.is_empty() {
+            // like --deny-new-connections-after-child-exit, open connections are still served
+            log!("accepted {} connections (--max-lifetime-connections), no longer accepting", stats.accepted);
+            listeners.clear();
+        }
+
+        for ((parent_flags, child_flags), conn) in flag_pairs(&poll_flags).zip(connections.iter_mut()) {
+            if let Some(watchdog) = &watchdog {
+                watchdog.stage("connection handling", Some(conn.id));
+            }
+            // a bug hit by one client's traffic shouldn't take the others down with it
+            match panic::catch_unwind(AssertUnwindSafe(|| handle_connection(conn, parent_flags, child_flags, clock.now(), opts, fd_limit, stats))) {
+                Ok(result) => result?,
+                Err(payload) => {
+                    log!("warning: conn {}: panicked while handling it ({}), closing it. This is a bug in the proxy", conn.id, panic_message(&*payload));
+                    conn.close(CloseReason::Panic);
+                }
+            }
+        }
+
+        if let Some(watchdog) = &watchdog {
+            watchdog.stage("cleanup", None);
+        }
+
+        let now = clock.now();
+        for conn in connections.iter_mut() {
+            let closed = conn.is_closed();
+            for (dir, queue) in [(Direction::ToParent, &mut conn.to_parent), (Direction::ToChild, &mut conn.to_child)] {
+                let warning = if closed {
+                    let fds: usize = queue.messages.iter().map(|m| m.fds.len()).sum();
+                    (fds > 0).then(|| format!("warning: conn {} closed with {} fds for the {} undelivered", conn.id, fds, dir.sink()))
+                } else {
+                    queue.check_held_fds(now).map(|(fds, age)| format!("warning: conn {} holding {} fds for the {} since {:.1?}", conn.id, fds, dir.sink(), age))
+                };
+                let Some(warning) = warning else {
+                    continue;
+                };
+                match fd_warnings.allow(now) {
+                    Some(0) => log!("{}", warning),
+                    Some(n) => log!("{} ({} similar warnings suppressed)", warning, n),
+                    None => {}
+                }
+            }
+        }
+
+        if let (Some(limit), Some((before, woken))) = (opts.spin_limit, spin_check) {
+            if !woken || activity(connections, stats) != before {
+                if *spins >= limit {
+                    log!("the loop moves data again after {} iterations without (--spin-limit)", *spins);
+                }
+                *spins = 0;
+            } else {
+                *spins += 1;
+                if *spins == limit {
+                    let busy: Vec<String> = flag_pairs(&poll_flags).zip(connections.iter()).filter(|((parent, child), _)| !parent.is_empty() || !child.is_empty()).map(|(_, conn)| conn.id.to_string()).collect();
+                    log!("warning: {} loop iterations in a row woke up for connections {} without moving any data, sleeping {:?} before each poll until something moves (--spin-limit). This is a bug in the proxy", limit, busy.join(", "), SPIN_SLEEP);
+                }
+            }
+        }
+
+        remove_closed(connections, opts, syslog, stats);
+        if let Some(trace_socket) = trace_socket {
+            trace_socket.flush();
+        }
+        fd_limit.update(connections.len());
+        stats.processing += woke_up.elapsed();
+
+        Ok(other_flags)
+    }
+
+    /// Closes all connections, for embedders that stop driving the proxy. Queued data is dropped.
+    pub fn shutdown(&mut self) {
+        for conn in self.connections.iter_mut() {
+            conn.close(CloseReason::Shutdown);
+        }
+        remove_closed(&mut self.connections, self.opts, &mut self.syslog, &mut self.stats);
+        self.fd_limit.update(0);
+    }
+}
+
+/// One loop iteration's work for a connection, `parent_flags` and `child_flags` are its revents.
+///
+/// Proxy::step catches panics from here and only closes the connection. That's a backstop for
+/// bugs, state shared with other connections such as the stats may be left off. Anything the
+/// traffic can cause has to be an error or a close reason instead.
+fn handle_connection(conn: &mut ProxiedConnection, parent_flags: &PollFlags, child_flags: &PollFlags, now: Instant, opts: &Options, fd_limit: &FdLimit, stats: &mut Stats) -> Result<(), ProxyError> {
+    if let Some(since) = conn.evicted {
+        let drained = conn.to_parent.is_empty() && conn.to_child.is_empty();
+        if drained || now.duration_since(since) >= EVICT_TIMEOUT {
+            if !drained {
+                log!("warning: conn {}: queues not delivered within {:?} of the close command, closing anyway", conn.id, EVICT_TIMEOUT);
+            }
+            // both peers see an orderly EOF rather than a reset
+            for fd in [&conn.parent, &conn.child].into_iter().flatten() {
+                let _ = rustix::net::shutdown(fd, rustix::net::Shutdown::Write);
+            }
+            conn.close(CloseReason::Control);
+            return Ok(());
+        }
+    }
+    if conn.reconnecting() {
+        if !child_flags.intersects(PollFlags::HUP | PollFlags::ERR) {
+            // the client still gets what the old compositor sent
+            drain_queue(conn, Direction::ToChild, child_flags, now, opts, stats)?;
+            conn.reconnect(now, opts, stats);
+            return Ok(());
+        }
+    } else if parent_flags.intersects(PollFlags::HUP | PollFlags::ERR) && !child_flags.intersects(PollFlags::HUP | PollFlags::ERR) && conn.parent_connected {
+        if let Some((id, interface)) = conn.replay.as_ref().and(conn.tracker.as_ref()).and_then(Tracker::compositor_object) {
+            // its requests to that object would reach a compositor that never heard of it
+            log!("warning: conn {}: lost the compositor, not reconnecting: the client holds {}@{}, which the old compositor created (--experimental-reconnect)", conn.id, interface, id);
+            conn.close(CloseReason::NotReplayable);
+            return Ok(());
+        }
+        if let Some(replay) = &mut conn.replay {
+            log!("warning: conn {}: lost the compositor, trying to reconnect and replay {} bytes of requests (--experimental-reconnect)", conn.id, replay.recorded().len());
+            replay.start(now);
+            conn.reconnect(now, opts, stats);
+            return Ok(());
+        }
+    }
+    // a peer that sent something right before hanging up reports both HUP and IN. what it
+    // sent is still forwarded below, the read then runs into the EOF and closes
+    let hung_up = |flags: &PollFlags| flags.contains(PollFlags::ERR) || (flags.contains(PollFlags::HUP) && !flags.contains(PollFlags::IN));
+    if hung_up(parent_flags) || hung_up(child_flags) {
+        // poll indicates error. close.
+        let reason = if child_flags.contains(PollFlags::ERR) {
+            CloseReason::ClientReset
+        } else if hung_up(child_flags) {
+            CloseReason::ClientEof
+        } else if !conn.parent_connected {
+            CloseReason::ParentConnectFailed
+        } else if parent_flags.contains(PollFlags::ERR) {
+            CloseReason::ServerReset
+        } else {
+            CloseReason::ServerEof
+        };
+        conn.close(reason);
+        return Ok(());
+    }
+
+    if !conn.parent_connected && parent_flags.contains(PollFlags::OUT) {
+        conn.parent_connected = true
+    }
+    if conn.parent_connected {
+        // whichever direction goes first may use up the fd headroom and budgets, so they
+        // take turns
+        let dirs = match conn.requests_first {
+            true => [Direction::ToParent, Direction::ToChild],
+            false => [Direction::ToChild, Direction::ToParent],
+        };
+        conn.requests_first = !conn.requests_first;
+        // (from, to) flags
+        let flags = |dir| match dir {
+            Direction::ToParent => (child_flags, parent_flags),
+            Direction::ToChild => (parent_flags, child_flags),
+        };
+        for dir in dirs {
+            transfer_or_queue(conn, dir, flags(dir).0, now, opts, fd_limit, stats)?;
+        }
+        for dir in dirs {
+            if drain_queue(conn, dir, flags(dir).1, now, opts, stats)? || conn.queue(dir).is_empty() {
+                conn.queue(dir).progress = now;
+            }
+        }
+    }
+
+    if let Some(connect_timeout) = opts.parent_connect_timeout {
+        if !conn.parent_connected && now.duration_since(conn.accepted_at) >= connect_timeout {
+            log!("warning: conn {}: parent did not accept the connection within {:?}, closing client", conn.id, connect_timeout);
+            conn.close(CloseReason::ParentConnectFailed);
+            return Ok(());
+        }
+    }
+
+    if let Some(limit) = opts.max_inflight_messages {
+        let over = [("parent", &conn.to_parent), ("child", &conn.to_child)].into_iter().find(|(_, queue)| queue.inflight > limit);
+        if let Some((peer, queue)) = over {
+            log!("warning: conn {}: {} messages queued for the {}, more than --max-inflight-messages {}, closing connection", conn.id, queue.inflight, peer, limit);
+            conn.close(CloseReason::LimitExceeded);
+            return Ok(());
+        }
+    }
+
+    if let Some(flush_timeout) = opts.queue_flush_timeout {
+        let stalled = [("parent", &conn.to_parent), ("child", &conn.to_child)]
+            .into_iter()
+            .find(|(_, queue)| !queue.is_empty() && now.duration_since(queue.progress) >= flush_timeout);
+        if let Some((peer, queue)) = stalled {
+            let fds: usize = queue.messages.iter().map(|m| m.fds.len()).sum();
+            log!("warning: conn {}: the {} stopped reading, closing it with {} queued messages holding {} fds", conn.id, peer, queue.messages.len(), fds);
+            conn.close(CloseReason::LimitExceeded);
+        }
+    }
+    Ok(())
+}
+
+/// The message a panic was started with, if it's a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("no message", String::as_str),
+    }
+}
+
+/// Drops closed connections and accounts for why they were closed.
+fn remove_closed(connections: &mut Vec<ProxiedConnection>, opts: &Options, syslog: &mut Option<Syslog>, stats: &mut Stats) {
+    for conn in connections.iter().filter(|c| c.is_closed()) {
+        let reason = conn.close_reason.map_or("unknown".to_owned(), |reason| reason.to_string());
+        if opts.verbose {
+            // whatever is still queued goes away with the connection, which can explain a client
+            // missing the end of e.g. a clipboard transfer
+            for (queue, dir) in [(&conn.to_parent, Direction::ToParent), (&conn.to_child, Direction::ToChild)] {
+                if !queue.is_empty() {
+                    log!("conn {}: closed ({}), dropping {} queued for the {}", conn.id, reason, queue.contents(), dir.sink());
+                }
+            }
+        }
+        if let Some(reason) = conn.close_reason {
+            *stats.closes.entry(reason).or_default() += 1;
+        }
+        if let Some(tracker) = &conn.tracker {
+            tracker.closed(conn.close_reason, &mut stats.to_child);
+        }
+        if let Some(syslog) = syslog {
+            syslog.log(&format!("conn {} closed ({}), {}, {} bytes to parent, {} bytes to child", conn.id, reason, peer::describe(conn.peer.as_ref()), conn.to_parent.received, conn.to_child.received));
+        }
+    }
+    connections.retain(|c| !c.is_closed());
+}
+
+struct ProxiedConnection {
+    id: u64,
+    parent: Option<OwnedFd>,
+    child: Option<OwnedFd>,
+    parent_connected: bool,
+    // when the connect to the parent started, for --parent-connect-timeout
+    accepted_at: Instant,
+    to_parent: Queue,
+    to_child: Queue,
+    // pending validation of the first client message, only with --strict-handshake
+    handshake: Option<HandshakeCheck>,
+    // message level processing, only for options that need it such as --cap-global
+    tracker: Option<Tracker>,
+    // credentials of the client process when it connected
+    peer: Option<Peer>,
+    // no reading from either side, set through the control socket
+    paused: bool,
+    // when the control socket asked to close it, which happens once its queues are delivered
+    evicted: Option<Instant>,
+    // either side is a SOCK_SEQPACKET socket, see transfer_or_queue
+    seqpacket: bool,
+    // what the client sent, while it can still be replayed with --experimental-reconnect
+    replay: Option<Replay>,
+    // both sides are unix sockets, fds can't go over TCP
+    passes_fds: bool,
+    // set where the connection got torn down
+    close_reason: Option<CloseReason>,
+    // --record-events, only on the first wayland connection
+    recorder: Option<Recorder>,
+    // the client shut down its write side. what it sent still goes to the parent, which then
+    // sees the same half-close, replies keep flowing until the parent closes
+    child_eof: bool,
+    // which direction is handled first in the next loop iteration, flipped every time
+    requests_first: bool,
+}
+
+impl ProxiedConnection {
+    fn new(id: u64, child: OwnedFd, parent: OwnedFd, parent_connected: bool, protocol: Protocol, opts: &Options, now: Instant) -> ProxiedConnection {
+        let handshake = (opts.strict_handshake && protocol == Protocol::Wayland).then(HandshakeCheck::default);
+        let tracker = if protocol == Protocol::Wayland { Tracker::new(id, opts) } else { None };
+        let peer = rustix::net::sockopt::get_socket_peercred(&child).ok().map(Peer::new);
+        // --seqpacket for accepted connections, embedders may hand us either kind
+        let seqpacket = [&child, &parent].iter().any(|fd| rustix::net::sockopt::get_socket_type(fd) == Ok(SocketType::SEQPACKET));
+        let passes_fds = [&child, &parent].iter().all(|fd| rustix::net::sockopt::get_socket_domain(fd) == Ok(AddressFamily::UNIX));
+        ProxiedConnection { id, parent: Some(parent), child: Some(child), parent_connected, accepted_at: now, to_parent: Queue::new(now), to_child: Queue::new(now), handshake, tracker, peer, paused: false, evicted: None, seqpacket, replay: None, passes_fds, close_reason: None, recorder: None, child_eof: false, requests_first: false }
+    }
+
+    fn queue(&mut self, dir: Direction) -> &mut Queue {
+        match dir {
+            Direction::ToParent => &mut self.to_parent,
+            Direction::ToChild => &mut self.to_child,
+        }
+    }
+
+    fn close(&mut self, reason: CloseReason) {
+        close_both(&mut self.child, &mut self.parent, &mut self.close_reason, reason);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.child.is_none() || self.parent.is_none()
+    }
+
+    fn reconnecting(&self) -> bool {
+        self.replay.as_ref().is_some_and(Replay::reconnecting)
+    }
+
+    /// Tries to reach the new compositor with --experimental-reconnect and queues the replay for
+    /// it, closes the connection if that fails for good.
+    fn reconnect(&mut self, now: Instant, opts: &Options, stats: &mut Stats) {
+        let replay = self.replay.as_mut().expect("reconnecting without a replay");
+        match replay.attempt(now) {
+            Ok(None) => {}
+            Ok(Some((parent, connected))) => {
+                log!("warning: conn {}: reconnected to the compositor, replaying the client's requests", self.id);
+                if let Some(tracker) = &mut self.tracker {
+                    tracker.parent_lost();
+                }
+                // whatever was still queued is part of the recording
+                self.to_parent.replace(replay.recorded(), now, opts, stats.direction(Direction::ToParent));
+                self.parent = Some(parent);
+                self.parent_connected = connected;
+                self.accepted_at = now;
+            }
+            Err(errno) => {
+                log!("warning: conn {}: could not reconnect to the compositor: {}, closing client", self.id, errno);
+                self.close(CloseReason::ParentConnectFailed);
+            }
+        }
+    }
+
+    /// One line summary for the --watchdog dump.
+    fn describe(&self, now: Instant) -> String {
+        format!(
+            "conn {}: {}, parent {}, to parent: {}, to child: {}{}{}{}{}",
+            self.id,
+            peer::describe(self.peer.as_ref()),
+            if self.parent_connected { "connected" } else { "connecting" },
+            self.to_parent.describe(now),
+            self.to_child.describe(now),
+            if self.handshake.is_some() { ", handshake pending" } else { "" },
+            if self.paused { ", paused" } else { "" },
+            if self.evicted.is_some() { ", closing" } else { "" },
+            if self.reconnecting() { ", reconnecting" } else { "" },
+        )
+    }
+}
+
+impl Drop for ProxiedConnection {
+    fn drop(&mut self) {
+        // whatever a closed connection still holds won't be delivered
+        for msg in self.to_parent.messages.iter_mut().chain(self.to_child.messages.iter_mut()) {
+            msg.fds.discard();
+        }
+        if let Some(tracker) = &mut self.tracker {
+            tracker.discard_fds();
+        }
+    }
+}
+
+/// Data received from one side that couldn't be sent to the other side yet.
+struct Queue {
+    messages: VecDeque<BufferedMessage>,
+    // last time the queue was empty or made progress, for --queue-flush-timeout
+    progress: Instant,
+    // every received chunk gets a sequence number, with --debug-ordering we check that
+    // chunks are completely forwarded in that order
+    next_seq: u64,
+    next_forward: u64,
+    // seq of the last message whose fds we warned about being held for long
+    fd_warned: Option<u64>,
+    // total bytes received in this direction, for --syslog
+    received: u64,
+    // and sent out of the queue, for --spin-limit
+    drained: u64,
+    // wayland messages in `messages`, for --max-inflight-messages
+    inflight: usize,
+}
+
+impl Queue {
+    fn new(now: Instant) -> Queue {
+        Queue { messages: VecDeque::new(), progress: now, next_seq: 0, next_forward: 0, fd_warned: None, received: 0, drained: 0, inflight: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn describe(&self, now: Instant) -> String {
+        if self.is_empty() {
+            return "empty".to_owned();
+        }
+        format!("{}, last progress {:.1?} ago", self.contents(), now.duration_since(self.progress))
+    }
+
+    fn contents(&self) -> String {
+        let bytes: usize = self.messages.iter().map(|m| m.payload.len()).sum();
+        let fds: usize = self.messages.iter().map(|m| m.fds.len()).sum();
+        format!("{} messages, {} bytes, {} fds", self.messages.len(), bytes, fds)
+    }
+
+    /// The oldest queued message with fds, unless we already complained about it.
+    fn held_fds(&self) -> Option<&BufferedMessage> {
+        self.messages.iter().find(|m| !m.fds.is_empty()).filter(|m| self.fd_warned != Some(m.seq))
+    }
+
+    /// The oldest queued fds if they've been waiting for longer than [`FD_HOLD_WARNING`] and we
+    /// haven't complained about them yet.
+    fn check_held_fds(&mut self, now: Instant) -> Option<(usize, Duration)> {
+        let (seq, fds, age) = self.held_fds().map(|msg| (msg.seq, msg.fds.len(), now.duration_since(msg.queued_at)))?;
+        if age < FD_HOLD_WARNING {
+            return None;
+        }
+        self.fd_warned = Some(seq);
+        Some((fds, age))
+    }
+
+    /// Drops everything queued in favor of `bytes`, which go out first, for a new peer.
+    fn replace(&mut self, bytes: &[u8], now: Instant, opts: &Options, stats: &mut DirectionStats) {
+        for mut msg in self.messages.drain(..) {
+            msg.fds.discard();
+        }
+        self.inflight = 0;
+        self.next_seq = self.next_forward;
+        self.progress = now;
+        if !bytes.is_empty() {
+            let seq = self.assign_seq();
+            let messages = if opts.max_inflight_messages.is_some() { wire::unsent_messages(bytes, 0) } else { 0 };
+            self.push(BufferedMessage::new(seq, InFlightFds::default(), bytes, now, opts, stats), messages);
+        }
+    }
+
+    fn push(&mut self, mut msg: BufferedMessage, messages: usize) {
+        msg.messages = messages;
+        self.inflight += messages;
+        self.messages.push_back(msg);
+    }
+
+    /// Like push, but for input events that go as far ahead as --reorder-safe-priority allows.
+    fn push_input(&mut self, mut msg: BufferedMessage, messages: usize, targets: &HashSet<u32>, stats: &mut DirectionStats) {
+        let at = priority::insertion_point(&self.messages, targets);
+        if at < self.messages.len() {
+            stats.reordered += 1;
+        }
+        msg.messages = messages;
+        self.inflight += messages;
+        self.messages.insert(at, msg);
+    }
+
+    fn assign_seq(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq - 1
+    }
+
+    /// Called once the last byte of chunk `seq` has been sent.
+    fn forwarded(&mut self, seq: u64, opts: &Options, conn: u64, dir: Direction) {
+        if opts.debug_ordering && seq != self.next_forward {
+            log!("ordering violation on conn {} {}->{}: forwarded chunk {} but expected {}", conn, dir.source(), dir.sink(), seq, self.next_forward);
+            std::process::abort();
+        }
+        self.next_forward = seq + 1;
+    }
+}
+
+fn control_command(command: control::Command, connections: &mut [ProxiedConnection], stats: &Stats, now: Instant) -> Result<String, String> {
+    let (id, paused) = match command {
+        control::Command::Close(id) => {
+            let conn = connections.iter_mut().find(|conn| conn.id == id).ok_or_else(|| format!("no connection {}", id))?;
+            conn.evicted.get_or_insert(now);
+            return Ok(String::new());
+        }
+        control::Command::List => return Ok(connections.iter().map(|conn| conn.describe(now) + "\n").collect()),
+        control::Command::Connections => return Ok(format!("{}\n", connections.len())),
+        control::Command::Closes => return Ok(stats.closes().map(|(reason, count)| format!("{}: {}\n", reason, count)).collect()),
+        control::Command::Metrics => return metrics::exposition(stats).ok_or_else(|| "the latency histogram is off, see --latency-histogram".to_owned()),
+        control::Command::Times => return Ok(format!("polling {:.6}\nprocessing {:.6}\n", stats.polling.as_secs_f64(), stats.processing.as_secs_f64())),
+        control::Command::Pause(id) => (id, true),
+        control::Command::Resume(id) => (id, false),
+        control::Command::Objects(id) => {
+            let conn = connections.iter().find(|conn| conn.id == id).ok_or_else(|| format!("no connection {}", id))?;
+            let tracker = conn.tracker.as_ref().ok_or_else(|| format!("connection {} isn't message framed, objects are only tracked with --message-framed or options implying it", id))?;
+            return Ok(tracker.objects().into_iter().map(|(object, interface, version)| format!("{} {} {}\n", object, interface, version)).collect());
+        }
+    };
+    let conn = connections.iter_mut().find(|conn| conn.id == id).ok_or_else(|| format!("no connection {}", id))?;
+    conn.paused = paused;
+    Ok(String::new())
+}
+
+/// Changes whenever data moves, a client is accepted or a connection closes, for --spin-limit.
+fn activity(connections: &[ProxiedConnection], stats: &Stats) -> (u64, u64) {
+    let moved = connections.iter().map(|conn| conn.to_parent.received + conn.to_parent.drained + conn.to_child.received + conn.to_child.drained + conn.is_closed() as u64).sum();
+    (stats.accepted, moved)
+}
+
+/// Whether --max-lifetime-connections clients were accepted already.
+fn lifetime_exhausted(opts: &Options, stats: &Stats) -> bool {
+    opts.max_lifetime_connections.is_some_and(|max| stats.accepted >= max)
+}
+
+/// Whether accepting is held off with --on-parent-outage wait because a client is waiting for the
+/// compositor to come back.
+fn waiting_for_parent(opts: &Options, connections: &[ProxiedConnection]) -> bool {
+    opts.on_parent_outage == Some(Outage::Wait) && connections.iter().any(ProxiedConnection::reconnecting)
+}
+
+/// Computes the (parent, child) poll flags for a connection.
+///
+/// A non-blocking connect to the parent signals completion by becoming writable, so we ask for OUT
+/// until then. Afterwards OUT is only requested on a side that has queued data, to avoid spinning
+/// on always-writable sockets. Reading from the child is deferred until the parent is connected
+/// since there's nowhere to forward its data yet and poll is level-triggered. `can_read` gates
+/// reading from both sides, e.g. when we're running out of fds. Paused connections aren't read
+/// from either, but still get their queues drained.
+fn poll_flags_for(conn: &ProxiedConnection, can_read: bool) -> (PollFlags, PollFlags) {
+    let can_read = can_read && !conn.paused && conn.evicted.is_none();
+    let mut parent_flags = PollFlags::empty();
+    let mut child_flags = PollFlags::empty();
+
+    if conn.reconnecting() {
+        // nothing to read from or send to until there's a new parent
+        if !conn.to_child.is_empty() {
+            child_flags |= PollFlags::OUT
+        }
+        return (parent_flags, child_flags);
+    }
+    if can_read {
+        parent_flags |= PollFlags::IN
+    }
+    if !conn.parent_connected || !conn.to_parent.is_empty() {
+        parent_flags |= PollFlags::OUT
+    }
+    if conn.parent_connected && can_read && !conn.child_eof {
+        child_flags |= PollFlags::IN
+    }
+    if !conn.to_child.is_empty() {
+        child_flags |= PollFlags::OUT
+    }
+
+    (parent_flags, child_flags)
+}
+
+/// The poll `timeout` in milliseconds shortened to wake up once `remaining` is over, rounded up so
+/// we don't wake up just before the deadline. Deadlines further out than poll can wait keep the
+/// longest timeout rather than wrapping around to a negative one, which would block forever.
+fn poll_timeout(timeout: i32, remaining: Duration) -> i32 {
+    timeout.min(i32::try_from(remaining.as_micros().div_ceil(1000)).unwrap_or(i32::MAX))
+}
+
+/// Splits the revents of the connection fds back into (parent, child) pairs, each connection has
+/// its two next to each other. A lone trailing entry doesn't belong to any connection.
+fn flag_pairs(flags: &[PollFlags]) -> impl Iterator<Item = (&PollFlags, &PollFlags)> {
+    flags.chunks_exact(2).map(|pair| (&pair[0], &pair[1]))
+}
+
+/// Raises the RLIMIT_NOFILE soft limit to the hard limit. Clients passing lots of dmabuf and shm
+/// fds easily get through the usual soft limit of 1024, which FdLimit then throttles on.
+fn raise_fd_limit() {
+    use rustix::process::{getrlimit, setrlimit, Resource, Rlimit};
+
+    let limit = getrlimit(Resource::Nofile);
+    // an unlimited hard limit can't actually be used, the kernel caps it at fs.nr_open
+    let wanted = limit.maximum.unwrap_or(UNLIMITED_NOFILE);
+    let Some(soft) = limit.current.filter(|&soft| soft < wanted) else {
+        return;
+    };
+    match setrlimit(Resource::Nofile, Rlimit { current: Some(wanted), maximum: limit.maximum }) {
+        Ok(()) => log!("raised the fd limit from {} to {}", soft, wanted),
+        Err(errno) => log!("warning: failed to raise the fd limit to {}: {}", wanted, errno),
+    }
+}
+
+// the default fs.nr_open
+const UNLIMITED_NOFILE: u64 = 1 << 20;
+
+/// The --max-total-fds budget: sockets of the open connections plus fds in flight.
+struct FdLimit {
+    max: usize,
+    connections: Cell<usize>,
+    exhausted: Cell<bool>,
+}
+
+// stdio, listening sockets, pty and whatever else we hold outside of connections
+const RESERVED_FDS: usize = 16;
+
+impl FdLimit {
+    fn new(opts: &Options) -> FdLimit {
+        let max = opts.max_total_fds.unwrap_or_else(|| {
+            let limit = rustix::process::getrlimit(rustix::process::Resource::Nofile);
+            limit.current.map_or(usize::MAX, |soft| soft.try_into().unwrap_or(usize::MAX))
+        });
+        FdLimit { max, connections: Cell::new(0), exhausted: Cell::new(false) }
+    }
+
+    fn used(&self) -> usize {
+        RESERVED_FDS + 2 * self.connections.get() + fds::in_flight()
+    }
+
+    /// Whether there's room for the two sockets of another connection.
+    fn can_accept(&self) -> bool {
+        self.used() + 2 <= self.max
+    }
+
+    /// Whether there's room for a full SCM_RIGHTS payload.
+    fn can_read(&self) -> bool {
+        self.used() + SCM_MAX_FD <= self.max
+    }
+
+    /// Track the number of open connections and log when we start and stop throttling.
+    fn update(&self, connections: usize) {
+        self.connections.set(connections);
+        let exhausted = !self.can_read();
+        if exhausted != self.exhausted.replace(exhausted) {
+            match exhausted {
+                true => log!("warning: near the fd limit of {} ({} in flight), pausing reads and accepts", self.max, fds::in_flight()),
+                false => log!("fd usage back below the limit of {}, resuming", self.max),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    ToParent,
+    ToChild,
+}
+
+impl Direction {
+    fn source(self) -> &'static str {
+        match self {
+            Direction::ToParent => "child",
+            Direction::ToChild => "parent",
+        }
+    }
+
+    fn sink(self) -> &'static str {
+        match self {
+            Direction::ToParent => "parent",
+            Direction::ToChild => "child",
+        }
+    }
+
+    fn source_eof(self) -> CloseReason {
+        match self {
+            Direction::ToParent => CloseReason::ClientEof,
+            Direction::ToChild => CloseReason::ServerEof,
+        }
+    }
+
+    fn source_reset(self) -> CloseReason {
+        match self {
+            Direction::ToParent => CloseReason::ClientReset,
+            Direction::ToChild => CloseReason::ServerReset,
+        }
+    }
+
+    fn sink_reset(self) -> CloseReason {
+        match self {
+            Direction::ToParent => CloseReason::ServerReset,
+            Direction::ToChild => CloseReason::ClientReset,
+        }
+    }
+}
+
+/// Why a connection was torn down, for the logs, --stats and the control socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum CloseReason {
+    ClientEof,
+    ServerEof,
+    ClientReset,
+    ServerReset,
+    /// the traffic broke a rule, e.g. --strict-handshake or a malformed message
+    Filtered,
+    /// one of the size, count or time limits was exceeded
+    LimitExceeded,
+    ParentConnectFailed,
+    /// the close command on the control socket
+    Control,
+    /// handling the connection panicked, see Proxy::step
+    Panic,
+    /// --experimental-reconnect can't restore the client's state on a new compositor
+    NotReplayable,
+    /// closed by [`Proxy::shutdown`]
+    Shutdown,
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CloseReason::ClientEof => "client eof",
+            CloseReason::ServerEof => "server eof",
+            CloseReason::ClientReset => "client reset",
+            CloseReason::ServerReset => "server reset",
+            CloseReason::Filtered => "filtered",
+            CloseReason::LimitExceeded => "limit exceeded",
+            CloseReason::ParentConnectFailed => "parent connect failed",
+            CloseReason::Control => "control",
+            CloseReason::Panic => "panic",
+            CloseReason::NotReplayable => "not replayable",
+            CloseReason::Shutdown => "shutdown",
+        })
+    }
+}
+
+/// Closes both sides, the first reason recorded for a connection is the one that sticks.
+fn close_both(from: &mut Option<OwnedFd>, to: &mut Option<OwnedFd>, close_reason: &mut Option<CloseReason>, reason: CloseReason) {
+    from.take();
+    to.take();
+    close_reason.get_or_insert(reason);
+}
+
+// receive buffer for SOCK_SEQPACKET connections, larger than any single wayland message
+const MAX_PACKET: usize = 64 * 1024;
+// received per transfer_or_queue call before a chatty connection has to let the others have their
+// turn, poll is level-triggered so the rest is picked up in the next iteration
+const TRANSFER_BUDGET: usize = 64 * 1024;
+
+fn transfer_or_queue(conn: &mut ProxiedConnection, dir: Direction, from_flags: &PollFlags, now: Instant, opts: &Options, fd_limit: &FdLimit, stats: &mut Stats) -> Result<(), ProxyError> {
+    if !from_flags.contains(PollFlags::IN) {
+        return Ok(());
+    }
+
+    let ProxiedConnection { id, parent, child, to_parent, to_child, handshake, tracker, seqpacket, replay, passes_fds, close_reason, recorder, child_eof, .. } = conn;
+    let seqpacket = *seqpacket;
+    // what the client sent so far, for --record-events
+    let requests = to_parent.received;
+    let mut no_check = None;
+    let (from, to, queued, handshake) = match dir {
+        Direction::ToParent => (child, parent, to_parent, handshake),
+        Direction::ToChild => (parent, child, to_child, &mut no_check),
+    };
+    let stats = stats.direction(dir);
+
+    let mut stream_buf = [0u8; 1024];
+    // a packet has to be received in one piece, anything that doesn't fit is cut off by the kernel
+    let mut packet_buf = Vec::new();
+    let bytes: &mut [u8] = match seqpacket {
+        true => {
+            packet_buf.resize(MAX_PACKET, 0);
+            &mut packet_buf
+        }
+        false => &mut stream_buf,
+    };
+    // this is the max per sendmsg
+    let mut space = [0; rustix::cmsg_space!(ScmRights(SCM_MAX_FD))];
+
+    // assumption: we don't receive FDs too often so this won't actually get allocated
+    let mut fds = InFlightFds::default();
+    let mut received = 0;
+
+
+    loop {
+        let mut recv_cmsg = RecvAncillaryBuffer::new(&mut space);
+
+        if from.is_none() || to.is_none() || received >= TRANSFER_BUDGET {
+            return Ok(())
+        }
+
+        // a single recvmsg could push us over the limit, the kernel would then silently drop fds
+        if !fd_limit.can_read() {
+            return Ok(())
+        }
+
+        // poll may get EINTR'd and restarted by the main loop, recv and send are retried in place
+        match retry_on_intr(|| recvmsg(from.as_ref().expect("Some(from fd)"), &mut [IoSliceMut::new(bytes)], &mut recv_cmsg, RecvFlags::CMSG_CLOEXEC)) {
+            Err(e) if e == Errno::CONNRESET => {
+                close_both(from, to, close_reason, dir.source_reset());
+                return Ok(())
+            }
+            Err(e) if e == Errno::WOULDBLOCK || e == Errno::AGAIN => return Ok(()),
+            Err(errno) => return Err(ProxyError::Recv { context: dir.source().to_owned(), errno }),
+            Ok(recv) => {
+                if recv.bytes == 0 && dir == Direction::ToParent {
+                    *child_eof = true;
+                    if queued.is_empty() {
+                        shutdown_parent(to);
+                    }
+                    return Ok(())
+                }
+                if recv.bytes == 0 {
+                    // EOF, close connections.
+                    // TODO: this is kinda dirty, we could shutdown more gracefully by draining messages that are still buffered if the sending side is still open
+                    close_both(from, to, close_reason, dir.source_eof());
+                    return Ok(())
+                }
+    
+                if !opts.direction.allows(dir) {
+                    log!("closing conn {}: the {} sent data, which --direction {} doesn't allow", id, dir.source(), opts.direction.name());
+                    recv_cmsg.drain().for_each(drop);
+                    close_both(from, to, close_reason, CloseReason::Filtered);
+                    return Ok(())
+                }
+
+                if recv.flags.contains(RecvFlags::TRUNC) {
+                    log!("closing conn {}: packet from the {} larger than {} bytes", id, dir.source(), MAX_PACKET);
+                    recv_cmsg.drain().for_each(drop);
+                    close_both(from, to, close_reason, CloseReason::LimitExceeded);
+                    return Ok(())
+                }
+
+                if fds::truncated(recv.flags) {
+                    log!("closing conn {}: the {} passed more than {} fds at once, the rest were lost", id, dir.source(), SCM_MAX_FD);
+                    recv_cmsg.drain().for_each(drop);
+                    close_both(from, to, close_reason, CloseReason::LimitExceeded);
+                    return Ok(())
+                }
+
+                let bytes = &bytes[0..recv.bytes];
+                received += recv.bytes;
+                queued.received += recv.bytes as u64;
+                stats.bytes += recv.bytes as u64;
+                recv_cmsg.drain().for_each(|msg| {
+                    if let RecvAncillaryMessage::ScmRights(rights) = msg {
+                        fds.extend(rights)
+                    }
+                });
+                drop(recv_cmsg);
+
+                if !*passes_fds && !fds.is_empty() {
+                    log!("closing conn {}: the {} passed fds, which can't be forwarded over TCP", id, dir.source());
+                    fds.discard();
+                    close_both(from, to, close_reason, CloseReason::Filtered);
+                    return Ok(())
+                }
+
+                if opts.trace_fds {
+                    for fd in fds.iter() {
+                        log!("fd conn {} {}->{}: {}", id, dir.source(), dir.sink(), fdinfo::describe(fd.as_fd()));
+                    }
+                }
+
+                if let Some(check) = handshake {
+                    match check.feed(bytes) {
+                        Handshake::Incomplete => {},
+                        Handshake::Valid => *handshake = None,
+                        Handshake::Invalid(header) => {
+                            *stats.filtered.entry(stats::Filtered::RejectedHandshake).or_default() += 1;
+                            log!("closing connection, first message is not a wl_display request: {:?}", header);
+                            fds.discard();
+                            close_both(from, to, close_reason, CloseReason::Filtered);
+                            return Ok(())
+                        }
+                    }
+                }
+
+                let framed;
+                let bytes = match tracker {
+                    Some(tracker) => match tracker.process(dir, bytes, &mut fds, opts, stats) {
+                        Ok(complete) => {
+                            framed = complete;
+                            &framed[..]
+                        }
+                        Err((reason, msg)) => {
+                            log!("closing conn {}: {}", id, msg);
+                            fds.discard();
+                            close_both(from, to, close_reason, reason);
+                            return Ok(())
+                        }
+                    },
+                    None => bytes,
+                };
+                if bytes.is_empty() {
+                    // only part of a message so far, the tracker holds on to it
+                    continue;
+                }
+                if let (Direction::ToChild, Some(recording), Some(tracker)) = (dir, recorder.as_mut(), tracker.as_ref()) {
+                    if let Err(e) = recording.record(requests, bytes, fds.len(), tracker) {
+                        log!("warning: failed to write --record-events: {}, recording stopped", e);
+                        *recorder = None;
+                    }
+                }
+                if dir == Direction::ToParent && replay.as_mut().is_some_and(|replay| !replay.record(bytes, fds.len())) {
+                    *replay = None;
+                }
+                if opts.hexdump {
+                    trace::hexdump(*id, dir, bytes, fds.len(), opts.hexdump_limit);
+                }
+
+                let seq = queued.assign_seq();
+                // messages that would still be queued after sending this many bytes, only counted
+                // when they're whole and someone cares
+                let unsent = |sent| match (opts.max_inflight_messages, tracker.is_some()) {
+                    (Some(_), true) => wire::unsent_messages(bytes, sent),
+                    _ => 0,
+                };
+
+                if !queued.is_empty() {
+                    // earlier data is still waiting, sending directly would overtake it
+                    let input = match (dir, tracker.as_ref()) {
+                        (Direction::ToChild, Some(tracker)) if opts.reorder_safe_priority => priority::input_targets(tracker, bytes, !fds.is_empty()),
+                        _ => None,
+                    };
+                    let msg = BufferedMessage::new(seq, mem::take(&mut fds), bytes, now, opts, stats);
+                    match input {
+                        Some(targets) => queued.push_input(msg, unsent(0), &targets, stats),
+                        None => queued.push(msg, unsent(0)),
+                    }
+                    return Ok(())
+                }
+
+                // attempt direct resend, queue otherwise
+
+                let to_send: Vec<_>;
+                let mut send_cmsg = match fds.is_empty() {
+                    // the common case, and TCP sockets reject SCM_RIGHTS even without any fds
+                    true => SendAncillaryBuffer::default(),
+                    false => {
+                        space.fill(0);
+                        to_send = fds.iter().map(|fd| fd.as_fd()).collect();
+                        let mut send_cmsg = SendAncillaryBuffer::new(&mut space);
+                        send_cmsg.push(SendAncillaryMessage::ScmRights(&to_send));
+                        send_cmsg
+                    }
+                };
+
+                match retry_on_intr(|| sendmsg(to.as_ref().expect("Some(to fd)"), &[IoSlice::new(bytes)], &mut send_cmsg, SendFlags::NOSIGNAL)) {
+                    // the peer shut down its read side or went away
+                    Ok(0) | Err(Errno::CONNRESET | Errno::PIPE) => {
+                        fds.discard();
+                        close_both(from, to, close_reason, dir.sink_reset());
+                        return Ok(())
+                    }
+                    Ok(sent) if sent == bytes.len() => {
+                        fds.forwarded();
+                        queued.forwarded(seq, opts, *id, dir);
+                        if let Some(latency) = &mut stats.latency {
+                            latency.observe(*id, Duration::ZERO);
+                        }
+                    }
+                    Ok(sent) if seqpacket => {
+                        short_packet(*id, dir, sent, bytes.len());
+                        fds.discard();
+                        close_both(from, to, close_reason, CloseReason::LimitExceeded);
+                        return Ok(())
+                    }
+                    Ok(sent) => {
+                        // the socket buffer is full, the rest has to wait for POLLOUT. the fds went
+                        // out with the first byte, see BufferedMessage::consume
+                        stats.partial_sends += 1;
+                        fds.forwarded();
+                        queued.push(BufferedMessage::new(seq, InFlightFds::default(), &bytes[sent..], now, opts, stats), unsent(sent));
+                        return Ok(())
+                    }
+                    Err(e) if e == Errno::WOULDBLOCK || e == Errno::AGAIN => {
+                        stats.wouldblock_requeues += 1;
+                        queued.push(BufferedMessage::new(seq, mem::take(&mut fds), bytes, now, opts, stats), unsent(0));
+                        return Ok(())
+                    },
+                    Err(errno) => {
+                        fds.discard();
+                        return Err(ProxyError::Send { context: dir.sink().to_owned(), errno })
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+/// Returns whether any queued bytes were sent. Sends at most --max-drain-bytes per call, poll
+/// keeps reporting the socket as writable so the rest goes out in the next iterations.
+fn drain_queue(conn: &mut ProxiedConnection, dir: Direction, to_flags: &PollFlags, now: Instant, opts: &Options, stats: &mut Stats) -> Result<bool, ProxyError> {
+    let mut progress = false;
+
+    let (to, queued) = match dir {
+        Direction::ToParent => (&mut conn.parent, &mut conn.to_parent),
+        Direction::ToChild => (&mut conn.child, &mut conn.to_child),
+    };
+    let stats = stats.direction(dir);
+
+    if !to_flags.contains(PollFlags::OUT) {
+        return Ok(progress);
+    }
+
+    let mut budget = opts.max_drain_bytes.unwrap_or(usize::MAX);
+    loop {
+        if to.is_none() || budget == 0 {
+            return Ok(progress);
+        }
+
+        let Some(mut msg) = queued.messages.pop_front() else {
+            return Ok(progress);
+        };
+        let bytes = msg.payload.plain();
+        let len = bytes.len();
+        // packets can't be split, the budget only applies between them
+        let limit = if conn.seqpacket { len } else { len.min(budget) };
+
+        let mut space;
+        let to_send: Vec<_>;
+        let mut send_cmsg = match msg.fds.is_empty() {
+            true => SendAncillaryBuffer::default(),
+            false => {
+                space = [0; rustix::cmsg_space!(ScmRights(SCM_MAX_FD))];
+                to_send = msg.fds.iter().map(|fd| fd.as_fd()).collect();
+                let mut send_cmsg = SendAncillaryBuffer::new(&mut space);
+                send_cmsg.push(SendAncillaryMessage::ScmRights(&to_send));
+                send_cmsg
+            }
+        };
+
+        let (front, back) = bytes.as_slices();
+        let front = &front[..front.len().min(limit)];
+        let back = &back[..back.len().min(limit - front.len())];
+
+        match retry_on_intr(|| sendmsg(to.as_ref().expect("Some(to fd)"), &[IoSlice::new(front), IoSlice::new(back)], &mut send_cmsg, SendFlags::NOSIGNAL)) {
+            Ok(0) | Err(Errno::CONNRESET | Errno::PIPE) => {
+                msg.fds.discard();
+                to.take();
+                conn.close_reason.get_or_insert(dir.sink_reset());
+                return Ok(progress)
+            }
+            Ok(sent) if sent == len => {
+                msg.fds.forwarded();
+                queued.inflight -= msg.messages;
+                queued.drained += sent as u64;
+                budget = budget.saturating_sub(sent);
+                progress = true;
+                if let Some(latency) = &mut stats.latency {
+                    latency.observe(conn.id, now.duration_since(msg.queued_at));
+                }
+                queued.forwarded(msg.seq, opts, conn.id, dir);
+                if dir == Direction::ToParent && conn.child_eof && queued.is_empty() {
+                    shutdown_parent(to);
+                }
+            }
+            Ok(sent) if conn.seqpacket => {
+                short_packet(conn.id, dir, sent, len);
+                msg.fds.discard();
+                to.take();
+                conn.close_reason.get_or_insert(CloseReason::LimitExceeded);
+                return Ok(progress)
+            }
+            Ok(sent) => {
+                // the rest has to go out before anything else queued
+                progress = true;
+                queued.drained += sent as u64;
+                if sent < limit {
+                    stats.partial_sends += 1;
+                }
+                msg.consume(sent);
+                queued.messages.push_front(msg);
+                return Ok(progress)
+            }
+            Err(e) if e == Errno::WOULDBLOCK || e == Errno::AGAIN => {
+                // nothing was sent, fds included
+                stats.wouldblock_requeues += 1;
+                queued.messages.push_front(msg);
+                return Ok(progress)
+            },
+            Err(errno) => {
+                msg.fds.discard();
+                return Err(ProxyError::Send { context: dir.sink().to_owned(), errno })
+            }
+        }
+    }
+}
+
+/// Passes on the client's half-close once everything it sent has been forwarded.
+fn shutdown_parent(parent: &Option<OwnedFd>) {
+    if let Some(parent) = parent {
+        // if this fails the parent is gone and we'll see that soon enough
+        let _ = rustix::net::shutdown(parent, rustix::net::Shutdown::Write);
+    }
+}
+
+// SOCK_SEQPACKET sends are all or nothing, a short one means the packet was cut off. The receiver
+// would see a truncated message and the rest we can't send as a packet of its own, so the
+// connection is lost.
+fn short_packet(id: u64, dir: Direction, sent: usize, len: usize) {
+    log!("closing conn {}: only {} of {} bytes of a packet were sent to the {}", id, sent, len, dir.sink());
+}
+
+struct BufferedMessage {
+    seq: u64,
+    fds: InFlightFds,
+    payload: Payload,
+    queued_at: Instant,
+    // wayland messages not yet completely sent, a partially sent chunk keeps counting the ones
+    // it started with
+    messages: usize,
+}
+
+// smaller chunks rarely compress well enough to be worth the effort
+const COMPRESS_THRESHOLD: usize = 256;
+
+impl BufferedMessage {
+    /// Copies `bytes` into a new message, compressing them with --compress-queue.
+    fn new(seq: u64, fds: InFlightFds, bytes: &[u8], queued_at: Instant, opts: &Options, stats: &mut DirectionStats) -> BufferedMessage {
+        let mut payload = Payload::Plain(bytes.iter().copied().collect());
+        if opts.compress_queue && bytes.len() >= COMPRESS_THRESHOLD {
+            let compressed = lz4::compress(bytes);
+            if compressed.len() < bytes.len() {
+                stats.compressed_messages += 1;
+                stats.compression_saved_bytes += (bytes.len() - compressed.len()) as u64;
+                payload = Payload::Compressed { len: bytes.len(), data: compressed.into_boxed_slice() };
+            }
+        }
+        BufferedMessage { seq, fds, payload, queued_at, messages: 0 }
+    }
+
+    /// Drops the first `sent` bytes after a partial send.
+    ///
+    /// The kernel attaches ancillary data to the first byte of a send. So sendmsg either passes
+    /// all fds along with at least one byte, or fails with WOULDBLOCK and passes none. After a
+    /// partial send the fds have been delivered and must not go out again with the rest.
+    fn consume(&mut self, sent: usize) {
+        debug_assert!(sent > 0 && sent < self.payload.len(), "partial send of {} out of {} bytes", sent, self.payload.len());
+        self.fds.forwarded();
+        self.payload.plain().drain(..sent);
+    }
+}
+
+enum Payload {
+    Plain(VecDeque<u8>),
+    /// An lz4 block decompressing to `len` bytes.
+    Compressed { len: usize, data: Box<[u8]> },
+}
+
+impl Payload {
+    /// Uncompressed size.
+    fn len(&self) -> usize {
+        match self {
+            Payload::Plain(bytes) => bytes.len(),
+            Payload::Compressed { len, .. } => *len,
+        }
+    }
+
+    /// The bytes, decompressing them in place if needed.
+    fn plain(&mut self) -> &mut VecDeque<u8> {
+        if let Payload::Compressed { .. } = self {
+            *self = Payload::Plain(mem::replace(self, Payload::Plain(VecDeque::new())).into_plain());
+        }
+        match self {
+            Payload::Plain(bytes) => bytes,
+            Payload::Compressed { .. } => unreachable!(),
+        }
+    }
+
+    fn into_plain(self) -> VecDeque<u8> {
+        match self {
+            Payload::Plain(bytes) => bytes,
+            Payload::Compressed { len, data } => lz4::decompress(&data, len).expect("corrupted compressed queue entry").into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::{Mutex, MutexGuard};
+
+    use rustix::fs::{memfd_create, MemfdFlags};
+    use rustix::net::socketpair;
+
+    /// fds::in_flight and the set of open fds are process-global, tests opening fds take turns.
+    pub(crate) fn serial() -> MutexGuard<'static, ()> {
+        static TURN: Mutex<()> = Mutex::new(());
+        TURN.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A connected pair of non-blocking unix stream sockets.
+    fn pair() -> (OwnedFd, OwnedFd) {
+        socketpair(AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None).expect("socketpair")
+    }
+
+    /// A connection along with the far ends of its child and parent sockets.
+    fn connection(opts: &Options, now: Instant) -> (ProxiedConnection, OwnedFd, OwnedFd) {
+        let (child, client) = pair();
+        let (parent, compositor) = pair();
+        (ProxiedConnection::new(1, child, parent, true, Protocol::Wayland, opts, now), client, compositor)
+    }
+
+    fn enqueue(queue: &mut Queue, fds: InFlightFds, bytes: &[u8], now: Instant, opts: &Options) {
+        let seq = queue.assign_seq();
+        queue.push(BufferedMessage::new(seq, fds, bytes, now, opts, &mut DirectionStats::default()), 0);
+    }
+
+    /// Polls the connection's sockets for what it asks for and handles whatever they report.
+    fn handle(conn: &mut ProxiedConnection, now: Instant, opts: &Options, stats: &mut Stats) -> Result<(), ProxyError> {
+        let (parent, child) = poll_flags_for(conn, true);
+        let mut fds = [PollFd::from_borrowed_fd(conn.parent.as_ref().expect("open").as_fd(), parent), PollFd::from_borrowed_fd(conn.child.as_ref().expect("open").as_fd(), child)];
+        poll(&mut fds, 0).expect("poll");
+        handle_connection(conn, &fds[0].revents(), &fds[1].revents(), now, opts, &FdLimit::new(opts), stats)
+    }
+
+    fn memfds(n: usize) -> InFlightFds {
+        let mut fds = InFlightFds::default();
+        fds.extend((0..n).map(|_| memfd_create("p5wl-test", MemfdFlags::CLOEXEC).expect("memfd_create")));
+        fds
+    }
+
+    /// Whatever the socket has ready without blocking, and the number of fds that came with it.
+    fn recv_ready(fd: &OwnedFd) -> (Vec<u8>, usize) {
+        let mut received = Vec::new();
+        let mut fds = 0;
+        loop {
+            let mut buf = [0u8; 4096];
+            let mut space = [0; rustix::cmsg_space!(ScmRights(SCM_MAX_FD))];
+            let mut cmsg = RecvAncillaryBuffer::new(&mut space);
+            match recvmsg(fd, &mut [IoSliceMut::new(&mut buf)], &mut cmsg, RecvFlags::CMSG_CLOEXEC) {
+                Ok(msg) if msg.bytes > 0 => {
+                    for m in cmsg.drain() {
+                        if let RecvAncillaryMessage::ScmRights(rights) = m {
+                            fds += rights.count();
+                        }
+                    }
+                    received.extend_from_slice(&buf[..msg.bytes]);
+                }
+                _ => return (received, fds),
+            }
+        }
+    }
+
+    #[test]
+    fn poll_flags() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        for bits in 0..1u32 << 7 {
+            let bit = |n: u32| bits & 1 << n != 0;
+            let (parent_connected, to_parent, to_child, paused, evicted, child_eof, can_read) = (bit(0), bit(1), bit(2), bit(3), bit(4), bit(5), bit(6));
+            let (mut conn, _client, _compositor) = connection(&opts, now);
+            conn.parent_connected = parent_connected;
+            if to_parent {
+                enqueue(&mut conn.to_parent, InFlightFds::default(), b"request", now, &opts);
+            }
+            if to_child {
+                enqueue(&mut conn.to_child, InFlightFds::default(), b"event", now, &opts);
+            }
+            conn.paused = paused;
+            conn.evicted = evicted.then_some(now);
+            conn.child_eof = child_eof;
+
+            let reading = can_read && !paused && !evicted;
+            let mut parent = PollFlags::empty();
+            let mut child = PollFlags::empty();
+            if reading {
+                parent |= PollFlags::IN;
+            }
+            if !parent_connected || to_parent {
+                parent |= PollFlags::OUT;
+            }
+            if reading && parent_connected && !child_eof {
+                child |= PollFlags::IN;
+            }
+            if to_child {
+                child |= PollFlags::OUT;
+            }
+            let state = format!("parent_connected {} to_parent {} to_child {} paused {} evicted {} child_eof {} can_read {}", parent_connected, to_parent, to_child, paused, evicted, child_eof, can_read);
+            assert_eq!(poll_flags_for(&conn, can_read), (parent, child), "{}", state);
+        }
+    }
+
+    /// A message whose payload wraps around the end of its ring buffer, so it's sent in two
+    /// chunks, `front` bytes and then the rest.
+    fn wrapped(fds: InFlightFds, front: usize, back: usize, now: Instant, opts: &Options) -> (BufferedMessage, Vec<u8>) {
+        let mut deque = VecDeque::with_capacity(front + back);
+        let capacity = deque.capacity();
+        // moves the start of the empty buffer along
+        for _ in 0..capacity - front {
+            deque.push_back(0);
+            deque.pop_front();
+        }
+        deque.extend((0..front + back).map(|i| i as u8));
+        assert_eq!(deque.as_slices().0.len(), front);
+        let expected = deque.iter().copied().collect();
+        let mut msg = BufferedMessage::new(0, fds, &[], now, opts, &mut DirectionStats::default());
+        msg.payload = Payload::Plain(deque);
+        (msg, expected)
+    }
+
+    #[test]
+    fn consume_inside_first_chunk() {
+        let _turn = serial();
+        let opts = Options::default();
+        let before = fds::in_flight();
+        let (mut msg, expected) = wrapped(memfds(2), 10, 20, Instant::now(), &opts);
+        assert_eq!(fds::in_flight(), before + 2);
+        msg.consume(4);
+        assert!(msg.fds.is_empty());
+        assert_eq!(fds::in_flight(), before);
+        assert_eq!(msg.payload.plain().iter().copied().collect::<Vec<_>>(), expected[4..]);
+        // the rest goes out without the fds
+        msg.consume(10);
+        assert_eq!(fds::in_flight(), before);
+        assert_eq!(msg.payload.plain().iter().copied().collect::<Vec<_>>(), expected[14..]);
+    }
+
+    #[test]
+    fn consume_after_first_chunk() {
+        let _turn = serial();
+        let opts = Options::default();
+        let before = fds::in_flight();
+        let (mut msg, expected) = wrapped(memfds(3), 10, 20, Instant::now(), &opts);
+        msg.consume(15);
+        assert!(msg.fds.is_empty());
+        assert_eq!(fds::in_flight(), before);
+        assert_eq!(msg.payload.plain().iter().copied().collect::<Vec<_>>(), expected[15..]);
+    }
+
+    #[test]
+    fn partial_drain_sends_fds_once() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        let mut stats = Stats::new(&opts);
+        let before = fds::in_flight();
+        let (mut conn, client, _compositor) = connection(&opts, now);
+        let bytes: Vec<u8> = (0..1 << 20).map(|i: usize| (i % 251) as u8).collect();
+        enqueue(&mut conn.to_child, memfds(2), &bytes, now, &opts);
+
+        assert!(drain_queue(&mut conn, Direction::ToChild, &PollFlags::OUT, now, &opts, &mut stats).expect("drain"));
+        // more than fits in the socket buffer, the rest waits without its fds
+        assert_eq!(conn.to_child.messages.len(), 1);
+        assert!(conn.to_child.messages[0].fds.is_empty());
+        assert_eq!(fds::in_flight(), before);
+
+        let mut received = Vec::new();
+        let mut arrivals = Vec::new();
+        while received.len() < bytes.len() {
+            let (chunk, n) = recv_ready(&client);
+            assert!(!chunk.is_empty() || !conn.to_child.is_empty(), "{} of {} bytes arrived", received.len(), bytes.len());
+            arrivals.push((received.len(), n));
+            received.extend(chunk);
+            drain_queue(&mut conn, Direction::ToChild, &PollFlags::OUT, now, &opts, &mut stats).expect("drain");
+        }
+        assert_eq!(received, bytes);
+        assert!(conn.to_child.is_empty());
+        // all fds arrived with the first byte
+        assert_eq!(arrivals.iter().map(|(_, n)| n).sum::<usize>(), 2);
+        assert_eq!(arrivals[0], (0, 2));
+        assert_eq!(fds::in_flight(), before);
+    }
+
+    #[test]
+    fn hang_up_after_sending() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        let mut stats = Stats::new(&opts);
+        let (mut conn, client, compositor) = connection(&opts, now);
+        let events: Vec<u8> = (0..5000).map(|i: usize| i as u8).collect();
+        assert_eq!(rustix::io::write(&compositor, &events), Ok(events.len()));
+        drop(compositor);
+
+        // HUP comes with IN, what the compositor sent goes through before the EOF closes
+        handle(&mut conn, now, &opts, &mut stats).expect("handle");
+        assert_eq!(conn.close_reason, Some(CloseReason::ServerEof));
+        assert_eq!(recv_ready(&client), (events, 0));
+    }
+
+    #[test]
+    fn hang_up_with_queued_data() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        let mut stats = Stats::new(&opts);
+        let before = fds::in_flight();
+        let (mut conn, client, compositor) = connection(&opts, now);
+        enqueue(&mut conn.to_parent, memfds(1), b"request", now, &opts);
+        drop(compositor);
+
+        // nobody to deliver the queued request to, it goes away with the connection
+        handle(&mut conn, now, &opts, &mut stats).expect("handle");
+        assert!(conn.is_closed());
+        assert_eq!(conn.close_reason, Some(CloseReason::ServerEof));
+        drop(conn);
+        assert_eq!(fds::in_flight(), before);
+        assert_eq!(recv_ready(&client), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn client_half_close() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        let mut stats = Stats::new(&opts);
+        let (mut conn, client, compositor) = connection(&opts, now);
+        enqueue(&mut conn.to_parent, InFlightFds::default(), b"queued ", now, &opts);
+        assert_eq!(rustix::io::write(&client, b"request"), Ok(7));
+        rustix::net::shutdown(&client, rustix::net::Shutdown::Write).expect("shutdown");
+
+        // a few rounds, the EOF may be read before the queue is drained
+        for _ in 0..3 {
+            handle(&mut conn, now, &opts, &mut stats).expect("handle");
+        }
+        assert!(!conn.is_closed());
+        assert!(conn.child_eof);
+        assert_eq!(recv_ready(&compositor), (b"queued request".to_vec(), 0));
+        let mut buf = [0u8; 16];
+        // the compositor sees the half-close once everything arrived
+        assert_eq!(rustix::io::read(&compositor, &mut buf), Ok(0));
+
+        assert_eq!(rustix::io::write(&compositor, b"reply"), Ok(5));
+        handle(&mut conn, now, &opts, &mut stats).expect("handle");
+        assert!(!conn.is_closed());
+        assert_eq!(recv_ready(&client), (b"reply".to_vec(), 0));
+
+        drop(compositor);
+        handle(&mut conn, now, &opts, &mut stats).expect("handle");
+        assert_eq!(conn.close_reason, Some(CloseReason::ServerEof));
+    }
+
+    #[test]
+    fn drain_to_closed_peer() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        let mut stats = Stats::new(&opts);
+        let before = fds::in_flight();
+        for shutdown_only in [true, false] {
+            let (mut conn, _client, compositor) = connection(&opts, now);
+            enqueue(&mut conn.to_parent, memfds(1), b"request", now, &opts);
+            match shutdown_only {
+                true => rustix::net::shutdown(&compositor, rustix::net::Shutdown::Read).expect("shutdown"),
+                false => drop(compositor),
+            }
+            // EPIPE, which closes just this connection rather than being an error
+            assert_eq!(drain_queue(&mut conn, Direction::ToParent, &PollFlags::OUT, now, &opts, &mut stats).map_err(|e| e.to_string()), Ok(false));
+            assert!(conn.is_closed());
+            assert_eq!(conn.close_reason, Some(CloseReason::ServerReset));
+            drop(conn);
+            assert_eq!(fds::in_flight(), before);
+        }
+    }
+
+    #[test]
+    fn send_to_closed_peer() {
+        let _turn = serial();
+        let opts = Options::default();
+        let now = Instant::now();
+        let mut stats = Stats::new(&opts);
+        let (mut conn, client, compositor) = connection(&opts, now);
+        rustix::net::shutdown(&compositor, rustix::net::Shutdown::Read).expect("shutdown");
+        assert_eq!(rustix::io::write(&client, b"request"), Ok(7));
+        transfer_or_queue(&mut conn, Direction::ToParent, &PollFlags::IN, now, &opts, &FdLimit::new(&opts), &mut stats).expect("transfer");
+        assert_eq!(conn.close_reason, Some(CloseReason::ServerReset));
+    }
+
+    #[test]
+    fn flag_pairs_leave_out_a_trailing_entry() {
+        let all = [PollFlags::IN, PollFlags::OUT, PollFlags::PRI, PollFlags::ERR, PollFlags::HUP];
+        for len in 0..=all.len() {
+            let pairs: Vec<_> = flag_pairs(&all[..len]).map(|(parent, child)| (*parent, *child)).collect();
+            let expected: Vec<_> = (0..len / 2).map(|i| (all[2 * i], all[2 * i + 1])).collect();
+            assert_eq!(pairs, expected, "{} flags", len);
+        }
+    }
+
+    #[test]
+    fn poll_timeout_for_distant_deadlines() {
+        let thirty_days = Duration::from_secs(30 * 24 * 60 * 60);
+        assert_eq!(poll_timeout(30000, thirty_days), 30000);
+        assert_eq!(poll_timeout(i32::MAX, thirty_days), i32::MAX);
+        assert_eq!(poll_timeout(30000, Duration::from_micros(1500)), 2);
+        assert_eq!(poll_timeout(30000, Duration::ZERO), 0);
+    }
+
+    /// A fresh directory with a compositor socket in it and a proxy socket forwarding to it.
+    fn sockets(name: &str) -> (PathBuf, UnixListener, Listener) {
+        let dir = std::env::temp_dir().join(format!("p5wl-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        let compositor_path = dir.join("compositor");
+        let compositor = UnixListener::bind(&compositor_path).expect("bind compositor");
+        let listener = Listener::bind(dir.join("proxy"), Some(compositor_path), Protocol::Wayland, SocketType::STREAM, false).expect("bind proxy");
+        (dir, compositor, listener)
+    }
+
+    #[test]
+    fn reconnect_gives_up_on_the_clock() {
+        let _turn = serial();
+        let (dir, compositor, listener) = sockets("reconnect-test");
+        let compositor_path = dir.join("compositor");
+        let client = UnixStream::connect(&listener.path).expect("connect");
+
+        let opts = Options { experimental_reconnect: true, ..Options::default() };
+        let clock = Arc::new(MockClock::new());
+        let mut proxy = Proxy::new(&opts, vec![listener], clock.clone()).expect("proxy");
+        proxy.step(0, &[]).expect("step");
+        assert_eq!(proxy.connection_count(), 1);
+
+        // the compositor goes away for good
+        let (conn, _) = compositor.accept().expect("accept");
+        drop((conn, compositor));
+        std::fs::remove_file(&compositor_path).expect("unlink compositor");
+        for _ in 0..10 {
+            proxy.step(0, &[]).expect("step");
+        }
+        assert!(proxy.connections[0].reconnecting());
+
+        // retries keep failing until they give up, which only the clock decides
+        clock.advance(Duration::from_secs(10));
+        proxy.step(0, &[]).expect("step");
+        assert_eq!(proxy.connection_count(), 0);
+        assert_eq!(proxy.stats.closes.get(&CloseReason::ParentConnectFailed), Some(&1));
+        let mut buf = [0u8; 16];
+        assert_eq!(rustix::io::read(&client, &mut buf), Ok(0));
+        drop(proxy);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn spin_sleeps_on_the_clock() {
+        let _turn = serial();
+        let (dir, _compositor, listener) = sockets("spin-test");
+        let opts = Options { spin_limit: Some(3), ..Options::default() };
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let mut proxy = Proxy::new(&opts, vec![listener], clock.clone()).expect("proxy");
+
+        // as if the last iterations woke up without moving anything
+        proxy.spins = 3;
+        let started = Instant::now();
+        proxy.step(0, &[]).expect("step");
+        assert_eq!(proxy.stats.spin_sleeps, 1);
+        assert_eq!(clock.now() - start, SPIN_SLEEP);
+        assert!(started.elapsed() < SPIN_SLEEP);
+        drop(proxy);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_parent_connect_only_affects_the_new_client() {
+        let _turn = serial();
+        let (dir, compositor, listener) = sockets("connect-failure-test");
+        let opts = Options::default();
+        let mut proxy = Proxy::new(&opts, vec![listener], Arc::new(SystemClock)).expect("proxy");
+        let proxy_path = dir.join("proxy");
+        let first = UnixStream::connect(&proxy_path).expect("connect");
+        proxy.step(0, &[]).expect("step");
+        let (conn, _) = compositor.accept().expect("accept");
+
+        // nobody is listening anymore, the next connect is refused
+        drop(compositor);
+        let second = UnixStream::connect(&proxy_path).expect("connect");
+        proxy.step(0, &[]).expect("step");
+        assert_eq!(proxy.connection_count(), 1);
+        assert_eq!(proxy.stats.parent_connect_failed, 1);
+        let mut buf = [0u8; 16];
+        assert_eq!(rustix::io::read(&second, &mut buf), Ok(0));
+
+        // the first client is still served
+        assert_eq!(rustix::io::write(&first, b"request"), Ok(7));
+        proxy.step(100, &[]).expect("step");
+        assert_eq!(rustix::io::read(&conn, &mut buf), Ok(7));
+        drop(proxy);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
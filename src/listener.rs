@@ -0,0 +1,304 @@
+// The sockets we accept client connections on, each paired with the socket its connections get
+// forwarded to.
+//
+// --listen-tcp adds a TCP socket for remote wayland clients, forwarded like the local ones. fds
+// can't cross TCP, so such clients get disconnected once anything passes fds, which rules out
+// most real clients as soon as they get a keymap or share a buffer.
+//
+// A socket path that's already taken is probed with a connect to tell a running proxy or
+// compositor, which we leave alone, from the leftovers of one that got killed. --force removes the
+// latter and binds anyway.
+//
+// --parent-failover gives the parent a list of backups. A new client whose connect to the parent
+// finds nobody listening is connected to the first backup that does instead. Clients matching a
+// --route don't fail over, and connections are never moved once established.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustix::fd::OwnedFd;
+use rustix::fs::{statat, unlink, AtFlags, FileType, CWD};
+use rustix::io::Errno;
+use rustix::net::{accept_with, bind, bind_unix, connect, connect_unix, listen, socket_with, AddressFamily, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::parent::check_socket;
+use crate::recording::Recording;
+use crate::route::Route;
+use crate::ProxyError;
+
+const X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+// --x11-display picks the first free display number from here, far above what X servers use
+const X11_PROXY_DISPLAYS: std::ops::Range<u32> = 100..1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Wayland,
+    X11,
+}
+
+pub struct Listener {
+    pub socket: OwnedFd,
+    /// empty for --listen-tcp
+    pub path: PathBuf,
+    /// --listen-tcp address and whether accepted connections get TCP_NODELAY
+    tcp: Option<(SocketAddr, bool)>,
+    /// `None` if we only have `preconnected` sockets to the parent
+    parent: Option<Parent>,
+    /// --parent-failover sockets tried in order when `parent` is unreachable
+    backups: Vec<Parent>,
+    /// connections to the parent, handed to the next clients instead of connecting
+    pub preconnected: Vec<OwnedFd>,
+    /// --route rules overriding `parent` for some clients
+    routes: Vec<(Route, Parent)>,
+    pub protocol: Protocol,
+    /// STREAM, or SEQPACKET with --seqpacket, used for the parent connections too
+    socket_type: SocketType,
+}
+
+#[derive(Clone)]
+struct Parent {
+    // socket path, tcp:ADDRESS or replay:FILE, for messages
+    name: String,
+    addr: ParentAddr,
+}
+
+#[derive(Clone)]
+enum ParentAddr {
+    Unix(SocketAddrUnix),
+    /// --parent-tcp, `nodelay` unless --tcp-nodelay=false
+    Tcp { addr: SocketAddr, nodelay: bool },
+    /// --replay-to-client, every connection gets the recording played to it
+    Playback(Arc<Recording>),
+}
+
+impl Parent {
+    fn new(path: PathBuf) -> Result<Parent, ProxyError> {
+        let addr = SocketAddrUnix::new(&path).map_err(|_| ProxyError::Parse { context: format!("invalid parent socket path {}", path.display()) })?;
+        Ok(Parent { name: path.display().to_string(), addr: ParentAddr::Unix(addr) })
+    }
+}
+
+/// Whoever has the file at a socket path we couldn't bind.
+enum Holder {
+    /// someone accepts connections on it, or at least has it bound
+    Live,
+    /// a socket nobody listens on anymore
+    Stale,
+    NotASocket,
+}
+
+fn holder(path: &Path, addr: &SocketAddrUnix, socket_type: SocketType) -> Holder {
+    match statat(CWD, path, AtFlags::SYMLINK_NOFOLLOW) {
+        Ok(stat) if FileType::from_raw_mode(stat.st_mode) == FileType::Socket => {}
+        _ => return Holder::NotASocket,
+    }
+    // if we can't tell it's better left alone
+    let Ok(probe) = socket_with(AddressFamily::UNIX, socket_type, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None) else {
+        return Holder::Live;
+    };
+    // a full backlog or a socket of another type still means someone is there
+    match connect_unix(&probe, addr) {
+        Err(Errno::CONNREFUSED) => Holder::Stale,
+        _ => Holder::Live,
+    }
+}
+
+impl Listener {
+    /// Binds the socket at `path`, with `force` replacing a stale one.
+    pub fn bind(path: PathBuf, parent_path: Option<PathBuf>, protocol: Protocol, socket_type: SocketType, force: bool) -> Result<Listener, ProxyError> {
+        let context = || path.display().to_string();
+        let parent = parent_path.map(Parent::new).transpose()?;
+        let socket = socket_with(AddressFamily::UNIX, socket_type, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None).map_err(|errno| ProxyError::Bind { context: context(), errno })?;
+        let addr = SocketAddrUnix::new(&path).map_err(|_| ProxyError::Parse { context: format!("invalid socket path {}", context()) })?;
+        match bind_unix(&socket, &addr) {
+            Ok(()) => {}
+            Err(errno @ Errno::ADDRINUSE) => {
+                let in_use = |why: &str| ProxyError::Bind { context: format!("{}, {}", context(), why), errno };
+                match holder(&path, &addr, socket_type) {
+                    Holder::Live => return Err(in_use("another instance or compositor is listening on it")),
+                    Holder::NotASocket => return Err(in_use("which exists and isn't a socket")),
+                    Holder::Stale if !force => return Err(in_use("a stale socket nobody listens on, --force removes it")),
+                    Holder::Stale => {
+                        // someone binding it between the probe and here makes our bind fail again
+                        log!("warning: removing the stale socket {} (--force)", context());
+                        unlink(&path).map_err(|errno| ProxyError::Bind { context: context(), errno })?;
+                        bind_unix(&socket, &addr).map_err(|errno| ProxyError::Bind { context: context(), errno })?;
+                    }
+                }
+            }
+            Err(errno) => return Err(ProxyError::Bind { context: context(), errno }),
+        }
+        // from here on dropping it removes the socket file, also if listen fails
+        let listener = Listener { socket, path, tcp: None, parent, backups: Vec::new(), preconnected: Vec::new(), routes: Vec::new(), protocol, socket_type };
+        listen(&listener.socket, 128).map_err(|errno| ProxyError::Listen { context: listener.path.display().to_string(), errno })?;
+        Ok(listener)
+    }
+
+    /// Binds a --listen-tcp socket whose wayland clients get forwarded wherever `local`'s go
+    /// when no --route matches them, which for TCP peers none can.
+    pub fn bind_tcp(addr: SocketAddr, nodelay: bool, local: &Listener) -> Result<Listener, ProxyError> {
+        let err = |errno| ProxyError::Bind { context: format!("tcp:{}", addr), errno };
+        let family = if addr.is_ipv4() { AddressFamily::INET } else { AddressFamily::INET6 };
+        let socket = socket_with(family, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None).map_err(err)?;
+        // restarting the proxy shouldn't have to wait for old connections in TIME_WAIT
+        rustix::net::sockopt::set_socket_reuseaddr(&socket, true).map_err(err)?;
+        bind(&socket, &addr).map_err(err)?;
+        listen(&socket, 128).map_err(|errno| ProxyError::Listen { context: format!("tcp:{}", addr), errno })?;
+        let (parent, backups) = (local.parent.clone(), local.backups.clone());
+        Ok(Listener { socket, path: PathBuf::new(), tcp: Some((addr, nodelay)), parent, backups, preconnected: Vec::new(), routes: Vec::new(), protocol: Protocol::Wayland, socket_type: local.socket_type })
+    }
+
+    /// The socket path, or tcp:ADDRESS, for messages.
+    pub fn name(&self) -> String {
+        match self.tcp {
+            Some((addr, _)) => format!("tcp:{}", addr),
+            None => self.path.display().to_string(),
+        }
+    }
+
+    pub fn accept(&self) -> Result<OwnedFd, Errno> {
+        let child = accept_with(&self.socket, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK)?;
+        if let Some((_, nodelay)) = self.tcp {
+            rustix::net::sockopt::set_tcp_nodelay(&child, nodelay)?;
+        }
+        Ok(child)
+    }
+
+    /// Forwards connections that no --route matches to a TCP endpoint instead of a socket path.
+    pub fn set_tcp_parent(&mut self, addr: SocketAddr, nodelay: bool) {
+        self.parent = Some(Parent { name: format!("tcp:{}", addr), addr: ParentAddr::Tcp { addr, nodelay } });
+    }
+
+    /// Plays a recording to every client instead of connecting anywhere.
+    pub fn set_playback(&mut self, path: &Path, recording: Arc<Recording>) {
+        self.parent = Some(Parent { name: format!("replay:{}", path.display()), addr: ParentAddr::Playback(recording) });
+    }
+
+    pub fn add_route(&mut self, route: Route, parent_path: PathBuf) -> Result<(), ProxyError> {
+        self.routes.push((route, Parent::new(parent_path)?));
+        Ok(())
+    }
+
+    /// Adds a --parent-failover backup, tried after the parent and the backups added before.
+    pub fn add_backup(&mut self, parent_path: PathBuf) -> Result<(), ProxyError> {
+        self.backups.push(Parent::new(parent_path)?);
+        Ok(())
+    }
+
+    /// Binds a proxy for X11 display `display` on a free display number, which is returned
+    /// alongside for the child's DISPLAY.
+    pub fn bind_x11(display: u32) -> Result<(Listener, u32), ProxyError> {
+        let parent_path = Path::new(X11_SOCKET_DIR).join(format!("X{}", display));
+        check_socket(&parent_path)?;
+
+        for n in X11_PROXY_DISPLAYS {
+            let path = Path::new(X11_SOCKET_DIR).join(format!("X{}", n));
+            if !matches!(statat(CWD, &path, AtFlags::SYMLINK_NOFOLLOW), Err(Errno::NOENT)) {
+                continue;
+            }
+            match Listener::bind(path, Some(parent_path.clone()), Protocol::X11, SocketType::STREAM, false) {
+                // lost a race with someone else picking the same number
+                Err(ProxyError::Bind { errno: Errno::ADDRINUSE, .. }) => continue,
+                Err(e) => return Err(e),
+                Ok(listener) => return Ok((listener, n)),
+            }
+        }
+        Err(ProxyError::Bind { context: format!("a free X11 display number in {}", X11_SOCKET_DIR), errno: Errno::ADDRINUSE })
+    }
+
+    /// Starts a non-blocking connect to the parent for the accepted `child`, returns whether it
+    /// already completed and the socket address it connected to, which preconnected sockets and
+    /// TCP parents don't have. `None` if there's no way to reach the parent anymore.
+    pub fn connect_parent(&mut self, child: &OwnedFd) -> Result<Option<(OwnedFd, bool, Option<SocketAddrUnix>)>, ProxyError> {
+        if let Some(parent) = self.route(child) {
+            return self.connect(parent).map(Some);
+        }
+        if let Some(fd) = self.preconnected.pop() {
+            return Ok(Some((fd, true, None)));
+        }
+        let Some(parent) = &self.parent else {
+            return Ok(None);
+        };
+        let mut connected = self.connect(parent);
+        for backup in &self.backups {
+            match connected {
+                // a unix socket tells right away whether anyone is listening
+                Err(ProxyError::Connect { errno: Errno::NOENT | Errno::CONNREFUSED, .. }) => connected = self.connect(backup),
+                _ => break,
+            }
+        }
+        connected.map(Some)
+    }
+
+    fn connect(&self, target: &Parent) -> Result<(OwnedFd, bool, Option<SocketAddrUnix>), ProxyError> {
+        let err = |errno| ProxyError::Connect { context: target.name.clone(), errno };
+        let flags = SocketFlags::CLOEXEC | SocketFlags::NONBLOCK;
+        let (connected, parent, unix_addr) = match &target.addr {
+            ParentAddr::Unix(addr) => {
+                let parent = socket_with(AddressFamily::UNIX, self.socket_type, flags, None).map_err(err)?;
+                (connect_unix(&parent, addr), parent, Some(addr.clone()))
+            }
+            ParentAddr::Tcp { addr, nodelay } => {
+                let family = if addr.is_ipv4() { AddressFamily::INET } else { AddressFamily::INET6 };
+                let parent = socket_with(family, SocketType::STREAM, flags, None).map_err(err)?;
+                // wayland is lots of small latency sensitive messages, Nagle would hold them back
+                rustix::net::sockopt::set_tcp_nodelay(&parent, *nodelay).map_err(err)?;
+                (connect(&parent, addr), parent, None)
+            }
+            ParentAddr::Playback(recording) => return Ok((recording.play(self.socket_type).map_err(err)?, true, None)),
+        };
+        match connected {
+            Ok(_) => Ok((parent, true, unix_addr)),
+            // unix sockets report a full backlog, TCP the handshake
+            Err(e) if e == Errno::AGAIN || e == Errno::INPROGRESS => Ok((parent, false, unix_addr)),
+            Err(errno) => Err(err(errno)),
+        }
+    }
+
+    /// The socket address `child` would be connected to and the socket type to use, for
+    /// connecting later with --on-parent-outage.
+    pub fn parent_addr(&self, child: &OwnedFd) -> Option<(SocketAddrUnix, SocketType)> {
+        match &self.route(child).or(self.parent.as_ref())?.addr {
+            ParentAddr::Unix(addr) => Some((addr.clone(), self.socket_type)),
+            _ => None,
+        }
+    }
+
+    /// The --route parent for `child`, if any rule matches.
+    fn route(&self, child: &OwnedFd) -> Option<&Parent> {
+        if self.routes.is_empty() {
+            return None;
+        }
+        // if we can't tell who the client is no rule can match
+        let cred = rustix::net::sockopt::get_socket_peercred(child).ok()?;
+        self.routes.iter().find(|(route, _)| route.matches(&cred)).map(|(_, parent)| parent)
+    }
+}
+
+// the socket file goes away with the listener, on every way out of the proxy including error
+// returns and unwinding panics. Only an abort or SIGKILL leave it behind
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if self.tcp.is_some() {
+            return;
+        }
+        if let Err(e) = unlink(&self.path) {
+            log!("warning: failed to unlink {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_listen_removes_the_socket() {
+        let path = std::env::temp_dir().join(format!("p5wl-listen-test-{}", std::process::id()));
+        // datagram sockets bind fine but can't listen
+        let result = Listener::bind(path.clone(), None, Protocol::Wayland, SocketType::DGRAM, false);
+        assert!(matches!(result, Err(ProxyError::Listen { errno: Errno::OPNOTSUPP, .. })));
+        assert_eq!(statat(CWD, &path, AtFlags::SYMLINK_NOFOLLOW).err(), Some(Errno::NOENT));
+    }
+}
@@ -0,0 +1,82 @@
+// Log output, on stderr or appended to --log-file. Every line starts with the --log-prefix, by
+// default the child's program name and pid, so the output of several proxies writing to the same
+// terminal or journal can be told apart. With --journald the lines go to the journal instead, see
+// the journal module.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::journal::Journal;
+
+struct Output {
+    // including the separator, empty for no prefix
+    prefix: String,
+    file: Option<(PathBuf, File)>,
+    journal: Option<Journal>,
+}
+
+static OUTPUT: Mutex<Output> = Mutex::new(Output { prefix: String::new(), file: None, journal: None });
+
+fn output() -> MutexGuard<'static, Output> {
+    OUTPUT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn set_prefix(prefix: &str) {
+    output().prefix = match prefix.is_empty() {
+        true => String::new(),
+        false => format!("{}: ", prefix),
+    };
+}
+
+pub fn prefix() -> String {
+    output().prefix.clone()
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Logs to `path` from now on instead of stderr.
+pub fn set_file(path: &Path) -> std::io::Result<()> {
+    let file = open(path)?;
+    output().file = Some((path.to_owned(), file));
+    Ok(())
+}
+
+/// Logs to the journal from now on instead of stderr.
+pub fn set_journal(journal: Journal) {
+    output().journal = Some(journal);
+}
+
+/// Opens the --log-file again, after it was rotated. The old one is kept if that fails.
+pub fn reopen() {
+    let mut output = output();
+    let Some((path, file)) = &mut output.file else {
+        return;
+    };
+    match open(path) {
+        Ok(reopened) => *file = reopened,
+        Err(e) => {
+            let _ = writeln!(file, "warning: failed to reopen {}: {}, still logging to the old file", path.display(), e);
+        }
+    }
+}
+
+/// Writes one line, for the log! macro.
+pub fn write(args: fmt::Arguments<'_>) {
+    let mut output = output();
+    let Output { prefix, file, journal } = &mut *output;
+    match (file, journal) {
+        // a full disk shouldn't take the proxy down, there's nowhere to report it anyway
+        (Some((_, file)), _) => drop(writeln!(file, "{}{}", prefix, args)),
+        (None, Some(journal)) => {
+            if !journal.log(prefix, &args.to_string()) {
+                eprintln!("{}warning: failed to write to the journal, dropping messages", prefix);
+            }
+        }
+        (None, None) => eprintln!("{}{}", prefix, args),
+    }
+}
@@ -0,0 +1,128 @@
+// The LZ4 block format, used by --compress-queue.
+//
+// A block is a series of sequences, each made of a token byte (literal length in the upper,
+// match length minus 4 in the lower nibble), extra literal length bytes, the literals,
+// a 2 byte little endian match offset and extra match length bytes. Nibbles of 15 are
+// continued by bytes that get added up until one is below 255. The last sequence only holds
+// literals. We only ever decompress our own output, this is not meant for untrusted input.
+
+const MIN_MATCH: usize = 4;
+// the format requires the last 5 bytes to be literals and the last match to start 12 bytes
+// before the end of the block
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const HASH_BITS: u32 = 12;
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn hash(v: u32) -> usize {
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(u16, usize)>) {
+    let lit_nibble = literals.len().min(15) as u8;
+    let match_nibble = matched.map_or(0, |(_, len)| (len - MIN_MATCH).min(15) as u8);
+    out.push(lit_nibble << 4 | match_nibble);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, len)) = matched {
+        out.extend_from_slice(&offset.to_le_bytes());
+        if len - MIN_MATCH >= 15 {
+            push_length(out, len - MIN_MATCH - 15);
+        }
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = [usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut i = 0;
+
+    while i + MF_LIMIT < input.len() {
+        let seq = read_u32(input, i);
+        let h = hash(seq);
+        let candidate = table[h];
+        table[h] = i;
+
+        if candidate == usize::MAX || i - candidate > u16::MAX as usize || read_u32(input, candidate) != seq {
+            i += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while i + len < input.len() - LAST_LITERALS && input[candidate + len] == input[i + len] {
+            len += 1;
+        }
+        push_sequence(&mut out, &input[anchor..i], Some(((i - candidate) as u16, len)));
+        i += len;
+        anchor = i;
+    }
+
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Returns `None` if `block` doesn't decompress to exactly `len` bytes.
+pub fn decompress(block: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+
+    let read_length = |pos: &mut usize, mut len: usize| -> Option<usize> {
+        loop {
+            let b = *block.get(*pos)?;
+            *pos += 1;
+            len += b as usize;
+            if b != 255 {
+                return Some(len);
+            }
+        }
+    };
+
+    loop {
+        let token = *block.get(pos)?;
+        pos += 1;
+
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len = read_length(&mut pos, lit_len)?;
+        }
+        out.extend_from_slice(block.get(pos..pos + lit_len)?);
+        pos += lit_len;
+
+        if pos == block.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes(block.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+        pos += 2;
+        let mut match_len = (token & 0xf) as usize;
+        if match_len == 15 {
+            match_len = read_length(&mut pos, match_len)?;
+        }
+        match_len += MIN_MATCH;
+
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+        // matches may overlap the bytes they produce, so copy byte by byte
+        let start = out.len() - offset;
+        for k in 0..match_len {
+            out.push(out[start + k]);
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
@@ -0,0 +1,93 @@
+// --latency-histogram: how long chunks spend in the proxy, from being received to being sent in
+// full, one histogram per direction. Chunks that go out right away count as 0, queued ones with
+// the time they waited. The control socket's `metrics` command returns them in the OpenMetrics
+// text format for a monitoring stack to scrape, through a small bridge since we don't speak HTTP.
+//
+// Every bucket carries the most recent observation that fell into it as an exemplar labeled with
+// the connection id, pointing at which client saw the latency.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::stats::Stats;
+
+/// Upper bounds in seconds unless given --latency-buckets, from "didn't wait" to "visible stall".
+pub const DEFAULT_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+const NAME: &str = "p5wl_forward_latency_seconds";
+
+pub struct Histogram {
+    // upper bounds in seconds, increasing, +Inf is implied
+    bounds: Vec<f64>,
+    // per bucket, not cumulative, with the +Inf bucket last
+    counts: Vec<u64>,
+    exemplars: Vec<Option<(u64, f64)>>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram { bounds: bounds.to_vec(), counts: vec![0; bounds.len() + 1], exemplars: vec![None; bounds.len() + 1], sum: 0.0 }
+    }
+
+    pub fn observe(&mut self, conn: u64, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let bucket = self.bounds.iter().position(|&bound| secs <= bound).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.exemplars[bucket] = Some((conn, secs));
+        self.sum += secs;
+    }
+
+    fn format(&self, out: &mut String, direction: &str) {
+        let mut cumulative = 0;
+        let bounds = self.bounds.iter().map(|&bound| canonical(bound)).chain(std::iter::once("+Inf".to_owned()));
+        for ((le, count), exemplar) in bounds.zip(&self.counts).zip(&self.exemplars) {
+            cumulative += count;
+            let _ = write!(out, "{}_bucket{{direction=\"{}\",le=\"{}\"}} {}", NAME, direction, le, cumulative);
+            if let Some((conn, secs)) = exemplar {
+                let _ = write!(out, " # {{conn=\"{}\"}} {}", conn, secs);
+            }
+            out.push('\n');
+        }
+        let _ = writeln!(out, "{}_sum{{direction=\"{}\"}} {}", NAME, direction, self.sum);
+        let _ = writeln!(out, "{}_count{{direction=\"{}\"}} {}", NAME, direction, cumulative);
+    }
+}
+
+/// `bound` the way OpenMetrics wants `le` values, always with a fraction: `1.0` rather than `1`.
+fn canonical(bound: f64) -> String {
+    let formatted = bound.to_string();
+    match formatted.contains('.') {
+        true => formatted,
+        false => formatted + ".0",
+    }
+}
+
+/// Both directions' histograms as an OpenMetrics exposition, `None` without --latency-histogram.
+pub fn exposition(stats: &Stats) -> Option<String> {
+    let (to_parent, to_child) = (stats.to_parent.latency.as_ref()?, stats.to_child.latency.as_ref()?);
+    let mut out = String::new();
+    let _ = writeln!(out, "# TYPE {} histogram", NAME);
+    let _ = writeln!(out, "# UNIT {} seconds", NAME);
+    let _ = writeln!(out, "# HELP {} Time chunks spent in the proxy before being sent in full.", NAME);
+    to_parent.format(&mut out, "to_parent");
+    to_child.format(&mut out, "to_child");
+    out.push_str("# EOF\n");
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_are_canonical_floats() {
+        let mut histogram = Histogram::new(&[0.0005, 1.0, 2.0, 10.0]);
+        histogram.observe(7, Duration::from_millis(1500));
+        let mut out = String::new();
+        histogram.format(&mut out, "to_child");
+        let le: Vec<&str> = out.lines().filter_map(|line| line.split("le=\"").nth(1)?.split('"').next()).collect();
+        assert_eq!(le, ["0.0005", "1.0", "2.0", "10.0", "+Inf"]);
+        assert!(out.contains("le=\"2.0\"} 1 # {conn=\"7\"} 1.5\n"));
+    }
+}
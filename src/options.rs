@@ -0,0 +1,759 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rustix::thread::UnshareFlags;
+
+use crate::route::Route;
+use crate::trace::Filter;
+
+const USAGE: &str = "\
+usage: p5wl [OPTIONS] [--] COMMAND [ARGS...]
+       p5wl [OPTIONS] (--args-file FILE | --args-stdin)
+       p5wl [OPTIONS] --no-child
+       p5wl [OPTIONS] --self-test
+
+options:
+    --strict-handshake               close connections whose first message isn't a wl_display request
+    --queue-flush-timeout SECONDS    close connections whose queued messages make no progress for this long
+    --parent-connect-timeout SECONDS close clients whose connection to the compositor isn't accepted within this time
+    --max-drain-bytes BYTES          send at most BYTES of a connection's queued data per loop iteration
+    --max-connections-per-uid N      reject new clients of a uid that already has N open connections
+    --max-lifetime-connections N     stop accepting after N clients and remove the socket, open connections
+                                     are still served. With --no-child exit once they're closed
+    --accept-batch N                 accept at most N new connections per loop iteration (default 8)
+    --spin-limit N                   once N loop iterations in a row woke up for connection events without
+                                     moving any data, sleep 10ms per iteration until something moves, a
+                                     backstop against busy looping bugs
+    --auto-discover                  if WAYLAND_DISPLAY is unset use the first connectable wayland-N socket
+    --parent-tcp HOST:PORT           forward wayland clients to a TCP endpoint instead of the compositor socket,
+                                     for bridges to a remote compositor. Clients can't pass fds over it
+    --parent-failover SOCKET[,SOCKET...]
+                                     forward wayland clients to the first of these compositor sockets that
+                                     accepts connections instead of WAYLAND_DISPLAY, checked for every new
+                                     client. Established connections stay where they are
+    --parent-fd-pool N[,N...]        inherited fds already connected to the compositor, each used for one client
+                                     in the given order, later clients connect as usual
+    --listen-tcp HOST:PORT           also accept wayland clients over TCP, e.g. from another machine. Anyone who
+                                     can connect can use the compositor, clients get disconnected on passing fds
+    --tcp-nodelay=BOOL               disable Nagle's algorithm on TCP connections (default true)
+    --wait-for-parent SECONDS        wait this long for the compositor socket to accept connections
+                                     before starting the child
+    --display-name NAME              name of the proxy socket in XDG_RUNTIME_DIR (default wayland-wrap-PID)
+    --persist                        keep serving the socket after the child exited, requires --display-name
+    --force                          replace a stale proxy socket left behind by an instance that got killed,
+                                     one something still listens on is never touched
+    --post-exit-grace SECONDS        keep accepting connections this long after the child exited before exiting,
+                                     e.g. for a supervisor restarting it
+    --no-child                       don't start a command, print WAYLAND_DISPLAY (and DISPLAY) to stdout
+                                     and serve clients started elsewhere until killed
+    --idle-timeout SECONDS           with --no-child, exit once there were no connections for this long
+    --expect-connection-timeout SECONDS
+                                     warn if no client connected within this time after starting the child
+    --expect-connection-fatal        exit with an error instead of warning, e.g. for CI
+    --deny-new-connections-after-child-exit
+                                     stop accepting connections once the child exited, open ones stay
+    --status-fd N                    once the socket is ready write a JSON line with its name, path and the
+                                     pids to the inherited fd N, then close it
+    --log-file FILE                  append log lines to FILE instead of stderr, reopened on SIGHUP, which
+                                     also dumps the --stats
+    --journald                       log to the systemd journal instead of stderr, with the connection id in
+                                     the WEYLAND_CONN field
+    --log-prefix STRING              start every line we log with STRING (default the child's program name
+                                     and pid), empty for none
+    --stats                          print send statistics on exit
+    --latency-histogram              track how long chunks take through the proxy per direction, served in the
+                                     OpenMetrics format by the metrics command, requires --control-socket
+    --latency-buckets SECONDS[,...]  upper bounds of the histogram buckets (default 0.0005,0.001,0.005,0.01,
+                                     0.05,0.1,0.5,1), implies --latency-histogram
+    --verbose                        also log what connections still had queued when they closed
+    --heartbeat-interval SECONDS     periodically log the number of connections and bytes forwarded
+    --liveness-fd N                  write a byte to the inherited fd N on every heartbeat so a supervisor
+                                     can tell the proxy isn't hung, requires --heartbeat-interval
+    --record-events FILE             record what the compositor sends to the first wayland client to FILE
+    --replay-to-client FILE          don't connect to a compositor, play a --record-events recording to every
+                                     client instead. Events that carried fds are skipped
+    --filter-summary                 on exit, print how often each --cap-global, --inject-global,
+                                     --allow-global, --single-seat and --strict-handshake rule matched
+    --count-messages                 count wayland messages by interface and opcode, print the table on exit
+    --check-protocol                 log messages that break some protocol rules without acting on them:
+                                     wl_display.sync answered out of order, late or not at all, and
+                                     messages to objects after their wl_display.delete_id
+    --trace                          log every wayland message
+    --trace-filter [!]NAME[,...]     only --trace messages to these interfaces or object ids, ! excludes,
+                                     implies --trace
+    --trace-socket PATH              stream the --trace output to clients of a unix socket at PATH instead of
+                                     logging it, lines are dropped for clients that don't keep up. Implies --trace
+    --trace-fds                      log the type and size of every fd passed through the proxy
+    --hexdump                        log every forwarded chunk as a hex dump
+    --hexdump-limit BYTES            only dump the first BYTES of each chunk (default 256), implies --hexdump
+    --config FILE                    read options from FILE, command line options take precedence
+    --debug-ordering                 abort if data is ever forwarded out of order (for debugging the proxy)
+    --pty                            run the child on a new pseudo-terminal connected to our stdio
+    --chdir DIR                      run the child in DIR instead of our working directory
+    --socket-env VAR[=VALUE]         pass the child a proxy connection as an inherited fd, VAR set to VALUE
+                                     with {fd} replaced by its number (default just the number), and leave
+                                     WAYLAND_DISPLAY alone. VAR WAYLAND_SOCKET works with libwayland clients
+    --no-cloexec-for-child N[,N...]  let the child inherit our fds N even if an option took them over, for
+                                     testing fd inheritance. --verbose logs all fds the child inherits
+    --unshare NS[,NS...]             run the child in new namespaces: user, mount, ipc, uts, net, cgroup
+    --seccomp PROFILE                restrict the child's syscalls: wayland-client, or no-network which also
+                                     only allows unix sockets. Clients needing more than the profile break
+    --cgroup PATH                    move the child into the cgroup at PATH before it executes
+    --max-total-fds N                pause accepting and reading when sockets plus passed fds approach N
+                                     (default RLIMIT_NOFILE)
+    --no-raise-rlimit                don't raise the RLIMIT_NOFILE soft limit to the hard limit at startup
+    --watchdog SECONDS               dump all connection states if an event loop iteration takes this long
+    --watchdog-abort                 abort after the --watchdog dump instead of continuing
+    --compress-queue                 (experimental) lz4 compress queued data to save memory while a peer is slow
+    --experimental-reconnect         (experimental) when the compositor restarts, reconnect clients that haven't
+                                     passed fds and replay their requests. Wayland can't resync a client,
+                                     this only works for clients that did next to nothing yet
+    --on-parent-outage POLICY        with --experimental-reconnect, what happens to clients connecting while the
+                                     compositor is unreachable: reject closes them, queue connects them once it's
+                                     back, wait stops accepting until then
+    --x11-display N                  also proxy X11 display :N on a new display number, set as DISPLAY for the child.
+                                     Only the connection is forwarded, requests aren't filtered
+    --route (uid|gid|pid)=N:SOCKET   forward wayland clients with these credentials to SOCKET instead,
+                                     may be repeated, the first matching rule wins
+    --reorder-safe-priority          let input events overtake other events queued for a slow client, only ahead
+                                     of events to other objects
+    --message-framed                 only forward whole wayland messages, implied by the options that parse messages
+    --seqpacket                      use SOCK_SEQPACKET instead of SOCK_STREAM for the wayland socket and the
+                                     connections to the compositor
+    --max-inflight-messages N        close connections with more than N wayland messages queued in one direction
+    --max-message-size BYTES         close connections that send a wayland message larger than BYTES
+    --cap-global INTERFACE=VERSION   advertise the INTERFACE global with at most VERSION, may be repeated
+    --inject-global INTERFACE=VERSION
+                                     also advertise a global the compositor doesn't have, binds to it are
+                                     logged and dropped, may be repeated
+    --allow-global INTERFACE[,...]   only advertise globals of these interfaces, may be repeated. Nothing is
+                                     allowed implicitly, most clients need at least wl_compositor and wl_shm
+    --single-seat                    only advertise the first wl_seat global to clients, hiding further seats
+    --direction DIRECTION            to-server or to-client only lets data flow that way, connections over which
+                                     the other side sends anything are closed (default both)
+    --syslog                         log connections with the client's pid, program name and uid and their byte counts to /dev/log
+    --control-socket PATH            accept commands to list, pause, resume and close connections on PATH
+    --args-file FILE                 read the command and its arguments from FILE, separated by NULs or newlines
+    --args-stdin                     like --args-file but read from stdin
+    --self-test                      check that data and fds make it through the proxy unharmed, using
+                                     an internal fake compositor and client, then exit
+    -h, --help                       show this help
+";
+
+pub enum ArgsSource {
+    File(PathBuf),
+    Stdin,
+}
+
+/// --direction, which way data may flow between the clients and the compositor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Directions {
+    Both,
+    ToServer,
+    ToClient,
+}
+
+impl Directions {
+    pub(crate) fn allows(self, dir: crate::Direction) -> bool {
+        match self {
+            Directions::Both => true,
+            Directions::ToServer => dir == crate::Direction::ToParent,
+            Directions::ToClient => dir == crate::Direction::ToChild,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Directions::Both => "both",
+            Directions::ToServer => "to-server",
+            Directions::ToClient => "to-client",
+        }
+    }
+}
+
+pub struct Options {
+    pub strict_handshake: bool,
+    pub queue_flush_timeout: Option<Duration>,
+    pub parent_connect_timeout: Option<Duration>,
+    pub accept_batch: usize,
+    pub spin_limit: Option<u64>,
+    pub max_connections_per_uid: Option<usize>,
+    pub max_lifetime_connections: Option<u64>,
+    pub max_drain_bytes: Option<usize>,
+    pub auto_discover: bool,
+    pub parent_tcp: Option<String>,
+    pub parent_failover: Vec<String>,
+    pub parent_fd_pool: Vec<i32>,
+    pub listen_tcp: Option<String>,
+    pub tcp_nodelay: bool,
+    pub wait_for_parent: Option<Duration>,
+    pub display_name: Option<String>,
+    pub persist: bool,
+    pub force: bool,
+    pub post_exit_grace: Option<Duration>,
+    pub no_child: bool,
+    pub status_fd: Option<i32>,
+    pub idle_timeout: Option<Duration>,
+    pub expect_connection_timeout: Option<Duration>,
+    pub expect_connection_fatal: bool,
+    pub deny_after_exit: bool,
+    pub log_file: Option<PathBuf>,
+    pub journald: bool,
+    pub log_prefix: Option<String>,
+    pub stats: bool,
+    pub latency_histogram: bool,
+    pub latency_buckets: Vec<f64>,
+    pub verbose: bool,
+    pub heartbeat_interval: Option<Duration>,
+    pub liveness_fd: Option<i32>,
+    pub record_events: Option<PathBuf>,
+    pub replay_to_client: Option<PathBuf>,
+    pub count_messages: bool,
+    pub check_protocol: bool,
+    pub filter_summary: bool,
+    pub allow_globals: Vec<String>,
+    pub single_seat: bool,
+    pub direction: Directions,
+    pub trace: bool,
+    pub trace_filter: Vec<Filter>,
+    pub trace_fds: bool,
+    pub hexdump: bool,
+    pub hexdump_limit: usize,
+    pub config: Option<PathBuf>,
+    pub debug_ordering: bool,
+    pub pty: bool,
+    pub chdir: Option<PathBuf>,
+    pub socket_env: Option<(String, String)>,
+    pub no_cloexec_for_child: Vec<i32>,
+    pub unshare: UnshareFlags,
+    pub cgroup: Option<PathBuf>,
+    pub seccomp: Option<crate::seccomp::Profile>,
+    pub max_total_fds: Option<usize>,
+    pub raise_rlimit: bool,
+    pub watchdog: Option<Duration>,
+    pub watchdog_abort: bool,
+    pub compress_queue: bool,
+    pub experimental_reconnect: bool,
+    pub on_parent_outage: Option<crate::reconnect::Outage>,
+    pub x11_display: Option<u32>,
+    pub routes: Vec<Route>,
+    pub self_test: bool,
+    pub control_socket: Option<PathBuf>,
+    pub trace_socket: Option<PathBuf>,
+    pub cap_globals: Vec<(String, u32)>,
+    pub inject_globals: Vec<(String, u32)>,
+    pub message_framed: bool,
+    pub reorder_safe_priority: bool,
+    pub max_message_size: Option<usize>,
+    pub max_inflight_messages: Option<usize>,
+    pub seqpacket: bool,
+    pub syslog: bool,
+    pub args_from: Option<ArgsSource>,
+    pub command: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            strict_handshake: false,
+            queue_flush_timeout: None,
+            parent_connect_timeout: None,
+            accept_batch: 8,
+            spin_limit: None,
+            max_connections_per_uid: None,
+            max_lifetime_connections: None,
+            max_drain_bytes: None,
+            auto_discover: false,
+            parent_tcp: None,
+            parent_failover: Vec::new(),
+            parent_fd_pool: Vec::new(),
+            listen_tcp: None,
+            tcp_nodelay: true,
+            wait_for_parent: None,
+            display_name: None,
+            persist: false,
+            force: false,
+            post_exit_grace: None,
+            no_child: false,
+            status_fd: None,
+            idle_timeout: None,
+            expect_connection_timeout: None,
+            expect_connection_fatal: false,
+            deny_after_exit: false,
+            log_file: None,
+            journald: false,
+            log_prefix: None,
+            stats: false,
+            latency_histogram: false,
+            latency_buckets: crate::metrics::DEFAULT_BUCKETS.to_vec(),
+            verbose: false,
+            heartbeat_interval: None,
+            liveness_fd: None,
+            record_events: None,
+            replay_to_client: None,
+            count_messages: false,
+            check_protocol: false,
+            filter_summary: false,
+            allow_globals: Vec::new(),
+            single_seat: false,
+            direction: Directions::Both,
+            trace: false,
+            trace_filter: Vec::new(),
+            trace_fds: false,
+            hexdump: false,
+            hexdump_limit: 256,
+            config: None,
+            debug_ordering: false,
+            pty: false,
+            chdir: None,
+            socket_env: None,
+            no_cloexec_for_child: Vec::new(),
+            unshare: UnshareFlags::empty(),
+            cgroup: None,
+            seccomp: None,
+            max_total_fds: None,
+            raise_rlimit: true,
+            watchdog: None,
+            watchdog_abort: false,
+            compress_queue: false,
+            experimental_reconnect: false,
+            on_parent_outage: None,
+            x11_display: None,
+            routes: Vec::new(),
+            self_test: false,
+            control_socket: None,
+            trace_socket: None,
+            cap_globals: Vec::new(),
+            inject_globals: Vec::new(),
+            message_framed: false,
+            reorder_safe_priority: false,
+            max_message_size: None,
+            max_inflight_messages: None,
+            seqpacket: false,
+            syslog: false,
+            args_from: None,
+            command: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    pub fn from_env() -> Options {
+        match Options::parse(std::env::args().skip(1)) {
+            Ok(opts) => opts,
+            Err(msg) => {
+                eprint!("{}\n\n{}", msg, USAGE);
+                exit(2);
+            }
+        }
+    }
+
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let args: Vec<String> = args.collect();
+        let mut opts = Options::parse_args(args.iter().cloned())?;
+        if let Some(path) = &opts.config {
+            // parse again with the config file entries in front so the command line overrides them
+            let mut combined = crate::config::load(path)?;
+            combined.extend(args);
+            opts = Options::parse_args(combined.into_iter())?;
+        }
+
+        // only read once both passes are done, stdin can't be read twice
+        if let Some(source) = &opts.args_from {
+            opts.command = read_command(source)?;
+        }
+        Ok(opts)
+    }
+
+    fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut args = Args { iter: args, name: String::new(), inline: None };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--" => break,
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    exit(0);
+                }
+                "--strict-handshake" => opts.strict_handshake = true,
+                "--queue-flush-timeout" => opts.queue_flush_timeout = Some(args.duration()?),
+                "--parent-connect-timeout" => opts.parent_connect_timeout = Some(args.duration()?),
+                "--accept-batch" => opts.accept_batch = args.parsed()?,
+                "--spin-limit" => opts.spin_limit = Some(args.parsed()?),
+                "--max-connections-per-uid" => opts.max_connections_per_uid = Some(args.parsed()?),
+                "--max-lifetime-connections" => opts.max_lifetime_connections = Some(args.parsed()?),
+                "--max-drain-bytes" => opts.max_drain_bytes = Some(args.parsed()?),
+                "--auto-discover" => opts.auto_discover = true,
+                "--parent-tcp" => opts.parent_tcp = Some(args.value()?),
+                "--parent-failover" => opts.parent_failover.extend(args.list::<String>()?),
+                "--parent-fd-pool" => opts.parent_fd_pool = args.list()?,
+                "--listen-tcp" => opts.listen_tcp = Some(args.value()?),
+                "--tcp-nodelay" => opts.tcp_nodelay = args.boolean()?,
+                "--wait-for-parent" => opts.wait_for_parent = Some(args.duration()?),
+                "--display-name" => opts.display_name = Some(args.value()?),
+                "--persist" => opts.persist = true,
+                "--force" => opts.force = true,
+                "--post-exit-grace" => opts.post_exit_grace = Some(args.duration()?),
+                "--no-child" => opts.no_child = true,
+                "--status-fd" => opts.status_fd = Some(args.parsed()?),
+                "--idle-timeout" => opts.idle_timeout = Some(args.duration()?),
+                "--expect-connection-timeout" => opts.expect_connection_timeout = Some(args.duration()?),
+                "--expect-connection-fatal" => opts.expect_connection_fatal = true,
+                "--deny-new-connections-after-child-exit" => opts.deny_after_exit = true,
+                "--log-file" => opts.log_file = Some(args.value()?.into()),
+                "--journald" => opts.journald = true,
+                "--log-prefix" => opts.log_prefix = Some(args.value()?),
+                "--stats" => opts.stats = true,
+                "--latency-histogram" => opts.latency_histogram = true,
+                "--latency-buckets" => {
+                    opts.latency_histogram = true;
+                    opts.latency_buckets = args.list()?;
+                }
+                "--verbose" => opts.verbose = true,
+                "--heartbeat-interval" => opts.heartbeat_interval = Some(args.duration()?),
+                "--liveness-fd" => opts.liveness_fd = Some(args.parsed()?),
+                "--record-events" => opts.record_events = Some(args.value()?.into()),
+                "--replay-to-client" => opts.replay_to_client = Some(args.value()?.into()),
+                "--count-messages" => opts.count_messages = true,
+                "--check-protocol" => opts.check_protocol = true,
+                "--filter-summary" => opts.filter_summary = true,
+                "--allow-global" => opts.allow_globals.extend(args.list::<String>()?),
+                "--single-seat" => opts.single_seat = true,
+                "--direction" => opts.direction = parse_directions(&args.value()?)?,
+                "--trace" => opts.trace = true,
+                "--trace-filter" => {
+                    let value = args.value()?;
+                    for entry in value.split(',') {
+                        opts.trace_filter.push(entry.parse().map_err(|_| format!("invalid --trace-filter entry {:?}", entry))?);
+                    }
+                    opts.trace = true;
+                }
+                "--trace-socket" => {
+                    opts.trace_socket = Some(args.value()?.into());
+                    opts.trace = true;
+                }
+                "--trace-fds" => opts.trace_fds = true,
+                "--hexdump" => opts.hexdump = true,
+                "--hexdump-limit" => {
+                    opts.hexdump = true;
+                    opts.hexdump_limit = args.parsed()?;
+                }
+                "--config" => opts.config = Some(args.value()?.into()),
+                "--debug-ordering" => opts.debug_ordering = true,
+                "--pty" => opts.pty = true,
+                "--chdir" => opts.chdir = Some(args.value()?.into()),
+                "--socket-env" => opts.socket_env = Some(crate::inherit::parse_socket_env(&args.value()?)?),
+                "--no-cloexec-for-child" => opts.no_cloexec_for_child = args.list()?,
+                "--unshare" => opts.unshare |= crate::isolate::parse_namespaces(&args.value()?)?,
+                "--cgroup" => opts.cgroup = Some(args.value()?.into()),
+                "--seccomp" => opts.seccomp = Some(crate::seccomp::parse_profile(&args.value()?)?),
+                "--max-total-fds" => opts.max_total_fds = Some(args.parsed()?),
+                "--no-raise-rlimit" => opts.raise_rlimit = false,
+                "--watchdog" => opts.watchdog = Some(args.duration()?),
+                "--watchdog-abort" => opts.watchdog_abort = true,
+                "--compress-queue" => opts.compress_queue = true,
+                "--experimental-reconnect" => opts.experimental_reconnect = true,
+                "--on-parent-outage" => opts.on_parent_outage = Some(crate::reconnect::parse_outage(&args.value()?)?),
+                "--control-socket" => opts.control_socket = Some(args.value()?.into()),
+                "--syslog" => opts.syslog = true,
+                "--args-file" => opts.args_from = Some(ArgsSource::File(args.value()?.into())),
+                "--args-stdin" => opts.args_from = Some(ArgsSource::Stdin),
+                "--self-test" => opts.self_test = true,
+                "--route" => opts.routes.push(args.parsed()?),
+                "--message-framed" => opts.message_framed = true,
+                "--reorder-safe-priority" => opts.reorder_safe_priority = true,
+                "--max-message-size" => opts.max_message_size = Some(args.parsed()?),
+                "--max-inflight-messages" => opts.max_inflight_messages = Some(args.parsed()?),
+                "--seqpacket" => opts.seqpacket = true,
+                "--cap-global" => opts.cap_globals.push(args.cap()?),
+                "--inject-global" => opts.inject_globals.push(args.cap()?),
+                "--x11-display" => opts.x11_display = Some(args.value()?.trim_start_matches(':').parse().map_err(|_| "invalid value for --x11-display".to_owned())?),
+                s if s.starts_with('-') => return Err(format!("unknown option {}", s)),
+                _ => {
+                    opts.command.push(arg);
+                    break;
+                }
+            }
+            args.finish()?;
+        }
+        opts.command.extend(args.iter);
+
+        if opts.accept_batch == 0 {
+            return Err("--accept-batch must be at least 1".to_owned());
+        }
+        if !opts.latency_buckets.iter().all(|bound| bound.is_finite() && *bound >= 0.0) || !opts.latency_buckets.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err("--latency-buckets must be increasing numbers of seconds".to_owned());
+        }
+        if opts.spin_limit == Some(0) {
+            return Err("--spin-limit must be at least 1".to_owned());
+        }
+        if opts.max_connections_per_uid == Some(0) {
+            return Err("--max-connections-per-uid must be at least 1".to_owned());
+        }
+        if !opts.parent_failover.is_empty() {
+            let parent_options = [(opts.parent_tcp.is_some(), "--parent-tcp"), (opts.replay_to_client.is_some(), "--replay-to-client"), (opts.wait_for_parent.is_some(), "--wait-for-parent"), (opts.auto_discover, "--auto-discover")];
+            if let Some((_, option)) = parent_options.iter().find(|(set, _)| *set) {
+                return Err(format!("--parent-failover can't be combined with {}", option));
+            }
+        }
+        if opts.journald && opts.log_file.is_some() {
+            return Err("--journald can't be combined with --log-file".to_owned());
+        }
+        if opts.max_lifetime_connections == Some(0) {
+            return Err("--max-lifetime-connections must be at least 1".to_owned());
+        }
+        if opts.max_drain_bytes == Some(0) {
+            return Err("--max-drain-bytes must be at least 1".to_owned());
+        }
+        if opts.persist && opts.display_name.is_none() {
+            return Err("--persist requires --display-name".to_owned());
+        }
+        if opts.persist && opts.deny_after_exit {
+            return Err("--persist and --deny-new-connections-after-child-exit are mutually exclusive".to_owned());
+        }
+        if opts.post_exit_grace.is_some() && (opts.persist || opts.deny_after_exit) {
+            return Err("--post-exit-grace can't be combined with --persist or --deny-new-connections-after-child-exit".to_owned());
+        }
+        if opts.display_name.as_ref().is_some_and(|name| name.is_empty() || name.contains('/')) {
+            return Err("--display-name must be a plain file name".to_owned());
+        }
+        if opts.expect_connection_fatal && opts.expect_connection_timeout.is_none() {
+            return Err("--expect-connection-fatal requires --expect-connection-timeout".to_owned());
+        }
+        if opts.watchdog_abort && opts.watchdog.is_none() {
+            return Err("--watchdog-abort requires --watchdog".to_owned());
+        }
+        if opts.watchdog.is_some_and(|timeout| timeout.is_zero()) {
+            return Err("--watchdog must be positive".to_owned());
+        }
+        if opts.latency_histogram && opts.control_socket.is_none() {
+            return Err("--latency-histogram requires --control-socket".to_owned());
+        }
+        if opts.liveness_fd.is_some() && opts.heartbeat_interval.is_none() {
+            return Err("--liveness-fd requires --heartbeat-interval".to_owned());
+        }
+        if opts.heartbeat_interval.is_some_and(|interval| interval.is_zero()) {
+            return Err("--heartbeat-interval must be positive".to_owned());
+        }
+        if opts.max_message_size.is_some_and(|size| size < crate::wire::HEADER_SIZE) {
+            return Err(format!("--max-message-size must be at least the {} byte message header", crate::wire::HEADER_SIZE));
+        }
+        // keeps the injected event far below the maximum wayland message size
+        if opts.inject_globals.iter().any(|(interface, _)| interface.len() > 1024) {
+            return Err("--inject-global interface names are limited to 1024 bytes".to_owned());
+        }
+        if opts.on_parent_outage.is_some() && !opts.experimental_reconnect {
+            return Err("--on-parent-outage requires --experimental-reconnect".to_owned());
+        }
+        if opts.replay_to_client.is_some() {
+            let parent_options = [(opts.parent_tcp.is_some(), "--parent-tcp"), (!opts.routes.is_empty(), "--route"), (opts.experimental_reconnect, "--experimental-reconnect")];
+            if let Some((_, option)) = parent_options.iter().find(|(set, _)| *set) {
+                return Err(format!("--replay-to-client can't be combined with {}", option));
+            }
+        }
+        // stdio is never ours to take over, and an fd given to two options would be closed twice
+        let adopted: Vec<_> = opts.adopted_fds().collect();
+        for (i, &(fd, option)) in adopted.iter().enumerate() {
+            if fd <= 2 {
+                return Err(format!("{} takes fd numbers above 2, {} is stdio", option, fd));
+            }
+            match adopted[..i].iter().find(|&&(earlier, _)| earlier == fd) {
+                Some(&(_, earlier)) if earlier == option => return Err(format!("{} lists fd {} twice", option, fd)),
+                Some(&(_, earlier)) => return Err(format!("{} and {} both name fd {}", earlier, option, fd)),
+                None => {}
+            }
+        }
+        // a byte stream from TCP can't be split into the packets a SEQPACKET compositor expects
+        if opts.listen_tcp.is_some() && opts.seqpacket {
+            return Err("--listen-tcp can't be combined with --seqpacket".to_owned());
+        }
+        if opts.reorder_safe_priority {
+            let ordered_options = [(opts.debug_ordering, "--debug-ordering"), (opts.compress_queue, "--compress-queue")];
+            if let Some((_, option)) = ordered_options.iter().find(|(set, _)| *set) {
+                return Err(format!("--reorder-safe-priority can't be combined with {}", option));
+            }
+        }
+        if opts.args_from.is_some() && !opts.command.is_empty() {
+            return Err("--args-file and --args-stdin can't be combined with a command on the command line".to_owned());
+        }
+        if opts.no_child {
+            let child_options = [
+                (!opts.command.is_empty() || opts.args_from.is_some(), "a command"),
+                (opts.pty, "--pty"),
+                (opts.chdir.is_some(), "--chdir"),
+                (opts.socket_env.is_some(), "--socket-env"),
+                (!opts.no_cloexec_for_child.is_empty(), "--no-cloexec-for-child"),
+                (!opts.unshare.is_empty(), "--unshare"),
+                (opts.cgroup.is_some(), "--cgroup"),
+                (opts.seccomp.is_some(), "--seccomp"),
+                (opts.deny_after_exit, "--deny-new-connections-after-child-exit"),
+                (opts.post_exit_grace.is_some(), "--post-exit-grace"),
+                (opts.expect_connection_timeout.is_some(), "--expect-connection-timeout"),
+            ];
+            if let Some((_, option)) = child_options.iter().find(|(set, _)| *set) {
+                return Err(format!("--no-child can't be combined with {}", option));
+            }
+        }
+        // the child would only fail with a generic spawn error
+        if let Some(dir) = &opts.chdir {
+            match std::fs::metadata(dir) {
+                Ok(metadata) if !metadata.is_dir() => return Err(format!("--chdir {} is not a directory", dir.display())),
+                Err(e) => return Err(format!("--chdir {}: {}", dir.display(), e)),
+                Ok(_) => {}
+            }
+            rustix::fs::access(dir, rustix::fs::Access::EXEC_OK).map_err(|e| format!("--chdir {}: {}", dir.display(), e))?;
+        }
+        if opts.idle_timeout.is_some() && !opts.no_child {
+            return Err("--idle-timeout requires --no-child".to_owned());
+        }
+        if opts.command.is_empty() && !opts.self_test && opts.args_from.is_none() && !opts.no_child {
+            return Err("no command given".to_owned());
+        }
+
+        Ok(opts)
+    }
+
+    /// The fds these options hand to the proxy to take over, with the option naming each.
+    pub(crate) fn adopted_fds(&self) -> impl Iterator<Item = (i32, &'static str)> + '_ {
+        let single = [(self.status_fd, "--status-fd"), (self.liveness_fd, "--liveness-fd")];
+        single.into_iter().filter_map(|(fd, option)| Some((fd?, option))).chain(self.parent_fd_pool.iter().map(|&fd| (fd, "--parent-fd-pool")))
+    }
+}
+
+fn parse_directions(name: &str) -> Result<Directions, String> {
+    [Directions::Both, Directions::ToServer, Directions::ToClient]
+        .into_iter()
+        .find(|directions| directions.name() == name)
+        .ok_or_else(|| format!("unknown --direction {:?}, expected both, to-server or to-client", name))
+}
+
+/// Splits the contents of an --args-file or stdin into arguments. The separator is NUL if there is
+/// one anywhere, newline otherwise, a separator at the very end is optional.
+fn read_command(source: &ArgsSource) -> Result<Vec<String>, String> {
+    let (name, contents) = match source {
+        ArgsSource::File(path) => (path.display().to_string(), std::fs::read(path)),
+        ArgsSource::Stdin => {
+            let mut contents = Vec::new();
+            ("stdin".to_owned(), std::io::stdin().read_to_end(&mut contents).map(|_| contents))
+        }
+    };
+    let contents = contents.map_err(|e| format!("failed to read arguments from {}: {}", name, e))?;
+    let contents = String::from_utf8(contents).map_err(|_| format!("arguments in {} are not valid UTF-8", name))?;
+
+    let separator = if contents.contains('\0') { '\0' } else { '\n' };
+    let contents = contents.strip_suffix(separator).unwrap_or(&contents);
+    let command: Vec<String> = match contents.is_empty() {
+        true => Vec::new(),
+        false => contents.split(separator).map(str::to_owned).collect(),
+    };
+    if command.first().is_none_or(|program| program.is_empty()) {
+        return Err(format!("no command in {}", name));
+    }
+    Ok(command)
+}
+
+/// The longest duration an option takes, deadlines get computed by adding them to the current time
+/// which has to stay representable.
+const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+/// Option values can either be passed as `--name=value` or as a separate `--name value` argument.
+struct Args<I> {
+    iter: I,
+    name: String,
+    inline: Option<String>,
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    fn next(&mut self) -> Option<String> {
+        let arg = self.iter.next()?;
+        match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                self.name = name.to_owned();
+                self.inline = Some(value.to_owned());
+            }
+            _ => self.name = arg,
+        }
+        Some(self.name.clone())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        match self.inline.take() {
+            Some(_) => Err(format!("{} does not take a value", self.name)),
+            None => Ok(()),
+        }
+    }
+
+    fn value(&mut self) -> Result<String, String> {
+        self.inline.take().or_else(|| self.iter.next()).ok_or_else(|| format!("{} requires a value", self.name))
+    }
+
+    /// A flag that may be given as `--flag=false`.
+    fn boolean(&mut self) -> Result<bool, String> {
+        match self.inline.take().as_deref() {
+            None | Some("true") => Ok(true),
+            Some("false") => Ok(false),
+            Some(value) => Err(format!("invalid value for {}: {}, expected true or false", self.name, value)),
+        }
+    }
+
+    fn parsed<T: FromStr>(&mut self) -> Result<T, String> {
+        let value = self.value()?;
+        value.parse().map_err(|_| format!("invalid value for {}: {}", self.name, value))
+    }
+
+    /// A comma separated list of values.
+    fn list<T: FromStr>(&mut self) -> Result<Vec<T>, String> {
+        let value = self.value()?;
+        value.split(',').map(|item| item.parse().map_err(|_| format!("invalid value for {}: {}", self.name, item))).collect()
+    }
+
+    fn cap(&mut self) -> Result<(String, u32), String> {
+        let value = self.value()?;
+        match value.split_once('=') {
+            Some((interface, version)) if !interface.is_empty() => match version.parse() {
+                Ok(version) => Ok((interface.to_owned(), version)),
+                Err(_) => Err(format!("invalid version for {}: {}", self.name, value)),
+            },
+            _ => Err(format!("{} expects INTERFACE=VERSION, got {}", self.name, value)),
+        }
+    }
+
+    fn duration(&mut self) -> Result<Duration, String> {
+        let secs: f64 = self.parsed()?;
+        let duration = Duration::try_from_secs_f64(secs).map_err(|_| format!("invalid duration for {}: {}", self.name, secs))?;
+        if duration > MAX_DURATION {
+            return Err(format!("{} takes at most {} seconds, got {}", self.name, MAX_DURATION.as_secs(), secs));
+        }
+        Ok(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn adopted_fds_are_distinct_and_not_stdio() {
+        assert!(parse("--status-fd 3 --liveness-fd 4 --heartbeat-interval 1 --parent-fd-pool 5,6 true").is_ok());
+        assert_eq!(parse("--status-fd 2 true").err(), Some("--status-fd takes fd numbers above 2, 2 is stdio".to_owned()));
+        assert_eq!(parse("--parent-fd-pool 3,0 true").err(), Some("--parent-fd-pool takes fd numbers above 2, 0 is stdio".to_owned()));
+        assert_eq!(parse("--status-fd 3 --liveness-fd 3 --heartbeat-interval 1 true").err(), Some("--status-fd and --liveness-fd both name fd 3".to_owned()));
+        assert_eq!(parse("--liveness-fd 4 --heartbeat-interval 1 --parent-fd-pool 3,4 true").err(), Some("--liveness-fd and --parent-fd-pool both name fd 4".to_owned()));
+        assert_eq!(parse("--parent-fd-pool 3,5,3 true").err(), Some("--parent-fd-pool lists fd 3 twice".to_owned()));
+    }
+
+    #[test]
+    fn durations_are_capped() {
+        assert_eq!(parse("--no-child --idle-timeout 315360000").ok().and_then(|opts| opts.idle_timeout), Some(MAX_DURATION));
+        assert_eq!(parse("--no-child --idle-timeout 1e19").err(), Some("--idle-timeout takes at most 315360000 seconds, got 10000000000000000000".to_owned()));
+        assert_eq!(parse("--queue-flush-timeout 315360001 true").err(), Some("--queue-flush-timeout takes at most 315360000 seconds, got 315360001".to_owned()));
+    }
+}
@@ -0,0 +1,203 @@
+// Resolution of the compositor socket we forward connections to.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rustix::fd::OwnedFd;
+use rustix::fs::{fcntl_getfl, fcntl_setfl, statat, AtFlags, FileType, OFlags, CWD};
+use rustix::io::{fcntl_setfd, Errno, FdFlags};
+use rustix::net::{connect_unix, getpeername, socket_with, AddressFamily, SocketAddrAny, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::{Clock, Options, ProxyError};
+
+// libwayland-server hands out wayland-0 to wayland-31
+const DISCOVER_RANGE: std::ops::Range<u32> = 0..32;
+// how often --wait-for-parent retries
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub enum Target {
+    Path(PathBuf),
+    /// WAYLAND_DISPLAY named an fd we inherited that's already connected to the compositor. It
+    /// can only carry one client, further ones get connected to the peer's address if it has one.
+    Inherited { fd: OwnedFd, peer: Option<PathBuf> },
+}
+
+pub fn resolve(opts: &Options, xdg_runtime_dir: &str, clock: &dyn Clock) -> Result<Target, ProxyError> {
+    let wayland = match std::env::var("WAYLAND_DISPLAY") {
+        Ok(wayland) if !wayland.is_empty() => wayland,
+        _ if opts.auto_discover => {
+            let path = discover(xdg_runtime_dir).ok_or_else(|| ProxyError::Parse { context: format!("WAYLAND_DISPLAY not set and no connectable wayland socket found in {}", xdg_runtime_dir) })?;
+            log!("WAYLAND_DISPLAY not set, using discovered compositor socket {}", path.display());
+            return Ok(Target::Path(path));
+        }
+        _ => return Err(ProxyError::Env { var: "WAYLAND_DISPLAY" }),
+    };
+
+    if let Some(fd) = inherited_fd(&wayland) {
+        // taking it over a second time would close it twice
+        if let Some((_, option)) = opts.adopted_fds().find(|&(adopted, _)| adopted == fd) {
+            return Err(ProxyError::Parse { context: format!("WAYLAND_DISPLAY {} names the fd already given to {}", wayland, option) });
+        }
+        let fd = adopt(fd, Path::new(&wayland))?;
+        let peer = match getpeername(&fd) {
+            Ok(Some(SocketAddrAny::Unix(addr))) => addr.path().map(|p| PathBuf::from(OsStr::from_bytes(p.to_bytes()))),
+            _ => None,
+        };
+        return Ok(Target::Inherited { fd, peer });
+    }
+    let path = runtime_path(&wayland, xdg_runtime_dir);
+    if let Some(timeout) = opts.wait_for_parent {
+        wait_connectable(&path, timeout, clock)?;
+    }
+    check_socket(&path)?;
+    Ok(Target::Path(path))
+}
+
+/// The --parent-failover sockets in the order they're tried. Unlike WAYLAND_DISPLAY they aren't
+/// checked, any of them may be down when we start.
+pub fn failover_paths(opts: &Options, xdg_runtime_dir: &str) -> Vec<PathBuf> {
+    opts.parent_failover.iter().map(|name| runtime_path(name, xdg_runtime_dir)).collect()
+}
+
+/// Retries connecting to `path` until it works, for a compositor that's still starting up.
+fn wait_connectable(path: &Path, timeout: Duration, clock: &dyn Clock) -> Result<(), ProxyError> {
+    let start = clock.now();
+    let mut logged = false;
+    loop {
+        match connectable(path) {
+            Ok(()) => return Ok(()),
+            Err(errno) if clock.now().duration_since(start) >= timeout => return Err(ProxyError::Connect { context: path.display().to_string(), errno }),
+            Err(_) => {}
+        }
+        if !mem::replace(&mut logged, true) {
+            log!("waiting up to {:?} for the compositor socket {}", timeout, path.display());
+        }
+        clock.sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Looks up the --parent-tcp or --listen-tcp address given to `option`, the first result wins.
+pub fn resolve_tcp(option: &str, target: &str) -> Result<std::net::SocketAddr, ProxyError> {
+    use std::net::ToSocketAddrs;
+    let fail = |reason: String| ProxyError::Parse { context: format!("invalid {} {}: {}", option, target, reason) };
+    target.to_socket_addrs().map_err(|e| fail(e.to_string()))?.next().ok_or_else(|| fail("no addresses found".to_owned()))
+}
+
+/// The fd number of a `/proc/self/fd/N` or `/dev/fd/N` path.
+fn inherited_fd(name: &str) -> Option<RawFd> {
+    let n = name.strip_prefix("/proc/self/fd/").or_else(|| name.strip_prefix("/dev/fd/"))?;
+    n.parse().ok()
+}
+
+/// Takes over the --parent-fd-pool sockets, in the order they'll be handed out.
+pub fn adopt_pool(fds: &[RawFd]) -> Result<Vec<OwnedFd>, ProxyError> {
+    fds.iter().map(|&fd| adopt(fd, Path::new(&format!("/proc/self/fd/{}", fd)))).collect()
+}
+
+fn adopt(fd: RawFd, path: &Path) -> Result<OwnedFd, ProxyError> {
+    let err = |errno| ProxyError::Connect { context: path.display().to_string(), errno };
+    if fd <= 2 {
+        return Err(ProxyError::Parse { context: format!("{} is stdio, not a connection to the compositor", path.display()) });
+    }
+    // checks through the path, so it also tells us whether the fd is open at all
+    check_socket(path)?;
+    // SAFETY: the fd is open, and WAYLAND_DISPLAY or --parent-fd-pool handing it to us means
+    // nobody else uses it, the other fds we take over are checked to be different ones
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if rustix::net::sockopt::get_socket_acceptconn(&fd) == Ok(true) {
+        // don't close what isn't ours after all
+        std::mem::forget(fd);
+        return Err(ProxyError::Setup { context: format!("{} as the compositor connection, it's a listening socket", path.display()), errno: Errno::NOTCONN });
+    }
+    // the child must not inherit it, and we only do non-blocking I/O on parent sockets
+    fcntl_setfd(&fd, FdFlags::CLOEXEC).map_err(err)?;
+    fcntl_setfl(&fd, fcntl_getfl(&fd).map_err(err)? | OFlags::NONBLOCK).map_err(err)?;
+    Ok(fd)
+}
+
+/// Resolves a socket name the way libwayland does for WAYLAND_DISPLAY, relative to
+/// XDG_RUNTIME_DIR unless it's absolute, and checks that it is a socket.
+pub fn socket_path(name: &str, xdg_runtime_dir: &str) -> Result<PathBuf, ProxyError> {
+    let path = runtime_path(name, xdg_runtime_dir);
+    check_socket(&path)?;
+    Ok(path)
+}
+
+fn runtime_path(name: &str, xdg_runtime_dir: &str) -> PathBuf {
+    if name.starts_with('/') {
+        PathBuf::from(name)
+    } else {
+        [xdg_runtime_dir, name].iter().collect()
+    }
+}
+
+/// Fail early with a clear message instead of a confusing connect() errno on the first client
+/// connection when the path is missing or points at something other than a socket.
+pub fn check_socket(path: &Path) -> Result<(), ProxyError> {
+    let stat = statat(CWD, path, AtFlags::empty()).map_err(|errno| ProxyError::Connect { context: path.display().to_string(), errno })?;
+    let kind = match FileType::from_raw_mode(stat.st_mode) {
+        FileType::Socket => return Ok(()),
+        FileType::RegularFile => "regular file",
+        FileType::Directory => "directory",
+        FileType::Fifo => "fifo",
+        FileType::CharacterDevice | FileType::BlockDevice => "device",
+        _ => "unknown file type",
+    };
+    Err(ProxyError::NotASocket { path: path.to_owned(), kind })
+}
+
+/// Probe the numbered compositor sockets and return the first that accepts a connection.
+fn discover(xdg_runtime_dir: &str) -> Option<PathBuf> {
+    DISCOVER_RANGE.map(|n| [xdg_runtime_dir, &format!("wayland-{}", n)].iter().collect::<PathBuf>()).find(|path| connectable(path).is_ok())
+}
+
+/// Connects to `path` and hangs up again.
+fn connectable(path: &Path) -> Result<(), Errno> {
+    let addr = SocketAddrUnix::new(path)?;
+    let socket = socket_with(AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC, None)?;
+    connect_unix(&socket, &addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::MockClock;
+
+    #[test]
+    fn wait_connectable_times_out_on_the_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let path = std::env::temp_dir().join(format!("p5wl-missing-compositor-{}", std::process::id()));
+        let result = wait_connectable(&path, Duration::from_secs(5), &clock);
+        assert!(matches!(result, Err(ProxyError::Connect { errno: Errno::NOENT, .. })));
+        // the retries slept on the clock, not for real
+        assert!(clock.now().duration_since(start) >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn adopt_refuses_stdio() {
+        for fd in 0..=2 {
+            assert!(matches!(adopt(fd, Path::new(&format!("/proc/self/fd/{}", fd))), Err(ProxyError::Parse { .. })));
+        }
+        // still open, nothing took them over
+        assert!(rustix::io::fcntl_getfd(rustix::stdio::stderr()).is_ok());
+    }
+
+    #[test]
+    fn wayland_display_refuses_stdio() {
+        let _turn = crate::tests::serial();
+        let previous = std::env::var_os("WAYLAND_DISPLAY");
+        std::env::set_var("WAYLAND_DISPLAY", "/proc/self/fd/1");
+        let result = resolve(&Options::default(), "/nonexistent", &MockClock::new());
+        match previous {
+            Some(previous) => std::env::set_var("WAYLAND_DISPLAY", previous),
+            None => std::env::remove_var("WAYLAND_DISPLAY"),
+        }
+        assert!(matches!(result, Err(ProxyError::Parse { context }) if context == "/proc/self/fd/1 is stdio, not a connection to the compositor"));
+    }
+}
@@ -0,0 +1,72 @@
+// Who opened a client connection, for logging. SO_PEERCRED only gives ids, the name of the
+// program comes from /proc/PID/comm.
+//
+// The name is only read once something wants to log it and then kept with the connection. By
+// then the client may have exited and its pid been handed to another process, so a process that
+// started after the connection was accepted isn't taken for the peer. Peers that are gone, in
+// another pid namespace or unreadable just get no name.
+
+use std::cell::OnceCell;
+use std::time::Duration;
+
+use rustix::net::UCred;
+use rustix::process::Pid;
+
+pub struct Peer {
+    pub cred: UCred,
+    // CLOCK_BOOTTIME at accept, the clock /proc reports process start times in
+    accepted: Option<Duration>,
+    name: OnceCell<Option<String>>,
+}
+
+impl Peer {
+    pub fn new(cred: UCred) -> Peer {
+        Peer { cred, accepted: boottime(), name: OnceCell::new() }
+    }
+
+    /// The peer's program name, if it can still be told.
+    pub fn name(&self) -> Option<&str> {
+        self.name.get_or_init(|| self.read_name()).as_deref()
+    }
+
+    fn read_name(&self) -> Option<String> {
+        let pid = Pid::as_raw(Some(self.cred.pid));
+        let name = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        // read after comm, a recycled pid shows up as a start after the accept
+        let started = start_time(pid)?;
+        if started > self.accepted? {
+            return None;
+        }
+        Some(name.trim_end_matches('\n').to_owned())
+    }
+}
+
+/// e.g. `pid 1234 (foot) uid 1000 gid 1000`.
+pub fn describe(peer: Option<&Peer>) -> String {
+    let Some(peer) = peer else {
+        return "unknown peer".to_owned();
+    };
+    let cred = &peer.cred;
+    let name = peer.name().map(|name| format!(" ({})", name)).unwrap_or_default();
+    format!("pid {}{} uid {} gid {}", Pid::as_raw(Some(cred.pid)), name, cred.uid.as_raw(), cred.gid.as_raw())
+}
+
+fn boottime() -> Option<Duration> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: ts is a valid timespec to write to
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// When the process started, relative to boot.
+fn start_time(pid: i32) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // comm can contain spaces and parentheses, the fields after it can't. starttime is field 22,
+    // the 20th after comm
+    let ticks: u64 = stat.rsplit_once(')')?.1.split_whitespace().nth(19)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (per_second > 0).then(|| Duration::from_secs_f64(ticks as f64 / per_second as f64))
+}
@@ -0,0 +1,89 @@
+// --reorder-safe-priority: let input events overtake other events queued for a slow client.
+//
+// When a client falls behind, everything the compositor sends it waits in the queue in arrival
+// order. A client busy with a large batch of surface or output events then also sees pointer and
+// keyboard input late, which is what users notice. With this option a chunk of input events may
+// be queued ahead of chunks that were received earlier.
+//
+// Wayland only guarantees ordering per connection, but clients rely on it, so reordering is
+// restricted to what can't change the meaning of the message stream:
+//
+//   - Only events to wl_pointer, wl_keyboard and wl_touch objects are moved, and only if every
+//     message of the received chunk is one. Such chunks are moved whole, so a pointer frame stays
+//     together with the events it groups.
+//   - A chunk never overtakes a queued message to one of the objects it targets, nor a
+//     wl_display.delete_id of one of them. Messages to the same object stay in order, and a
+//     destroyed object's id can't be seen reused before its deletion.
+//   - Chunks with fds, like wl_keyboard.keymap, or events whose signature we don't know stay in
+//     place. The receiver pairs fds with messages in stream order.
+//   - The front of the queue may already be partially sent, nothing is moved ahead of it.
+//
+// Input events don't create objects and only refer to objects the client created itself, like
+// the surface a pointer entered, so moving them can't make them refer to something the client
+// hasn't been told about yet. Reordering needs whole messages and turns on message framing. It
+// makes --debug-ordering meaningless, and with --compress-queue every queued chunk would have to
+// be decompressed to be inspected, so both are rejected.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::tracker::Tracker;
+use crate::wire::{self, Header, DISPLAY_DELETE_ID, DISPLAY_ID, HEADER_SIZE};
+use crate::BufferedMessage;
+
+const INPUT_INTERFACES: &[&str] = &["wl_pointer", "wl_keyboard", "wl_touch"];
+
+/// The objects targeted by the events in `bytes` if the chunk may be moved ahead of others.
+pub fn input_targets(tracker: &Tracker, bytes: &[u8], has_fds: bool) -> Option<HashSet<u32>> {
+    if has_fds {
+        return None;
+    }
+    let mut targets = HashSet::new();
+    let mut offset = 0;
+    while let Some(header) = Header::parse(&bytes[offset..]) {
+        let input = tracker.interface(header.object).is_some_and(|interface| INPUT_INTERFACES.contains(&interface));
+        if !input || tracker.event_fds(header) != Some(0) {
+            return None;
+        }
+        targets.insert(header.object);
+        offset += header.size as usize;
+    }
+    Some(targets)
+}
+
+/// How far from the front a chunk targeting `targets` can be inserted into `queued`.
+pub fn insertion_point(queued: &VecDeque<BufferedMessage>, targets: &HashSet<u32>) -> usize {
+    let mut at = queued.len();
+    while at > 1 && !blocks(&queued[at - 1], targets) {
+        at -= 1;
+    }
+    at
+}
+
+fn blocks(msg: &BufferedMessage, targets: &HashSet<u32>) -> bool {
+    // everything but the front is a chunk of whole messages, and never compressed
+    let crate::Payload::Plain(queued) = &msg.payload else {
+        return true;
+    };
+    let (front, back) = queued.as_slices();
+    let contiguous;
+    let bytes = match back.is_empty() {
+        true => front,
+        false => {
+            contiguous = [front, back].concat();
+            &contiguous[..]
+        }
+    };
+    let mut offset = 0;
+    while let Some(header) = Header::parse(&bytes[offset..]) {
+        let size = header.size as usize;
+        if targets.contains(&header.object) {
+            return true;
+        }
+        let deleted = header.object == DISPLAY_ID && header.opcode == DISPLAY_DELETE_ID;
+        if deleted && wire::arg_u32(&bytes[offset..][..size], HEADER_SIZE).is_some_and(|id| targets.contains(&id)) {
+            return true;
+        }
+        offset += size;
+    }
+    false
+}
@@ -0,0 +1,263 @@
+// Message signatures for the core wayland protocol and xdg-shell, so the tracker can follow which
+// interface every object id belongs to and name messages.
+//
+// Signatures use libwayland's notation without the nullability and since-version markers:
+// `i` int, `u` uint, `f` fixed, `s` string, `o` object, `n` new_id, `a` array, `h` fd. A new_id
+// without a fixed interface (wl_registry.bind) takes its interface from the preceding string.
+
+use crate::wire::{self, HEADER_SIZE};
+use crate::Direction;
+
+pub struct Interface {
+    pub name: &'static str,
+    pub requests: &'static [Message],
+    pub events: &'static [Message],
+}
+
+pub struct Message {
+    pub name: &'static str,
+    pub signature: &'static str,
+    /// interface of the object created by the `n` argument
+    pub creates: Option<&'static str>,
+}
+
+const fn m(name: &'static str, signature: &'static str) -> Message {
+    Message { name, signature, creates: None }
+}
+
+const fn new(name: &'static str, signature: &'static str, interface: &'static str) -> Message {
+    Message { name, signature, creates: Some(interface) }
+}
+
+pub fn interface(name: &str) -> Option<&'static Interface> {
+    INTERFACES.iter().find(|interface| interface.name == name)
+}
+
+impl Interface {
+    /// The request (towards the parent) or event (towards the child) with this opcode.
+    pub fn message(&self, dir: Direction, opcode: u16) -> Option<&Message> {
+        let messages = match dir {
+            Direction::ToParent => self.requests,
+            Direction::ToChild => self.events,
+        };
+        messages.get(opcode as usize)
+    }
+}
+
+impl Message {
+    /// The object id and interface name of the object this message creates, if any. For a new_id
+    /// without a fixed interface, as in wl_registry.bind, also the version the client asked for.
+    pub fn created_object<'a>(&'a self, msg: &'a [u8]) -> Option<(u32, &'a [u8], Option<u32>)> {
+        let mut offset = HEADER_SIZE;
+        let mut last_string: &[u8] = &[];
+        let mut last_uint = None;
+        for arg in self.signature.bytes() {
+            match arg {
+                b'n' => {
+                    let id = wire::arg_u32(msg, offset)?;
+                    return Some(match self.creates {
+                        Some(interface) => (id, interface.as_bytes(), None),
+                        None => (id, last_string, last_uint),
+                    });
+                }
+                b's' => {
+                    let (string, next) = wire::arg_string(msg, offset)?;
+                    last_string = string;
+                    offset = next;
+                }
+                b'u' => {
+                    last_uint = wire::arg_u32(msg, offset);
+                    offset += 4;
+                }
+                b'a' => offset = wire::arg_string(msg, offset)?.1,
+                // passed as ancillary data, not in the message body
+                b'h' => {}
+                _ => offset += 4,
+            }
+        }
+        None
+    }
+}
+
+static INTERFACES: &[Interface] = &[
+    Interface {
+        name: "wl_display",
+        requests: &[new("sync", "n", "wl_callback"), new("get_registry", "n", "wl_registry")],
+        events: &[m("error", "ous"), m("delete_id", "u")],
+    },
+    Interface {
+        name: "wl_registry",
+        requests: &[m("bind", "usun")],
+        events: &[m("global", "usu"), m("global_remove", "u")],
+    },
+    Interface { name: "wl_callback", requests: &[], events: &[m("done", "u")] },
+    Interface {
+        name: "wl_compositor",
+        requests: &[new("create_surface", "n", "wl_surface"), new("create_region", "n", "wl_region")],
+        events: &[],
+    },
+    Interface {
+        name: "wl_shm_pool",
+        requests: &[new("create_buffer", "niiiiu", "wl_buffer"), m("destroy", ""), m("resize", "i")],
+        events: &[],
+    },
+    Interface {
+        name: "wl_shm",
+        requests: &[new("create_pool", "nhi", "wl_shm_pool"), m("release", "")],
+        events: &[m("format", "u")],
+    },
+    Interface { name: "wl_buffer", requests: &[m("destroy", "")], events: &[m("release", "")] },
+    Interface {
+        name: "wl_data_offer",
+        requests: &[m("accept", "us"), m("receive", "sh"), m("destroy", ""), m("finish", ""), m("set_actions", "uu")],
+        events: &[m("offer", "s"), m("source_actions", "u"), m("action", "u")],
+    },
+    Interface {
+        name: "wl_data_source",
+        requests: &[m("offer", "s"), m("destroy", ""), m("set_actions", "u")],
+        events: &[m("target", "s"), m("send", "sh"), m("cancelled", ""), m("dnd_drop_performed", ""), m("dnd_finished", ""), m("action", "u")],
+    },
+    Interface {
+        name: "wl_data_device",
+        requests: &[m("start_drag", "ooou"), m("set_selection", "ou"), m("release", "")],
+        events: &[new("data_offer", "n", "wl_data_offer"), m("enter", "uoffo"), m("leave", ""), m("motion", "uff"), m("drop", ""), m("selection", "o")],
+    },
+    Interface {
+        name: "wl_data_device_manager",
+        requests: &[new("create_data_source", "n", "wl_data_source"), new("get_data_device", "no", "wl_data_device")],
+        events: &[],
+    },
+    Interface { name: "wl_shell", requests: &[new("get_shell_surface", "no", "wl_shell_surface")], events: &[] },
+    Interface {
+        name: "wl_shell_surface",
+        requests: &[
+            m("pong", "u"),
+            m("move", "ou"),
+            m("resize", "ouu"),
+            m("set_toplevel", ""),
+            m("set_transient", "oiiu"),
+            m("set_fullscreen", "uuo"),
+            m("set_popup", "ouoiiu"),
+            m("set_maximized", "o"),
+            m("set_title", "s"),
+            m("set_class", "s"),
+        ],
+        events: &[m("ping", "u"), m("configure", "uii"), m("popup_done", "")],
+    },
+    Interface {
+        name: "wl_surface",
+        requests: &[
+            m("destroy", ""),
+            m("attach", "oii"),
+            m("damage", "iiii"),
+            new("frame", "n", "wl_callback"),
+            m("set_opaque_region", "o"),
+            m("set_input_region", "o"),
+            m("commit", ""),
+            m("set_buffer_transform", "i"),
+            m("set_buffer_scale", "i"),
+            m("damage_buffer", "iiii"),
+            m("offset", "ii"),
+        ],
+        events: &[m("enter", "o"), m("leave", "o"), m("preferred_buffer_scale", "i"), m("preferred_buffer_transform", "u")],
+    },
+    Interface {
+        name: "wl_seat",
+        requests: &[new("get_pointer", "n", "wl_pointer"), new("get_keyboard", "n", "wl_keyboard"), new("get_touch", "n", "wl_touch"), m("release", "")],
+        events: &[m("capabilities", "u"), m("name", "s")],
+    },
+    Interface {
+        name: "wl_pointer",
+        requests: &[m("set_cursor", "uoii"), m("release", "")],
+        events: &[
+            m("enter", "uoff"),
+            m("leave", "uo"),
+            m("motion", "uff"),
+            m("button", "uuuu"),
+            m("axis", "uuf"),
+            m("frame", ""),
+            m("axis_source", "u"),
+            m("axis_stop", "uu"),
+            m("axis_discrete", "ui"),
+            m("axis_value120", "ui"),
+            m("axis_relative_direction", "uu"),
+        ],
+    },
+    Interface {
+        name: "wl_keyboard",
+        requests: &[m("release", "")],
+        events: &[m("keymap", "uhu"), m("enter", "uoa"), m("leave", "uo"), m("key", "uuuu"), m("modifiers", "uuuuu"), m("repeat_info", "ii")],
+    },
+    Interface {
+        name: "wl_touch",
+        requests: &[m("release", "")],
+        events: &[m("down", "uuoiff"), m("up", "uui"), m("motion", "uiff"), m("frame", ""), m("cancel", ""), m("shape", "iff"), m("orientation", "if")],
+    },
+    Interface {
+        name: "wl_output",
+        requests: &[m("release", "")],
+        events: &[m("geometry", "iiiiissi"), m("mode", "uiii"), m("done", ""), m("scale", "i"), m("name", "s"), m("description", "s")],
+    },
+    Interface { name: "wl_region", requests: &[m("destroy", ""), m("add", "iiii"), m("subtract", "iiii")], events: &[] },
+    Interface {
+        name: "wl_subcompositor",
+        requests: &[m("destroy", ""), new("get_subsurface", "noo", "wl_subsurface")],
+        events: &[],
+    },
+    Interface {
+        name: "wl_subsurface",
+        requests: &[m("destroy", ""), m("set_position", "ii"), m("place_above", "o"), m("place_below", "o"), m("set_sync", ""), m("set_desync", "")],
+        events: &[],
+    },
+    Interface {
+        name: "xdg_wm_base",
+        requests: &[m("destroy", ""), new("create_positioner", "n", "xdg_positioner"), new("get_xdg_surface", "no", "xdg_surface"), m("pong", "u")],
+        events: &[m("ping", "u")],
+    },
+    Interface {
+        name: "xdg_positioner",
+        requests: &[
+            m("destroy", ""),
+            m("set_size", "ii"),
+            m("set_anchor_rect", "iiii"),
+            m("set_anchor", "u"),
+            m("set_gravity", "u"),
+            m("set_constraint_adjustment", "u"),
+            m("set_offset", "ii"),
+            m("set_reactive", ""),
+            m("set_parent_size", "ii"),
+            m("set_parent_configure", "u"),
+        ],
+        events: &[],
+    },
+    Interface {
+        name: "xdg_surface",
+        requests: &[m("destroy", ""), new("get_toplevel", "n", "xdg_toplevel"), new("get_popup", "noo", "xdg_popup"), m("set_window_geometry", "iiii"), m("ack_configure", "u")],
+        events: &[m("configure", "u")],
+    },
+    Interface {
+        name: "xdg_toplevel",
+        requests: &[
+            m("destroy", ""),
+            m("set_parent", "o"),
+            m("set_title", "s"),
+            m("set_app_id", "s"),
+            m("show_window_menu", "ouii"),
+            m("move", "ou"),
+            m("resize", "ouu"),
+            m("set_max_size", "ii"),
+            m("set_min_size", "ii"),
+            m("set_maximized", ""),
+            m("unset_maximized", ""),
+            m("set_fullscreen", "o"),
+            m("unset_fullscreen", ""),
+            m("set_minimized", ""),
+        ],
+        events: &[m("configure", "iia"), m("close", ""), m("configure_bounds", "ii"), m("wm_capabilities", "a")],
+    },
+    Interface {
+        name: "xdg_popup",
+        requests: &[m("destroy", ""), m("grab", "ou"), m("reposition", "ou")],
+        events: &[m("configure", "iiii"), m("popup_done", ""), m("repositioned", "u")],
+    },
+];
@@ -0,0 +1,137 @@
+// --pty: run the child on a fresh pseudo-terminal and shuttle its I/O to our own stdio.
+
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+use rustix::event::PollFlags;
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::fs::{fcntl_getfl, fcntl_setfl, open, Mode, OFlags};
+use rustix::io::{read, retry_on_intr, write, Errno};
+use rustix::pty::{grantpt, openpt, ptsname, unlockpt, OpenptFlags};
+use rustix::stdio::stdin;
+use rustix::termios::{isatty, tcgetattr, tcgetwinsize, tcsetattr, tcsetwinsize, OptionalActions, Termios};
+
+use crate::ProxyError;
+
+pub struct Pty {
+    master: Option<OwnedFd>,
+    stdin_open: bool,
+    // keystrokes not yet accepted by the master
+    input: Vec<u8>,
+    // the terminal settings of our stdin before we put it into raw mode
+    saved: Option<Termios>,
+}
+
+impl Pty {
+    /// Opens a new pty and makes it the controlling terminal and stdio of `cmd`.
+    pub fn attach(cmd: &mut Command) -> Result<Pty, ProxyError> {
+        let err = |errno| ProxyError::Pty { errno };
+
+        let master = openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY | OpenptFlags::CLOEXEC).map_err(err)?;
+        grantpt(&master).map_err(err)?;
+        unlockpt(&master).map_err(err)?;
+        let name = ptsname(&master, Vec::new()).map_err(err)?;
+        let slave = open(name.as_c_str(), OFlags::RDWR | OFlags::NOCTTY | OFlags::CLOEXEC, Mode::empty()).map_err(err)?;
+        fcntl_setfl(&master, fcntl_getfl(&master).map_err(err)? | OFlags::NONBLOCK).map_err(err)?;
+
+        let stdin = stdin();
+        let mut saved = None;
+        if isatty(stdin) {
+            if let Ok(size) = tcgetwinsize(stdin) {
+                let _ = tcsetwinsize(&slave, size);
+            }
+            // pass keystrokes through unprocessed, the child's terminal does the line editing
+            let termios = tcgetattr(stdin).map_err(err)?;
+            let mut raw = termios.clone();
+            raw.make_raw();
+            tcsetattr(stdin, OptionalActions::Now, &raw).map_err(err)?;
+            saved = Some(termios);
+        }
+
+        let dup = |fd: &OwnedFd| fd.try_clone().map(Stdio::from).map_err(|e| ProxyError::Pty { errno: Errno::from_io_error(&e).unwrap_or(Errno::IO) });
+        cmd.stdin(dup(&slave)?).stdout(dup(&slave)?).stderr(Stdio::from(slave));
+        // SAFETY: setsid and ioctl are async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                rustix::process::setsid()?;
+                rustix::process::ioctl_tiocsctty(std::io::stdin())?;
+                Ok(())
+            });
+        }
+
+        Ok(Pty { master: Some(master), stdin_open: true, input: Vec::new(), saved })
+    }
+
+    /// Poll entries for the master and our stdin, in that order. Closed ones are polled with empty
+    /// flags so the caller's indexing stays stable.
+    pub fn poll_fds(&self) -> [(BorrowedFd<'_>, PollFlags); 2] {
+        let stdin = stdin();
+        let (master, master_flags) = match &self.master {
+            Some(master) if self.input.is_empty() => (master.as_fd(), PollFlags::IN),
+            Some(master) => (master.as_fd(), PollFlags::IN | PollFlags::OUT),
+            None => (stdin, PollFlags::empty()),
+        };
+        let stdin_flags = if self.stdin_open && self.master.is_some() && self.input.is_empty() { PollFlags::IN } else { PollFlags::empty() };
+        [(master, master_flags), (stdin, stdin_flags)]
+    }
+
+    pub fn handle(&mut self, master_flags: PollFlags, stdin_flags: PollFlags) {
+        let mut buf = [0u8; 4096];
+
+        if stdin_flags.intersects(PollFlags::IN | PollFlags::HUP) {
+            match retry_on_intr(|| read(stdin(), &mut buf)) {
+                Ok(0) | Err(_) => self.stdin_open = false,
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        let Some(master) = &self.master else {
+            return;
+        };
+
+        if !self.input.is_empty() {
+            match retry_on_intr(|| write(master, &self.input)) {
+                Ok(n) => {
+                    self.input.drain(..n);
+                }
+                Err(Errno::AGAIN) => {}
+                Err(_) => self.input.clear(),
+            }
+        }
+
+        if master_flags.intersects(PollFlags::IN | PollFlags::HUP | PollFlags::ERR) {
+            self.flush_output();
+        }
+    }
+
+    /// Copy everything the child has written so far to our stdout.
+    pub fn flush_output(&mut self) {
+        let Some(master) = &self.master else {
+            return;
+        };
+        let mut buf = [0u8; 4096];
+        let mut stdout = std::io::stdout().lock();
+        loop {
+            match retry_on_intr(|| read(master, &mut buf)) {
+                Ok(n) if n > 0 => {
+                    let _ = stdout.write_all(&buf[..n]);
+                    continue;
+                }
+                Err(Errno::AGAIN) => break,
+                // EIO once the last slave fd has been closed, i.e. the child exited
+                _ => self.master = None,
+            }
+            break;
+        }
+        let _ = stdout.flush();
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(termios) = &self.saved {
+            let _ = tcsetattr(stdin(), OptionalActions::Now, termios);
+        }
+    }
+}
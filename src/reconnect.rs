@@ -0,0 +1,126 @@
+// --experimental-reconnect: when the compositor goes away, connect the client to whatever listens
+// on the compositor socket next and replay everything the client sent so far.
+//
+// Wayland has no way to resynchronize a client with a new compositor. The object ids the client
+// holds and the global names it was told about belong to the old one, replaying the requests only
+// recreates that state if the new compositor answers them exactly like the old one did. That's
+// plausible for a client that did little more than look at the registry, so only connections that
+// never passed fds and sent at most REPLAY_LIMIT bytes are recorded. Everything else closes with
+// the compositor as it does without the option. So do connections that hold an object the
+// compositor created, e.g. a wl_data_offer: nothing in the recording makes the new one create it,
+// the client's next request to it would be a protocol error.
+//
+// --on-parent-outage decides what happens to clients that connect while the compositor is away:
+// `reject` closes them right away, `queue` accepts them and connects them once the compositor is
+// back, within the same RETRY_TIMEOUT reconnects get, and `wait` leaves them in the listen
+// backlog by not accepting while any client is waiting for the compositor. Without the option
+// such a client is closed with a warning, like any client whose compositor connect fails.
+
+use std::time::{Duration, Instant};
+
+use rustix::fd::OwnedFd;
+use rustix::io::Errno;
+use rustix::net::{connect_unix, socket_with, AddressFamily, SocketAddrUnix, SocketFlags, SocketType};
+
+pub const REPLAY_LIMIT: usize = 64 * 1024;
+// how often and how long we try to reach the restarted compositor
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outage {
+    Reject,
+    Queue,
+    Wait,
+}
+
+pub fn parse_outage(name: &str) -> Result<Outage, String> {
+    match name {
+        "reject" => Ok(Outage::Reject),
+        "queue" => Ok(Outage::Queue),
+        "wait" => Ok(Outage::Wait),
+        _ => Err(format!("unknown --on-parent-outage policy {:?}, expected reject, queue or wait", name)),
+    }
+}
+
+pub struct Replay {
+    addr: SocketAddrUnix,
+    socket_type: SocketType,
+    // everything the client sent to the compositor
+    sent: Vec<u8>,
+    retry: Option<Retry>,
+}
+
+struct Retry {
+    since: Instant,
+    next: Instant,
+}
+
+impl Replay {
+    pub fn new(addr: SocketAddrUnix, parent: &OwnedFd) -> Replay {
+        let socket_type = rustix::net::sockopt::get_socket_type(parent).unwrap_or(SocketType::STREAM);
+        Replay { addr, socket_type, sent: Vec::new(), retry: None }
+    }
+
+    /// For a client that connected while the compositor was away, already trying to reach it.
+    pub fn pending(addr: SocketAddrUnix, socket_type: SocketType, now: Instant) -> Replay {
+        let mut replay = Replay { addr, socket_type, sent: Vec::new(), retry: None };
+        replay.start(now);
+        replay
+    }
+
+    /// Records bytes received from the client, returns false once the connection can't be
+    /// replayed anymore.
+    pub fn record(&mut self, bytes: &[u8], fds: usize) -> bool {
+        if fds > 0 || self.sent.len() + bytes.len() > REPLAY_LIMIT {
+            return false;
+        }
+        self.sent.extend_from_slice(bytes);
+        true
+    }
+
+    pub fn recorded(&self) -> &[u8] {
+        &self.sent
+    }
+
+    /// Starts trying to reach the new compositor, the first attempt is due right away.
+    pub fn start(&mut self, now: Instant) {
+        self.retry = Some(Retry { since: now, next: now });
+    }
+
+    pub fn reconnecting(&self) -> bool {
+        self.retry.is_some()
+    }
+
+    /// When the next attempt is due, for the poll timeout.
+    pub fn next_attempt(&self) -> Option<Instant> {
+        self.retry.as_ref().map(|retry| retry.next)
+    }
+
+    /// Connects if the next attempt is due, returns the new socket and whether the connect already
+    /// completed. `Ok(None)` means try again later, an error that we gave up.
+    pub fn attempt(&mut self, now: Instant) -> Result<Option<(OwnedFd, bool)>, Errno> {
+        let Some(retry) = &mut self.retry else {
+            return Ok(None);
+        };
+        if now < retry.next {
+            return Ok(None);
+        }
+        let socket = socket_with(AddressFamily::UNIX, self.socket_type, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None)?;
+        let connected = match connect_unix(&socket, &self.addr) {
+            Ok(_) => true,
+            Err(Errno::AGAIN) => false,
+            // nobody listening yet, the socket may not even exist while the compositor restarts
+            Err(errno @ (Errno::NOENT | Errno::CONNREFUSED)) => {
+                if now.duration_since(retry.since) >= RETRY_TIMEOUT {
+                    return Err(errno);
+                }
+                retry.next = now + RETRY_INTERVAL;
+                return Ok(None);
+            }
+            Err(errno) => return Err(errno),
+        };
+        self.retry = None;
+        Ok(Some((socket, connected)))
+    }
+}
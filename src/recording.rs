@@ -0,0 +1,135 @@
+// --record-events and --replay-to-client: record what the compositor sends to a client and play
+// it back to clients later without a compositor, e.g. to test a toolkit's rendering against a
+// fixed event sequence.
+//
+// Only the first wayland connection is recorded. The file starts with MAGIC, followed by one
+// record per event:
+//
+//     u64 LE   bytes the client had sent when the event arrived
+//     u32 LE   fds the event carried
+//     ...      the event as it was on the wire
+//
+// On replay every event waits until the client sent at least as many bytes as the recorded one
+// had, so a client doing the same as back then sees events only for objects it already created.
+// What the client sends is otherwise ignored. fds can't be replayed, events that carried some
+// (keymaps, dmabuf feedback and the like) are skipped.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustix::fd::OwnedFd;
+use rustix::fs::{fcntl_getfl, fcntl_setfl, OFlags};
+use rustix::io::Errno;
+use rustix::net::{socketpair, AddressFamily, SocketFlags, SocketType};
+
+use crate::tracker::Tracker;
+use crate::wire::{Header, HEADER_SIZE};
+use crate::ProxyError;
+
+const MAGIC: &[u8; 8] = b"p5wlev1\n";
+
+/// The --record-events file, owned by the connection being recorded.
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder, ProxyError> {
+        let err = |e: std::io::Error| ProxyError::Setup { context: format!("--record-events file {}", path.display()), errno: Errno::from_io_error(&e).unwrap_or(Errno::IO) };
+        let mut file = File::create(path).map_err(err)?;
+        file.write_all(MAGIC).map_err(err)?;
+        Ok(Recorder { file })
+    }
+
+    /// Appends the whole messages in `events`. `requests` is how many bytes the client sent so
+    /// far, `fds` how many arrived with the chunk, which the tracker attributes to messages.
+    pub fn record(&mut self, requests: u64, events: &[u8], fds: usize, tracker: &Tracker) -> std::io::Result<()> {
+        let mut out = Vec::with_capacity(events.len() + 16);
+        let mut offset = 0;
+        while let Some(header) = Header::parse(&events[offset..]) {
+            let size = header.size as usize;
+            // if we don't know the signature we have to assume the worst
+            let msg_fds = tracker.event_fds(header).unwrap_or(fds.min(1));
+            out.extend_from_slice(&requests.to_le_bytes());
+            out.extend_from_slice(&(msg_fds as u32).to_le_bytes());
+            out.extend_from_slice(&events[offset..][..size]);
+            offset += size;
+        }
+        self.file.write_all(&out)
+    }
+}
+
+/// A --replay-to-client recording, shared by the connections it's played to.
+pub struct Recording {
+    events: Vec<Event>,
+}
+
+struct Event {
+    requests: u64,
+    has_fds: bool,
+    bytes: Vec<u8>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Arc<Recording>, ProxyError> {
+        let invalid = |reason: String| ProxyError::Parse { context: format!("--replay-to-client {}: {}", path.display(), reason) };
+        let data = std::fs::read(path).map_err(|e| invalid(e.to_string()))?;
+        let mut rest = data.strip_prefix(MAGIC).ok_or_else(|| invalid("not a --record-events file".to_owned()))?;
+
+        let mut events = Vec::new();
+        while !rest.is_empty() {
+            let truncated = || invalid(format!("truncated after {} events", events.len()));
+            let record = rest.get(..12 + HEADER_SIZE).ok_or_else(truncated)?;
+            let requests = u64::from_le_bytes(record[0..8].try_into().unwrap());
+            let fds = u32::from_le_bytes(record[8..12].try_into().unwrap());
+            let size = Header::parse(&record[12..]).unwrap().size as usize;
+            if size < HEADER_SIZE {
+                return Err(invalid(format!("malformed event after {} events", events.len())));
+            }
+            let bytes = rest.get(12..12 + size).ok_or_else(truncated)?;
+            events.push(Event { requests, has_fds: fds > 0, bytes: bytes.to_vec() });
+            rest = &rest[12 + size..];
+        }
+
+        let skipped = events.iter().filter(|event| event.has_fds).count();
+        log!("replaying {} recorded events to every client, {} of them carried fds and are skipped", events.len(), skipped);
+        Ok(Arc::new(Recording { events }))
+    }
+
+    /// Starts playing the recording on a new socket, returned in non-blocking mode to be used in
+    /// place of a compositor connection.
+    pub fn play(self: &Arc<Recording>, socket_type: SocketType) -> Result<OwnedFd, Errno> {
+        let (ours, theirs) = socketpair(AddressFamily::UNIX, socket_type, SocketFlags::CLOEXEC, None)?;
+        fcntl_setfl(&theirs, fcntl_getfl(&theirs)? | OFlags::NONBLOCK)?;
+        let recording = Arc::clone(self);
+        std::thread::Builder::new()
+            .name("replay".to_owned())
+            .spawn(move || recording.feed(UnixStream::from(ours)))
+            .map_err(|_| Errno::AGAIN)?;
+        Ok(theirs)
+    }
+
+    // runs until the proxy closes the connection
+    fn feed(&self, mut socket: UnixStream) {
+        let mut buf = [0u8; 4096];
+        let mut received = 0;
+        for event in &self.events {
+            while received < event.requests {
+                match socket.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => received += n as u64,
+                }
+            }
+            if event.has_fds {
+                continue;
+            }
+            if socket.write_all(&event.bytes).is_err() {
+                return;
+            }
+        }
+        while matches!(socket.read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
@@ -0,0 +1,53 @@
+// --route: pick the parent compositor per connection based on the client's credentials.
+//
+// Rules look like `uid=1000:wayland-1` and are checked in order when a connection is accepted,
+// the first one matching the SO_PEERCRED credentials of the client wins. Connections matching
+// no rule go to the default compositor.
+
+use std::str::FromStr;
+
+use rustix::net::UCred;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Pid,
+    Uid,
+    Gid,
+}
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    field: Field,
+    value: u32,
+    /// socket name in XDG_RUNTIME_DIR or absolute path, like WAYLAND_DISPLAY
+    pub target: String,
+}
+
+impl Route {
+    pub fn matches(&self, cred: &UCred) -> bool {
+        match self.field {
+            Field::Pid => rustix::process::Pid::as_raw(Some(cred.pid)) as u32 == self.value,
+            Field::Uid => cred.uid.as_raw() == self.value,
+            Field::Gid => cred.gid.as_raw() == self.value,
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Route, ()> {
+        let (predicate, target) = s.split_once(':').ok_or(())?;
+        let (field, value) = predicate.split_once('=').ok_or(())?;
+        let field = match field {
+            "pid" => Field::Pid,
+            "uid" => Field::Uid,
+            "gid" => Field::Gid,
+            _ => return Err(()),
+        };
+        if target.is_empty() {
+            return Err(());
+        }
+        Ok(Route { field, value: value.parse().map_err(|_| ())?, target: target.to_owned() })
+    }
+}
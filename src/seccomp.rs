@@ -0,0 +1,148 @@
+// --seccomp: confine the child to a baseline set of syscalls with a seccomp-bpf filter, installed
+// right before exec.
+//
+// Profiles:
+//
+//     wayland-client   what toolkits, Mesa and the usual libc machinery need for a GUI client
+//     no-network       wayland-client, but sockets can only be AF_UNIX
+//
+// Syscalls outside the profile fail with ENOSYS, which libc treats as an old kernel and usually
+// works around. The filter is inherited by everything the child starts and can't be lifted.
+// No list fits every client: a toolkit or driver that needs something not on it breaks in ways
+// that are hard to trace back to the filter, so try a client with the profile before relying on
+// it. Only x86_64 and aarch64 are supported.
+
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use libc::{c_long, sock_filter, sock_fprog};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    WaylandClient,
+    NoNetwork,
+}
+
+pub fn parse_profile(name: &str) -> Result<Profile, String> {
+    if AUDIT_ARCH == 0 {
+        return Err("--seccomp is only supported on x86_64 and aarch64".to_owned());
+    }
+    match name {
+        "wayland-client" => Ok(Profile::WaylandClient),
+        "no-network" => Ok(Profile::NoNetwork),
+        _ => Err(format!("unknown --seccomp profile {:?}, expected wayland-client or no-network", name)),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: u32 = 0;
+
+// offsets into struct seccomp_data
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+// low half of the first argument, we only run on little endian
+const DATA_ARG0: u32 = 16;
+
+const BASELINE: &[c_long] = &[
+    // io
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_preadv, libc::SYS_pwritev, libc::SYS_lseek,
+    libc::SYS_close, libc::SYS_close_range, libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2, libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_flock,
+    libc::SYS_splice, libc::SYS_copy_file_range, libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_ftruncate, libc::SYS_fallocate,
+    // files
+    libc::SYS_openat, libc::SYS_newfstatat, libc::SYS_fstat, libc::SYS_statx, libc::SYS_statfs, libc::SYS_fstatfs, libc::SYS_faccessat, libc::SYS_faccessat2,
+    libc::SYS_getdents64, libc::SYS_getcwd, libc::SYS_chdir, libc::SYS_fchdir, libc::SYS_readlinkat, libc::SYS_mkdirat, libc::SYS_unlinkat,
+    libc::SYS_renameat2, libc::SYS_linkat, libc::SYS_symlinkat, libc::SYS_fchmod, libc::SYS_fchmodat, libc::SYS_utimensat, libc::SYS_umask, libc::SYS_getxattr,
+    libc::SYS_fgetxattr, libc::SYS_lgetxattr, libc::SYS_inotify_init1, libc::SYS_inotify_add_watch, libc::SYS_inotify_rm_watch,
+    // memory
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_mremap, libc::SYS_madvise, libc::SYS_brk, libc::SYS_mincore, libc::SYS_mlock, libc::SYS_munlock,
+    libc::SYS_memfd_create, libc::SYS_membarrier, libc::SYS_get_mempolicy,
+    // event loops and timers
+    libc::SYS_ppoll, libc::SYS_pselect6, libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_epoll_pwait2, libc::SYS_eventfd2,
+    libc::SYS_timerfd_create, libc::SYS_timerfd_settime, libc::SYS_timerfd_gettime, libc::SYS_signalfd4, libc::SYS_nanosleep, libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime, libc::SYS_clock_getres, libc::SYS_gettimeofday, libc::SYS_setitimer, libc::SYS_getitimer, libc::SYS_times,
+    // sockets, the wayland connection and whatever else the client talks to
+    libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_bind, libc::SYS_listen, libc::SYS_accept4, libc::SYS_sendmsg, libc::SYS_recvmsg,
+    libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_shutdown, libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_getsockopt, libc::SYS_setsockopt,
+    // processes, threads and signals
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_execve, libc::SYS_execveat, libc::SYS_exit, libc::SYS_exit_group, libc::SYS_wait4, libc::SYS_waitid,
+    libc::SYS_kill, libc::SYS_tgkill, libc::SYS_tkill, libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_rt_sigtimedwait,
+    libc::SYS_sigaltstack, libc::SYS_restart_syscall, libc::SYS_futex, libc::SYS_futex_waitv, libc::SYS_set_tid_address, libc::SYS_set_robust_list,
+    libc::SYS_get_robust_list, libc::SYS_sched_yield, libc::SYS_sched_getaffinity, libc::SYS_sched_setaffinity, libc::SYS_sched_getparam,
+    libc::SYS_sched_getscheduler, libc::SYS_sched_get_priority_max, libc::SYS_sched_get_priority_min, libc::SYS_getpriority, libc::SYS_setpriority,
+    libc::SYS_prctl, libc::SYS_pidfd_open, libc::SYS_kcmp,
+    // identity and limits
+    libc::SYS_getpid, libc::SYS_gettid, libc::SYS_getppid, libc::SYS_getpgid, libc::SYS_getsid, libc::SYS_setsid, libc::SYS_setpgid, libc::SYS_getuid,
+    libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid, libc::SYS_getgroups, libc::SYS_getresuid, libc::SYS_getresgid, libc::SYS_capget, libc::SYS_uname,
+    libc::SYS_sysinfo, libc::SYS_prlimit64, libc::SYS_getrusage, libc::SYS_getrandom,
+];
+
+// the older variants only x86_64 still has, and a few that libc only names there
+#[cfg(target_arch = "x86_64")]
+const LEGACY: &[c_long] = &[
+    libc::SYS_fadvise64, libc::SYS_getrlimit, libc::SYS_setrlimit, libc::SYS_renameat, libc::SYS_sendfile,
+    libc::SYS_open, libc::SYS_stat, libc::SYS_lstat, libc::SYS_access, libc::SYS_poll, libc::SYS_select, libc::SYS_pipe, libc::SYS_dup2, libc::SYS_readlink,
+    libc::SYS_mkdir, libc::SYS_rmdir, libc::SYS_unlink, libc::SYS_rename, libc::SYS_link, libc::SYS_symlink, libc::SYS_chmod, libc::SYS_getdents,
+    libc::SYS_epoll_create, libc::SYS_epoll_wait, libc::SYS_eventfd, libc::SYS_inotify_init, libc::SYS_signalfd, libc::SYS_fork, libc::SYS_vfork,
+    libc::SYS_alarm, libc::SYS_pause, libc::SYS_time, libc::SYS_arch_prctl,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY: &[c_long] = &[];
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter { code: code as u16, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter { code: code as u16, jt, jf, k }
+}
+
+fn program(profile: Profile) -> Vec<sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS};
+
+    let load = |offset| stmt(BPF_LD | BPF_W | BPF_ABS, offset);
+    let ret = |action| stmt(BPF_RET | BPF_K, action);
+    let skip_unless = |value, skip| jump(BPF_JMP | BPF_JEQ | BPF_K, value, 0, skip);
+
+    // syscall numbers of other ABIs mean something else entirely
+    let mut filter = vec![load(DATA_ARCH), jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0), ret(SECCOMP_RET_KILL_PROCESS), load(DATA_NR)];
+    if profile == Profile::NoNetwork {
+        filter.extend([
+            skip_unless(libc::SYS_socket as u32, 4),
+            load(DATA_ARG0),
+            skip_unless(libc::AF_UNIX as u32, 1),
+            ret(SECCOMP_RET_ALLOW),
+            ret(SECCOMP_RET_ERRNO | libc::EAFNOSUPPORT as u32),
+        ]);
+    }
+    // one check per syscall, a jump to a shared allow would be too far for the 8 bit offsets
+    for &nr in BASELINE.iter().chain(LEGACY) {
+        filter.extend([skip_unless(nr as u32, 1), ret(SECCOMP_RET_ALLOW)]);
+    }
+    filter.push(ret(SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+    filter
+}
+
+/// Installs the filter for `profile` in the child, after the other pre_exec hooks since it may
+/// deny what they need.
+pub fn apply(cmd: &mut Command, profile: Profile) {
+    let filter = program(profile);
+
+    // SAFETY: prctl is async-signal-safe and the filter was built before the fork
+    unsafe {
+        cmd.pre_exec(move || {
+            let prog = sock_fprog { len: filter.len() as u16, filter: filter.as_ptr() as *mut sock_filter };
+            // required for unprivileged filters, and keeps setuid binaries from running unfiltered
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const sock_fprog) != 0 {
+                let error = std::io::Error::last_os_error();
+                let msg = b"p5wl: failed to install the --seccomp filter\n";
+                libc::write(2, msg.as_ptr().cast(), msg.len());
+                return Err(error);
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,341 @@
+// --self-test: push a known pattern of messages and fds through the proxy and back, stream a lot
+// of data both ways at once, then open and close lots of connections and check that the proxy
+// didn't leak any fds doing so.
+//
+// Both ends run on threads of this process: a fake compositor that echoes everything it receives,
+// fds included, and a client that sends the pattern and checks what comes back. The main thread
+// drives the same event loop as a real run in between. Since everything shares one fd table the
+// leak check compares the number of open fds before and after the churn, once every connection
+// is gone on all sides.
+
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustix::fd::{AsFd, OwnedFd};
+use rustix::fs::{memfd_create, MemfdFlags};
+use rustix::io::{pread, write};
+use rustix::net::{recvmsg, sendmsg, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer, SendAncillaryMessage, SendFlags, SocketType};
+
+use crate::listener::{Listener, Protocol};
+use crate::{wire, Options, Proxy, ProxyError, SystemClock};
+
+const MESSAGES: usize = 200;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How much the stress and churn clients do.
+struct Load {
+    // sent by the stress client while it reads the echo back on another thread, so both
+    // directions are busy at the same time
+    stress_bytes: usize,
+    // connections opened and closed again by the churn client, every third drops without sending
+    // anything, every third passes an fd
+    churn: usize,
+}
+
+const FULL: Load = Load { stress_bytes: 16 << 20, churn: 2000 };
+
+/// Runs the self-test, printing the result. Fails if anything came back different.
+pub fn self_test(opts: &Options) -> Result<(), ProxyError> {
+    let (bytes, fds) = in_temp_dir("self-test", |dir| run_in(dir, opts, &FULL))?;
+    println!("self-test passed: {} messages with {} bytes and {} fds made the round trip, {} MiB went both ways at once, {} connections came and went without leaking fds", MESSAGES, bytes, fds, FULL.stress_bytes >> 20, FULL.churn);
+    Ok(())
+}
+
+fn fail(reason: String) -> ProxyError {
+    ProxyError::SelfTest { reason }
+}
+
+/// Runs `f` with a fresh directory for the sockets, removed again afterwards.
+fn in_temp_dir<T>(name: &str, f: impl FnOnce(&Path) -> Result<T, ProxyError>) -> Result<T, ProxyError> {
+    let dir = std::env::temp_dir().join(format!("p5wl-{}-{}", name, std::process::id()));
+    std::fs::create_dir(&dir).map_err(|e| fail(format!("failed to create {}: {}", dir.display(), e)))?;
+    let result = f(&dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+/// The proxy in front of the fake compositor.
+struct Setup<'a> {
+    proxy: Proxy<'a>,
+    // where clients connect to the proxy
+    path: PathBuf,
+    // compositor side connections that are still open
+    open: Arc<AtomicUsize>,
+}
+
+/// Starts the fake compositor and a proxy for it with sockets in `dir`.
+fn start<'a>(dir: &Path, opts: &'a Options) -> Result<Setup<'a>, ProxyError> {
+    let compositor_path = dir.join("compositor");
+    let compositor = UnixListener::bind(&compositor_path).map_err(|e| fail(format!("failed to bind fake compositor: {}", e)))?;
+    let listener = Listener::bind(dir.join("proxy"), Some(compositor_path), Protocol::Wayland, SocketType::STREAM, false)?;
+    let path = listener.path.clone();
+
+    let open = Arc::new(AtomicUsize::new(0));
+    let compositor_open = Arc::clone(&open);
+    thread::Builder::new()
+        .name("compositor".to_owned())
+        .spawn(move || {
+            while let Ok((conn, _)) = compositor.accept() {
+                compositor_open.fetch_add(1, Ordering::SeqCst);
+                let open = Arc::clone(&compositor_open);
+                let echoing = thread::Builder::new().name("compositor conn".to_owned()).spawn(move || {
+                    echo(&conn);
+                    drop(conn);
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+                if echoing.is_err() {
+                    return;
+                }
+            }
+        })
+        .map_err(|e| fail(format!("failed to spawn fake compositor: {}", e)))?;
+
+    let proxy = Proxy::new(opts, vec![listener], Arc::new(SystemClock))?;
+    Ok(Setup { proxy, path, open })
+}
+
+/// Runs `client` on a thread of its own, driving the proxy until it's done.
+fn drive<T: Send + 'static>(proxy: &mut Proxy, name: &str, client: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, ProxyError> {
+    let client = thread::Builder::new().name(name.to_owned()).spawn(client).map_err(|e| fail(format!("failed to spawn {}: {}", name, e)))?;
+    let deadline = Instant::now() + TIMEOUT;
+    while !client.is_finished() {
+        if Instant::now() > deadline {
+            return Err(fail(format!("{} still running after {:?}", name, TIMEOUT)));
+        }
+        proxy.step(100, &[])?;
+    }
+    client.join().map_err(|_| fail(format!("{} panicked", name)))?.map_err(fail)
+}
+
+fn run_in(dir: &Path, opts: &Options, load: &Load) -> Result<(usize, usize), ProxyError> {
+    let Setup { mut proxy, path, open } = start(dir, opts)?;
+    let client_path = path.clone();
+    let result = drive(&mut proxy, "client", move || client(&client_path))?;
+
+    settle(&mut proxy, &open, "round trip")?;
+    let (stress_path, bytes) = (path.clone(), load.stress_bytes);
+    drive(&mut proxy, "stress client", move || stress(&stress_path, bytes))?;
+
+    let baseline = settle(&mut proxy, &open, "stress")?;
+    let connections = load.churn;
+    drive(&mut proxy, "churn client", move || churn(&path, connections))?;
+    let after = settle(&mut proxy, &open, "churn")?;
+    if after != baseline {
+        return Err(fail(format!("{} fds open after {} connections came and went, {} before", after, load.churn, baseline)));
+    }
+    Ok(result)
+}
+
+/// Runs the proxy until all connections are closed on both ends and returns the number of open
+/// fds then.
+fn settle(proxy: &mut Proxy, open: &AtomicUsize, phase: &str) -> Result<usize, ProxyError> {
+    let deadline = Instant::now() + TIMEOUT;
+    while !proxy.connections.is_empty() || open.load(Ordering::SeqCst) > 0 {
+        if Instant::now() > deadline {
+            return Err(fail(format!("{} connections still open {:?} after the {}", proxy.connections.len(), TIMEOUT, phase)));
+        }
+        proxy.step(10, &[])?;
+    }
+    std::fs::read_dir("/proc/self/fd").map(|fds| fds.count()).map_err(|e| fail(format!("failed to count open fds: {}", e)))
+}
+
+/// Message `i` of the pattern and the contents of the memfds sent along with it.
+fn message(i: usize) -> (Vec<u8>, Vec<String>) {
+    if i == 0 {
+        // a proper wl_display.get_registry so --strict-handshake is happy
+        let word = ((wire::HEADER_SIZE as u32 + 4) << 16) | wire::DISPLAY_GET_REGISTRY as u32;
+        let bytes = [wire::DISPLAY_ID, word, 2].iter().flat_map(|w| w.to_ne_bytes()).collect();
+        return (bytes, Vec::new());
+    }
+    let len = 8 + (i * 97) % 3000;
+    let bytes = (0..len).map(|j| (i * 31 + j * 7) as u8).collect();
+    let fds = if i.is_multiple_of(10) { (0..i % 3 + 1).map(|k| format!("fd {}.{}", i, k)).collect() } else { Vec::new() };
+    (bytes, fds)
+}
+
+fn client(path: &Path) -> Result<(usize, usize), String> {
+    let conn = UnixStream::connect(path).map_err(|e| format!("failed to connect to the proxy: {}", e))?;
+
+    let mut expected_bytes = Vec::new();
+    let mut expected_fds = Vec::new();
+    for i in 0..MESSAGES {
+        let (bytes, contents) = message(i);
+        let mut fds = Vec::new();
+        for content in &contents {
+            let fd = memfd_create("p5wl-self-test", MemfdFlags::CLOEXEC).map_err(|e| format!("memfd_create failed: {}", e))?;
+            write(&fd, content.as_bytes()).map_err(|e| format!("failed to fill memfd: {}", e))?;
+            fds.push(fd);
+        }
+        send_all(&conn, &bytes, &fds).map_err(|e| format!("failed to send message {}: {}", i, e))?;
+        expected_bytes.extend_from_slice(&bytes);
+        expected_fds.extend(contents);
+    }
+
+    let mut received = Vec::new();
+    let mut received_fds = Vec::new();
+    while received.len() < expected_bytes.len() {
+        let (bytes, fds) = recv(&conn).map_err(|e| format!("failed to receive: {}", e))?;
+        if bytes.is_empty() {
+            return Err(format!("connection closed after {} of {} bytes", received.len(), expected_bytes.len()));
+        }
+        received.extend_from_slice(&bytes);
+        received_fds.extend(fds);
+    }
+
+    if let Some(pos) = received.iter().zip(&expected_bytes).position(|(a, b)| a != b) {
+        return Err(format!("byte {} differs", pos));
+    }
+    if received.len() != expected_bytes.len() {
+        return Err(format!("got {} bytes, expected {}", received.len(), expected_bytes.len()));
+    }
+    if received_fds.len() != expected_fds.len() {
+        return Err(format!("got {} fds, expected {}", received_fds.len(), expected_fds.len()));
+    }
+    for (fd, expected) in received_fds.iter().zip(&expected_fds) {
+        let mut buf = [0u8; 64];
+        let n = pread(fd, &mut buf, 0).map_err(|e| format!("failed to read received fd: {}", e))?;
+        if &buf[..n] != expected.as_bytes() {
+            return Err(format!("fd for {:?} came back as {:?}", expected, String::from_utf8_lossy(&buf[..n])));
+        }
+    }
+
+    Ok((received.len(), received_fds.len()))
+}
+
+/// Sends `bytes` from one thread while another reads them back, and checks they arrived in order.
+fn stress(path: &Path, bytes: usize) -> Result<(), String> {
+    let pattern = |i: usize| (i % 251) as u8;
+    let conn = UnixStream::connect(path).map_err(|e| format!("stress client failed to connect: {}", e))?;
+    let sending = conn.try_clone().map_err(|e| format!("failed to clone stress connection: {}", e))?;
+    let sender = thread::Builder::new()
+        .name("stress sender".to_owned())
+        .spawn(move || {
+            let (request, _) = message(0);
+            let chunk: Vec<u8> = (0..64 * 1024).map(pattern).collect();
+            let chunks = std::iter::once(&request).chain(std::iter::repeat_n(&chunk, bytes / chunk.len()));
+            for (i, bytes) in chunks.enumerate() {
+                send_all(&sending, bytes, &[]).map_err(|e| format!("stress client failed to send chunk {}: {}", i, e))?;
+            }
+            Ok(())
+        })
+        .map_err(|e| format!("failed to spawn stress sender: {}", e))?;
+
+    let (request, _) = message(0);
+    let mut received = 0;
+    while received < request.len() + bytes {
+        let (chunk, _) = recv(&conn).map_err(|e| format!("stress client failed to receive: {}", e))?;
+        if chunk.is_empty() {
+            return Err(format!("stress connection closed after {} of {} bytes", received, request.len() + bytes));
+        }
+        for (i, &byte) in chunk.iter().enumerate() {
+            let pos = received + i;
+            let expected = if pos < request.len() { request[pos] } else { pattern((pos - request.len()) % (64 * 1024)) };
+            if byte != expected {
+                return Err(format!("stress byte {} differs", pos));
+            }
+        }
+        received += chunk.len();
+    }
+    sender.join().map_err(|_| "stress sender panicked".to_owned())?
+}
+
+/// Opens and closes `connections` connections, two thirds of them closing down in an orderly
+/// fashion after sending the initial request and maybe an fd.
+fn churn(path: &Path, connections: usize) -> Result<(), String> {
+    let (request, _) = message(0);
+    for i in 0..connections {
+        let conn = UnixStream::connect(path).map_err(|e| format!("churn connection {} failed to connect: {}", i, e))?;
+        if i.is_multiple_of(3) {
+            continue;
+        }
+        let mut fds = Vec::new();
+        if i % 3 == 2 {
+            fds.push(memfd_create("p5wl-self-test", MemfdFlags::CLOEXEC).map_err(|e| format!("memfd_create failed: {}", e))?);
+        }
+        send_all(&conn, &request, &fds).map_err(|e| format!("churn connection {} failed to send: {}", i, e))?;
+        // waiting for the proxy to close its end means nothing it still sends us hits a closed socket
+        conn.shutdown(Shutdown::Write).map_err(|e| format!("churn connection {} failed to shut down: {}", i, e))?;
+        while matches!(recv(&conn), Ok((bytes, _)) if !bytes.is_empty()) {}
+    }
+    Ok(())
+}
+
+fn echo(conn: &UnixStream) {
+    loop {
+        match recv(conn) {
+            Ok((bytes, fds)) if !bytes.is_empty() => {
+                if send_all(conn, &bytes, &fds).is_err() {
+                    return;
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+fn recv(conn: &UnixStream) -> rustix::io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+    let mut buf = [0u8; 4096];
+    let mut space = [0; rustix::cmsg_space!(ScmRights(crate::fds::SCM_MAX_FD))];
+    let mut cmsg = RecvAncillaryBuffer::new(&mut space);
+    let msg = rustix::io::retry_on_intr(|| recvmsg(conn, &mut [IoSliceMut::new(&mut buf)], &mut cmsg, RecvFlags::CMSG_CLOEXEC))?;
+    let mut fds = Vec::new();
+    for m in cmsg.drain() {
+        if let RecvAncillaryMessage::ScmRights(rights) = m {
+            fds.extend(rights);
+        }
+    }
+    Ok((buf[..msg.bytes].to_vec(), fds))
+}
+
+/// Blocking send of all of `bytes`, with `fds` attached to the first byte.
+fn send_all(conn: &UnixStream, mut bytes: &[u8], fds: &[OwnedFd]) -> rustix::io::Result<()> {
+    let borrowed: Vec<_> = fds.iter().map(|fd| fd.as_fd()).collect();
+    let mut space = [0; rustix::cmsg_space!(ScmRights(crate::fds::SCM_MAX_FD))];
+    let mut cmsg = SendAncillaryBuffer::new(&mut space);
+    cmsg.push(SendAncillaryMessage::ScmRights(&borrowed));
+    while !bytes.is_empty() {
+        let sent = rustix::io::retry_on_intr(|| sendmsg(conn, &[IoSlice::new(bytes)], &mut cmsg, SendFlags::empty()))?;
+        bytes = &bytes[sent..];
+        // the fds went out with the first chunk
+        cmsg.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fraction of --self-test's, still filling the socket buffers many times over
+    const LOAD: Load = Load { stress_bytes: 2 << 20, churn: 200 };
+
+    #[test]
+    fn stress_and_churn() {
+        // the leak check counts every open fd of the process
+        let _turn = crate::tests::serial();
+        let opts = Options::default();
+        let result = in_temp_dir("stress-test", |dir| run_in(dir, &opts, &LOAD));
+        assert_eq!(result.map(|_| ()).map_err(|e| e.to_string()), Ok(()));
+    }
+
+    #[test]
+    fn round_trip() {
+        let _turn = crate::tests::serial();
+        let expected = (0..MESSAGES).map(message).fold((0, 0), |(bytes, fds), (msg, contents)| (bytes + msg.len(), fds + contents.len()));
+        // the first message of the pattern passes the handshake check
+        let opts = Options { strict_handshake: true, ..Options::default() };
+        let result = in_temp_dir("round-trip-test", |dir| {
+            let Setup { mut proxy, path, open } = start(dir, &opts)?;
+            let result = drive(&mut proxy, "client", move || client(&path))?;
+            settle(&mut proxy, &open, "round trip")?;
+            Ok(result)
+        });
+        assert_eq!(result.map_err(|e| e.to_string()), Ok(expected));
+    }
+}
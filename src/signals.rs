@@ -0,0 +1,61 @@
+// SIGHUP through a signalfd so the event loop can see it: dumps the stats and reopens
+// --log-file, the usual convention for daemons whose logs get rotated externally.
+//
+// SIGPIPE is ignored, a peer that went away has to show up as EPIPE and close its connection
+// rather than kill the proxy.
+
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::io::{read, Errno};
+
+use crate::ProxyError;
+
+pub struct Sighup(OwnedFd);
+
+impl Sighup {
+    /// Blocks SIGHUP for the calling thread and threads spawned from it afterwards. Has to happen
+    /// after the child was spawned, it would inherit the signal mask.
+    pub fn install() -> Result<Sighup, ProxyError> {
+        // SAFETY: the set is initialized by sigemptyset before use, the returned fd is new and ours
+        let fd = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGHUP);
+            let errno = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            if errno != 0 {
+                return Err(ProxyError::Signal { errno: Errno::from_raw_os_error(errno) });
+            }
+            libc::signalfd(-1, &set, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK)
+        };
+        if fd < 0 {
+            return Err(ProxyError::Signal { errno: Errno::from_io_error(&std::io::Error::last_os_error()).unwrap_or(Errno::IO) });
+        }
+        // SAFETY: checked above
+        Ok(Sighup(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+    }
+
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+
+    /// Consumes the pending signals, returns whether there were any.
+    pub fn received(&self) -> bool {
+        let mut info = [0u8; std::mem::size_of::<libc::signalfd_siginfo>()];
+        let mut received = false;
+        while matches!(read(&self.0, &mut info), Ok(n) if n == info.len()) {
+            received = true;
+        }
+        received
+    }
+}
+
+/// Ignores SIGPIPE for the whole process. Rust binaries start out like this already, this makes
+/// it hold for programs that run the proxy and changed it. Children get the default back from
+/// std::process::Command.
+pub fn ignore_sigpipe() {
+    // SAFETY: SIG_IGN installs no handler code
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+}
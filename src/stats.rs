@@ -0,0 +1,192 @@
+// Counters for understanding where backpressure happens, dumped on exit with --stats.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::metrics::Histogram;
+use crate::{protocol, CloseReason, Direction, Options};
+
+#[derive(Default)]
+pub struct DirectionStats {
+    /// everything received for this direction, whether it was sent directly or queued
+    pub bytes: u64,
+    /// sendmsg accepted only part of a chunk, the rest got queued
+    pub partial_sends: u64,
+    /// sendmsg would have blocked and the whole chunk got queued (or requeued)
+    pub wouldblock_requeues: u64,
+    /// queued chunks stored compressed with --compress-queue, and how much memory that saved
+    pub compressed_messages: u64,
+    pub compression_saved_bytes: u64,
+    /// chunks of input events queued ahead of earlier ones with --reorder-safe-priority
+    pub reordered: u64,
+    /// how long chunks took through the proxy, with --latency-histogram
+    pub latency: Option<Histogram>,
+    /// --check-protocol violations seen in messages of this direction
+    pub violations: u64,
+    /// --count-messages, by interface (if the object is known) and opcode
+    pub messages: HashMap<(Option<Rc<str>>, u16), u64>,
+    /// what the filtering and rewriting options did, for --filter-summary
+    pub filtered: HashMap<Filtered, u64>,
+}
+
+/// Something a filtering or rewriting option did to a message. Rules are identified by their
+/// index among the options of the same kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Filtered {
+    /// a wl_registry.global version lowered by a --cap-global
+    CappedVersion(usize),
+    /// an --inject-global advertised on a registry
+    InjectedGlobal(usize),
+    /// a bind to an --inject-global, dropped
+    DroppedBind(usize),
+    /// a request on a bound --inject-global, which closes the connection
+    InjectedRequest,
+    /// a global whose interface isn't on the --allow-global list
+    DisallowedGlobal,
+    /// a wl_seat global other than the first, with --single-seat
+    HiddenSeat,
+    /// a first message that isn't a wl_display request, with --strict-handshake
+    RejectedHandshake,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub to_parent: DirectionStats,
+    pub to_child: DirectionStats,
+    /// closed connections by why they were closed
+    pub closes: HashMap<CloseReason, u64>,
+    /// clients accepted over the proxy's lifetime, for --max-lifetime-connections
+    pub accepted: u64,
+    /// clients turned away by --max-connections-per-uid
+    pub rejected: u64,
+    /// clients turned away while the compositor was unreachable, with --on-parent-outage reject
+    pub rejected_outage: u64,
+    /// clients turned away because connecting them to the compositor failed otherwise
+    pub parent_connect_failed: u64,
+    /// wall-clock time Proxy::step spent blocked in poll and doing everything else. Processing
+    /// taking a large share means the proxy is CPU bound
+    pub polling: Duration,
+    pub processing: Duration,
+    /// loop iterations --spin-limit slowed down
+    pub spin_sleeps: u64,
+}
+
+impl Stats {
+    pub fn new(opts: &Options) -> Stats {
+        let mut stats = Stats::default();
+        if opts.latency_histogram {
+            stats.to_parent.latency = Some(Histogram::new(&opts.latency_buckets));
+            stats.to_child.latency = Some(Histogram::new(&opts.latency_buckets));
+        }
+        stats
+    }
+
+    pub fn direction(&mut self, dir: Direction) -> &mut DirectionStats {
+        match dir {
+            Direction::ToParent => &mut self.to_parent,
+            Direction::ToChild => &mut self.to_child,
+        }
+    }
+
+    pub fn dump(&self) {
+        log!("stats:");
+        for (name, d) in [("to parent", &self.to_parent), ("to child", &self.to_child)] {
+            log!("  {}: {} bytes, {} partial sends, {} wouldblock requeues", name, d.bytes, d.partial_sends, d.wouldblock_requeues);
+            if d.compressed_messages > 0 {
+                log!("  {}: {} chunks compressed, saving {} bytes", name, d.compressed_messages, d.compression_saved_bytes);
+            }
+            if d.reordered > 0 {
+                log!("  {}: {} chunks of input events moved ahead", name, d.reordered);
+            }
+            if d.violations > 0 {
+                log!("  {}: {} --check-protocol violations", name, d.violations);
+            }
+        }
+        log!("  accepted: {} connections", self.accepted);
+        for (reason, count) in self.closes() {
+            log!("  closed by {}: {}", reason, count);
+        }
+        if self.rejected > 0 {
+            log!("  rejected by --max-connections-per-uid: {}", self.rejected);
+        }
+        if self.rejected_outage > 0 {
+            log!("  rejected while the compositor was unreachable: {}", self.rejected_outage);
+        }
+        if self.parent_connect_failed > 0 {
+            log!("  rejected because connecting to the compositor failed: {}", self.parent_connect_failed);
+        }
+        if self.spin_sleeps > 0 {
+            log!("  iterations slowed down by --spin-limit: {}", self.spin_sleeps);
+        }
+        log!("  time: {:.3?} polling, {:.3?} processing ({:.1}% busy)", self.polling, self.processing, self.busy() * 100.0);
+    }
+
+    /// The share of processing in the time spent in Proxy::step.
+    fn busy(&self) -> f64 {
+        let total = (self.polling + self.processing).as_secs_f64();
+        if total == 0.0 { 0.0 } else { self.processing.as_secs_f64() / total }
+    }
+
+    /// Close counts, in the order the reasons are declared.
+    pub fn closes(&self) -> impl Iterator<Item = (CloseReason, u64)> {
+        let mut closes: Vec<_> = self.closes.iter().map(|(&reason, &count)| (reason, count)).collect();
+        closes.sort();
+        closes.into_iter()
+    }
+
+    /// The --count-messages table, most frequent first.
+    pub fn dump_messages(&self) {
+        for (title, dir) in [("requests", Direction::ToParent), ("events", Direction::ToChild)] {
+            let mut counts: Vec<(String, u64)> = self.to_direction(dir).messages.iter().map(|((interface, opcode), &count)| (message_name(interface.as_deref(), dir, *opcode), count)).collect();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            log!("{}:", title);
+            for (name, count) in counts {
+                log!("  {}: {}", name, count);
+            }
+        }
+    }
+
+    /// The --filter-summary, with every rule listed so one that never matched stands out.
+    pub fn dump_filters(&self, opts: &Options) {
+        let count = |filtered| [&self.to_parent, &self.to_child].iter().filter_map(|d| d.filtered.get(&filtered)).sum::<u64>();
+        log!("filter summary:");
+        for (i, (interface, version)) in opts.cap_globals.iter().enumerate() {
+            log!("  --cap-global {}={}: {} advertisements capped", interface, version, count(Filtered::CappedVersion(i)));
+        }
+        for (i, (interface, version)) in opts.inject_globals.iter().enumerate() {
+            log!("  --inject-global {}={}: advertised {} times, {} binds dropped", interface, version, count(Filtered::InjectedGlobal(i)), count(Filtered::DroppedBind(i)));
+        }
+        if !opts.inject_globals.is_empty() {
+            log!("  requests on injected globals: {}", count(Filtered::InjectedRequest));
+        }
+        if !opts.allow_globals.is_empty() {
+            log!("  --allow-global {}: {} globals hidden", opts.allow_globals.join(","), count(Filtered::DisallowedGlobal));
+        }
+        if opts.single_seat {
+            log!("  --single-seat: {} seats hidden", count(Filtered::HiddenSeat));
+        }
+        if opts.strict_handshake {
+            log!("  --strict-handshake: {} connections rejected", count(Filtered::RejectedHandshake));
+        }
+        log!("  {} connections closed by filters", self.closes.get(&CloseReason::Filtered).copied().unwrap_or(0));
+    }
+
+    fn to_direction(&self, dir: Direction) -> &DirectionStats {
+        match dir {
+            Direction::ToParent => &self.to_parent,
+            Direction::ToChild => &self.to_child,
+        }
+    }
+}
+
+/// `interface.message`, or the opcode where we don't know the name.
+fn message_name(interface: Option<&str>, dir: Direction, opcode: u16) -> String {
+    let Some(interface) = interface else {
+        return format!("<unknown object>#{}", opcode);
+    };
+    match protocol::interface(interface).and_then(|i| i.message(dir, opcode)) {
+        Some(message) => format!("{}.{}", interface, message.name),
+        None => format!("{}#{}", interface, opcode),
+    }
+}
@@ -0,0 +1,95 @@
+// --status-fd: tells a supervisor where the proxy socket is once it accepts connections, as a
+// single JSON line written to an fd it passed us, which is closed afterwards.
+//
+//     {"wayland_display":"wayland-wrap-123","socket":"/run/user/1000/wayland-wrap-123","pid":123,"child_pid":124}
+//
+// `child_pid` is missing with --no-child, `x11_display` and `control_socket` are only present when
+// enabled. The socket is always a path, never in the abstract namespace.
+
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+
+use rustix::fd::OwnedFd;
+use rustix::io::{fcntl_getfd, fcntl_setfd, retry_on_intr, write, Errno, FdFlags};
+
+use crate::ProxyError;
+
+pub struct StatusFd(OwnedFd);
+
+pub struct Status<'a> {
+    pub wayland_display: &'a str,
+    pub socket: &'a Path,
+    pub pid: u32,
+    pub child_pid: Option<u32>,
+    pub x11_display: Option<&'a str>,
+    pub control_socket: Option<&'a Path>,
+}
+
+/// Takes over an fd inherited for `option`, before anything is spawned so the child doesn't get
+/// it too. `err` reports failing to set it up.
+pub fn adopt_fd(fd: RawFd, option: &str, err: impl Fn(Errno) -> ProxyError) -> Result<OwnedFd, ProxyError> {
+    let invalid = || ProxyError::Parse { context: format!("{} {} is not an open fd", option, fd) };
+    if fd < 0 {
+        return Err(invalid());
+    }
+    // SAFETY: checked below before we do anything with it, the caller handed it to us
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+    if fcntl_getfd(&owned).is_err() {
+        // not ours to close
+        std::mem::forget(owned);
+        return Err(invalid());
+    }
+    fcntl_setfd(&owned, FdFlags::CLOEXEC).map_err(err)?;
+    Ok(owned)
+}
+
+impl StatusFd {
+    pub fn adopt(fd: RawFd) -> Result<StatusFd, ProxyError> {
+        adopt_fd(fd, "--status-fd", |errno| ProxyError::Status { errno }).map(StatusFd)
+    }
+
+    /// Writes the status line and closes the fd.
+    pub fn report(self, status: &Status<'_>) -> Result<(), ProxyError> {
+        let line = status.to_json() + "\n";
+        let mut remaining = line.as_bytes();
+        while !remaining.is_empty() {
+            match retry_on_intr(|| write(&self.0, remaining)) {
+                Ok(0) => return Err(ProxyError::Status { errno: Errno::PIPE }),
+                Ok(n) => remaining = &remaining[n..],
+                Err(errno) => return Err(ProxyError::Status { errno }),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Status<'_> {
+    fn to_json(&self) -> String {
+        let mut json = format!("{{\"wayland_display\":{},\"socket\":{},\"pid\":{}", json_string(self.wayland_display), json_string(&self.socket.to_string_lossy()), self.pid);
+        if let Some(pid) = self.child_pid {
+            json += &format!(",\"child_pid\":{}", pid);
+        }
+        if let Some(display) = self.x11_display {
+            json += &format!(",\"x11_display\":{}", json_string(display));
+        }
+        if let Some(path) = self.control_socket {
+            json += &format!(",\"control_socket\":{}", json_string(&path.to_string_lossy()));
+        }
+        json + "}"
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,38 @@
+// --syslog: connection open/close events as RFC 3164 messages on the local /dev/log socket.
+//
+// Messages omit the timestamp and hostname, the local syslog daemon fills those in.
+
+use std::mem;
+
+use rustix::fd::OwnedFd;
+use rustix::net::{connect_unix, send, socket_with, AddressFamily, SendFlags, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::ProxyError;
+
+const DEV_LOG: &str = "/dev/log";
+// facility daemon (3), severity info (6)
+const PRIORITY: u32 = 3 << 3 | 6;
+
+pub struct Syslog {
+    socket: OwnedFd,
+    failed: bool,
+}
+
+impl Syslog {
+    pub fn connect() -> Result<Syslog, ProxyError> {
+        let err = |errno| ProxyError::ConnectLocal { context: DEV_LOG.to_owned(), errno };
+        let socket = socket_with(AddressFamily::UNIX, SocketType::DGRAM, SocketFlags::CLOEXEC, None).map_err(err)?;
+        connect_unix(&socket, &SocketAddrUnix::new(DEV_LOG).map_err(err)?).map_err(err)?;
+        Ok(Syslog { socket, failed: false })
+    }
+
+    pub fn log(&mut self, msg: &str) {
+        let line = format!("<{}>p5wl[{}]: {}", PRIORITY, std::process::id(), msg);
+        // never block the event loop on a slow syslog daemon, the message is lost instead
+        if let Err(errno) = send(&self.socket, line.as_bytes(), SendFlags::DONTWAIT | SendFlags::NOSIGNAL) {
+            if !mem::replace(&mut self.failed, true) {
+                log!("warning: failed to write to {}: {}, dropping messages", DEV_LOG, errno);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+// --trace: log every wayland message in a WAYLAND_DEBUG like format, optionally limited by
+// --trace-filter to some interfaces or object ids.
+//
+// With --trace-socket the lines go to the consumers of that socket instead of the log.
+//
+// --hexdump: log every forwarded chunk as it goes out, as offset, hex bytes and ASCII, for when
+// the parsing itself is in doubt. Only the first --hexdump-limit bytes of a chunk are shown.
+
+use std::str::FromStr;
+
+use crate::protocol::{self, Message};
+use crate::wire::{self, Header, HEADER_SIZE};
+use crate::{tracesocket, Direction};
+
+/// One comma separated entry of --trace-filter: an interface name or object id, `!` excludes it.
+#[derive(Clone)]
+pub struct Filter {
+    exclude: bool,
+    target: Target,
+}
+
+#[derive(Clone)]
+enum Target {
+    Interface(String),
+    Object(u32),
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Filter, ()> {
+        let (exclude, name) = match s.strip_prefix('!') {
+            Some(name) => (true, name),
+            None => (false, s),
+        };
+        let target = match name.parse() {
+            Ok(id) => Target::Object(id),
+            Err(_) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => Target::Interface(name.to_owned()),
+            Err(_) => return Err(()),
+        };
+        Ok(Filter { exclude, target })
+    }
+}
+
+impl Filter {
+    fn matches(&self, object: u32, interface: Option<&str>) -> bool {
+        match &self.target {
+            Target::Interface(name) => interface == Some(name.as_str()),
+            Target::Object(id) => *id == object,
+        }
+    }
+}
+
+/// Whether a message passes the filters: no exclusion may match, and if there are any inclusions
+/// one of them has to.
+pub fn wanted(filters: &[Filter], object: u32, interface: Option<&str>) -> bool {
+    let mut included = filters.iter().filter(|f| !f.exclude).peekable();
+    let include = included.peek().is_none() || included.any(|f| f.matches(object, interface));
+    include && !filters.iter().any(|f| f.exclude && f.matches(object, interface))
+}
+
+pub fn log(conn: u64, dir: Direction, header: Header, interface: Option<&str>, msg: &[u8]) {
+    let arrow = match dir {
+        Direction::ToParent => "->",
+        Direction::ToChild => "<-",
+    };
+    let interface_name = interface.unwrap_or("<unknown>");
+    let message = interface.and_then(protocol::interface).and_then(|i| i.message(dir, header.opcode));
+    let line = match message {
+        Some(message) => format!("trace conn {} {} {}@{}.{}({})", conn, arrow, interface_name, header.object, message.name, format_args(message, msg)),
+        None => format!("trace conn {} {} {}@{}#{}({} bytes)", conn, arrow, interface_name, header.object, header.opcode, msg.len() - HEADER_SIZE),
+    };
+    if !tracesocket::send(&line) {
+        log!("{}", line);
+    }
+}
+
+const HEXDUMP_WIDTH: usize = 16;
+
+pub fn hexdump(conn: u64, dir: Direction, bytes: &[u8], fds: usize, limit: usize) {
+    log!("hexdump conn {} {}->{}: {} bytes, {} fds", conn, dir.source(), dir.sink(), bytes.len(), fds);
+    let shown = &bytes[..bytes.len().min(limit)];
+    for (line, chunk) in shown.chunks(HEXDUMP_WIDTH).enumerate() {
+        let mut hex = String::with_capacity(HEXDUMP_WIDTH * 3 + 1);
+        for (i, byte) in chunk.iter().enumerate() {
+            // an extra space between the two halves, like hexdump -C
+            if i == HEXDUMP_WIDTH / 2 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let ascii: String = chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        log!("  {:04x}  {:<49} |{}|", line * HEXDUMP_WIDTH, hex, ascii);
+    }
+    if shown.len() < bytes.len() {
+        log!("  ... {} more bytes (--hexdump-limit)", bytes.len() - shown.len());
+    }
+}
+
+fn format_args(message: &Message, msg: &[u8]) -> String {
+    let mut args = Vec::new();
+    let mut offset = HEADER_SIZE;
+    for arg in message.signature.bytes() {
+        if arg == b'h' {
+            // passed as ancillary data, not in the message body
+            args.push("fd".to_owned());
+            continue;
+        }
+        let (formatted, next) = match arg {
+            b's' => wire::arg_string(msg, offset).map(|(s, next)| (format!("{:?}", String::from_utf8_lossy(s)), next)),
+            b'a' => wire::arg_string(msg, offset).map(|(_, next)| (format!("array[{}]", wire::arg_u32(msg, offset).unwrap_or(0)), next)),
+            _ => wire::arg_u32(msg, offset).map(|v| {
+                let formatted = match arg {
+                    b'i' => (v as i32).to_string(),
+                    // 24.8 fixed point
+                    b'f' => format!("{:.2}", v as i32 as f64 / 256.0),
+                    b'o' if v == 0 => "nil".to_owned(),
+                    b'n' => format!("new id {}", v),
+                    _ => v.to_string(),
+                };
+                (formatted, offset + 4)
+            }),
+        }
+        .unwrap_or_else(|| ("<truncated>".to_owned(), msg.len()));
+        args.push(formatted);
+        offset = next;
+    }
+    args.join(", ")
+}
@@ -0,0 +1,152 @@
+// --trace-socket: stream the --trace output to whoever is connected to a unix socket, e.g. a protocol
+// inspector, instead of logging it. Every consumer gets the same lines, without the --log-prefix,
+// from the moment it connects.
+//
+// Tracing must never hold up the proxy. Lines are collected while connections are processed and
+// written out without blocking at the end of each loop iteration. A consumer that doesn't keep up
+// gets up to MAX_BUFFERED bytes buffered, lines beyond that are dropped and counted, and a
+// `# N trace lines dropped` line marks the gap once it catches up. Without any consumer the lines
+// are discarded. At most MAX_CONSUMERS are served at once.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rustix::event::PollFlags;
+use rustix::fd::{AsFd, BorrowedFd, OwnedFd};
+use rustix::fs::unlink;
+use rustix::io::{read, retry_on_intr, write, Errno};
+use rustix::net::{accept_with, SocketFlags};
+
+use crate::control::listen_unix;
+use crate::ProxyError;
+
+const MAX_BUFFERED: usize = 1 << 20;
+const MAX_CONSUMERS: usize = 4;
+
+/// Lines traced since the last flush, `None` without a trace socket.
+static PENDING: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Queues a trace line for the trace socket, returns false if there is none and it should be
+/// logged instead.
+pub fn send(line: &str) -> bool {
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(pending) = &mut *pending else {
+        return false;
+    };
+    pending.extend_from_slice(line.as_bytes());
+    pending.push(b'\n');
+    true
+}
+
+pub struct TraceSocket {
+    socket: OwnedFd,
+    path: PathBuf,
+    consumers: Vec<Consumer>,
+}
+
+struct Consumer {
+    fd: OwnedFd,
+    buffered: Vec<u8>,
+    // lines dropped since the last gap marker
+    dropped: u64,
+    dropped_total: u64,
+}
+
+impl TraceSocket {
+    pub fn bind(path: &Path) -> Result<TraceSocket, ProxyError> {
+        let socket = listen_unix(path, "trace socket")?;
+        *PENDING.lock().unwrap_or_else(|e| e.into_inner()) = Some(Vec::new());
+        Ok(TraceSocket { socket, path: path.to_owned(), consumers: Vec::new() })
+    }
+
+    /// The listening socket followed by one entry per consumer. Consumers are only read from to
+    /// notice them hanging up.
+    pub fn poll_fds(&self) -> impl Iterator<Item = (BorrowedFd<'_>, PollFlags)> {
+        let consumers = self.consumers.iter().map(|c| (c.fd.as_fd(), if c.buffered.is_empty() { PollFlags::IN } else { PollFlags::IN | PollFlags::OUT }));
+        std::iter::once((self.socket.as_fd(), PollFlags::IN)).chain(consumers)
+    }
+
+    /// Accepts consumers and drops those that hung up, `flags` are the revents for
+    /// [`TraceSocket::poll_fds`].
+    pub fn handle(&mut self, flags: &[PollFlags]) {
+        let mut buf = [0u8; 256];
+        let mut closed = Vec::new();
+        for (i, (consumer, flags)) in self.consumers.iter_mut().zip(&flags[1..]).enumerate() {
+            // whatever a consumer sends is ignored
+            let eof = flags.contains(PollFlags::IN) && !matches!(retry_on_intr(|| read(&consumer.fd, &mut buf)), Ok(1..) | Err(Errno::AGAIN));
+            if eof || flags.intersects(PollFlags::HUP | PollFlags::ERR) || (flags.contains(PollFlags::OUT) && !consumer.write()) {
+                closed.push(i);
+            }
+        }
+        for i in closed.into_iter().rev() {
+            let consumer = self.consumers.remove(i);
+            if consumer.dropped_total > 0 {
+                log!("trace socket consumer disconnected, {} trace lines were dropped for it", consumer.dropped_total);
+            }
+        }
+
+        if flags[0].contains(PollFlags::IN) {
+            while let Ok(fd) = accept_with(&self.socket, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK) {
+                if self.consumers.len() >= MAX_CONSUMERS {
+                    log!("warning: more than {} --trace-socket consumers, closing new one", MAX_CONSUMERS);
+                    continue;
+                }
+                self.consumers.push(Consumer { fd, buffered: Vec::new(), dropped: 0, dropped_total: 0 });
+            }
+        }
+    }
+
+    /// Hands the lines traced since the last call to the consumers, for the end of a loop
+    /// iteration.
+    pub fn flush(&mut self) {
+        let lines = std::mem::take(PENDING.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(Vec::new));
+        if lines.is_empty() {
+            return;
+        }
+        self.consumers.retain_mut(|consumer| {
+            consumer.queue(&lines);
+            consumer.write()
+        });
+    }
+}
+
+// unlinks the socket like a Listener does
+impl Drop for TraceSocket {
+    fn drop(&mut self) {
+        *PENDING.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        if let Err(e) = unlink(&self.path) {
+            log!("warning: failed to unlink {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Consumer {
+    fn queue(&mut self, lines: &[u8]) {
+        for line in lines.split_inclusive(|&b| b == b'\n') {
+            let marker = match self.dropped {
+                0 => String::new(),
+                n => format!("# {} trace lines dropped\n", n),
+            };
+            if self.buffered.len() + marker.len() + line.len() > MAX_BUFFERED {
+                self.dropped += 1;
+                self.dropped_total += 1;
+                continue;
+            }
+            self.buffered.extend_from_slice(marker.as_bytes());
+            self.buffered.extend_from_slice(line);
+            self.dropped = 0;
+        }
+    }
+
+    /// Writes as much as the socket takes, returns false if the consumer is gone.
+    fn write(&mut self) -> bool {
+        while !self.buffered.is_empty() {
+            match retry_on_intr(|| write(&self.fd, &self.buffered)) {
+                Ok(n) => drop(self.buffered.drain(..n)),
+                Err(Errno::AGAIN) => return true,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
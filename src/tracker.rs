@@ -0,0 +1,415 @@
+// Message level processing for options that need to look inside the wayland protocol.
+//
+// Without such options the proxy forwards whatever bytes it receives. With them each direction is
+// split into whole messages first: the tail of a message that hasn't fully arrived yet is held
+// back until the rest does, together with any fds received in the meantime, so every forwarded
+// chunk starts and ends on a message boundary and can be rewritten in place.
+//
+// Object creation is followed using the signatures in the protocol module, so every object id
+// known to the tracker maps to its interface name. Objects of interfaces we have no signatures for
+// are still tracked when they're bound through the registry, but objects they create aren't.
+// Objects bound through the registry get the version the client asked for, the objects they
+// create inherit it like they do in libwayland.
+//
+// --inject-global advertises extra globals on every registry, right after the compositor's first
+// global. They get names counting down from u32::MAX, far away from the small numbers compositors
+// hand out. Binds to them are logged and not forwarded since the compositor would treat them as a
+// protocol error. The client can't actually use the object, a request on it closes the connection.
+//
+// --single-seat lets every registry advertise only the first wl_seat global, the others and
+// their removal are dropped. Once the advertised one is removed the next new seat takes its
+// place. A bind to a hidden seat closes the connection, the client could only know its name by
+// guessing.
+//
+// --allow-global turns the registry into an allowlist: only globals of the listed interfaces are
+// advertised, hidden ones are handled like hidden seats. Nothing is allowed implicitly, a client
+// won't get far without wl_compositor, wl_shm and usually xdg_wm_base, which have to be listed
+// too. --inject-global advertisements are never hidden.
+//
+// --check-protocol looks at every message before any of the above, see the check module.
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+
+use crate::check::Checks;
+use crate::fds::{InFlightFds, SCM_MAX_FD};
+use crate::stats::{DirectionStats, Filtered};
+use crate::wire::{self, Header, DISPLAY_DELETE_ID, DISPLAY_ID, HEADER_SIZE, REGISTRY_BIND, REGISTRY_GLOBAL, REGISTRY_GLOBAL_REMOVE, SERVER_ID_START};
+use crate::{protocol, trace, CloseReason, Direction, Options};
+
+pub struct Tracker {
+    // connection id for --trace
+    conn: u64,
+    to_parent: Held,
+    to_child: Held,
+    // every live object
+    objects: HashMap<u32, Object>,
+    // registries that haven't been sent the --inject-global advertisements yet
+    uninjected: HashSet<u32>,
+    // objects the client bound injected globals as
+    injected: HashSet<u32>,
+    // --single-seat and --allow-global state by registry
+    registries: HashMap<u32, Registry>,
+    // only with --check-protocol
+    checks: Option<Checks>,
+}
+
+/// The globals hidden from one registry.
+#[derive(Default)]
+struct Registry {
+    // name of the wl_seat advertised with --single-seat
+    seat: Option<u32>,
+    // interface of every hidden global by name
+    hidden: HashMap<u32, Rc<str>>,
+}
+
+struct Object {
+    interface: Rc<str>,
+    version: u32,
+}
+
+/// What to do with an inspected message.
+enum Action {
+    Forward,
+    Drop,
+    /// forward the message followed by these bytes
+    Append(Vec<u8>),
+    /// drop the message and forward these bytes instead
+    Replace(Vec<u8>),
+}
+
+#[derive(Default)]
+struct Held {
+    bytes: Vec<u8>,
+    fds: InFlightFds,
+}
+
+impl Tracker {
+    /// A tracker if any of the enabled options needs one.
+    pub fn new(conn: u64, opts: &Options) -> Option<Tracker> {
+        let needed = opts.message_framed || !opts.cap_globals.is_empty() || !opts.inject_globals.is_empty() || opts.max_message_size.is_some() || opts.max_inflight_messages.is_some() || opts.experimental_reconnect || opts.record_events.is_some() || opts.count_messages || opts.trace || opts.reorder_safe_priority || opts.single_seat || !opts.allow_globals.is_empty() || opts.check_protocol;
+        needed.then(|| Tracker {
+            conn,
+            to_parent: Held::default(),
+            to_child: Held::default(),
+            objects: HashMap::from([(DISPLAY_ID, Object { interface: Rc::from("wl_display"), version: 1 })]),
+            uninjected: HashSet::new(),
+            injected: HashSet::new(),
+            registries: HashMap::new(),
+            checks: opts.check_protocol.then(Checks::default),
+        })
+    }
+
+    /// Takes the next received chunk and returns the whole messages that can be forwarded now,
+    /// possibly none. `fds` is replaced with the fds that go with them. An error means the
+    /// connection has to be closed.
+    pub fn process(&mut self, dir: Direction, bytes: &[u8], fds: &mut InFlightFds, opts: &Options, stats: &mut DirectionStats) -> Result<Vec<u8>, (CloseReason, String)> {
+        let held = match dir {
+            Direction::ToParent => &mut self.to_parent,
+            Direction::ToChild => &mut self.to_child,
+        };
+
+        let mut data = mem::take(&mut held.bytes);
+        data.extend_from_slice(bytes);
+        let max_size = opts.max_message_size.unwrap_or(usize::MAX);
+        let complete = wire::complete_prefix(&data, max_size).map_err(|header| match header.size as usize > max_size {
+            true => (CloseReason::LimitExceeded, format!("message of {} bytes exceeds --max-message-size {}: {:?}", header.size, max_size, header)),
+            false => (CloseReason::Filtered, format!("malformed message {:?}", header)),
+        })?;
+        held.bytes = data.split_off(complete);
+
+        if data.is_empty() {
+            held.fds.append(fds);
+        } else {
+            // fds that arrived with an incomplete message go out with its first bytes
+            let mut ready = mem::take(&mut held.fds);
+            ready.append(fds);
+            *fds = ready;
+        }
+        if held.fds.len().max(fds.len()) > SCM_MAX_FD {
+            return Err((CloseReason::LimitExceeded, format!("more than {} fds for one message", SCM_MAX_FD)));
+        }
+
+        // only copied once a message is dropped or added
+        let mut edited: Option<Vec<u8>> = None;
+        let mut offset = 0;
+        while let Some(header) = Header::parse(&data[offset..]) {
+            let size = header.size as usize;
+            let action = self.inspect(dir, header, &mut data[offset..][..size], opts, stats)?;
+            let msg = &data[offset..][..size];
+            match action {
+                Action::Forward => {
+                    if let Some(edited) = &mut edited {
+                        edited.extend_from_slice(msg);
+                    }
+                }
+                Action::Drop => {
+                    edited.get_or_insert_with(|| data[..offset].to_vec());
+                }
+                Action::Append(extra) => {
+                    let edited = edited.get_or_insert_with(|| data[..offset].to_vec());
+                    edited.extend_from_slice(msg);
+                    edited.extend_from_slice(&extra);
+                }
+                Action::Replace(replacement) => {
+                    edited.get_or_insert_with(|| data[..offset].to_vec()).extend_from_slice(&replacement);
+                }
+            }
+            offset += size;
+        }
+
+        Ok(edited.unwrap_or(data))
+    }
+
+    /// Gives up on the fds held back with incomplete messages, for closing the connection.
+    pub fn discard_fds(&mut self) {
+        self.to_parent.fds.discard();
+        self.to_child.fds.discard();
+    }
+
+    /// The interface of a live object, if it's known.
+    pub fn interface(&self, id: u32) -> Option<&str> {
+        self.objects.get(&id).map(|object| &*object.interface)
+    }
+
+    /// Id, interface and version of every live object, by id.
+    pub fn objects(&self) -> Vec<(u32, &str, u32)> {
+        let mut objects: Vec<_> = self.objects.iter().map(|(&id, object)| (id, &*object.interface, object.version)).collect();
+        objects.sort_unstable_by_key(|&(id, ..)| id);
+        objects
+    }
+
+    /// A live object the compositor created, if there is one. Replaying the client's requests
+    /// can't recreate it on a new compositor.
+    pub fn compositor_object(&self) -> Option<(u32, &str)> {
+        self.objects.iter().filter(|(&id, _)| id >= SERVER_ID_START).map(|(&id, object)| (id, &*object.interface)).min()
+    }
+
+    /// How many fds the signature of an event says it carries.
+    pub fn event_fds(&self, header: Header) -> Option<usize> {
+        let interface = &self.objects.get(&header.object)?.interface;
+        let message = protocol::interface(interface)?.message(Direction::ToChild, header.opcode)?;
+        Some(message.signature.bytes().filter(|&arg| arg == b'h').count())
+    }
+
+    /// Reports what --check-protocol was still waiting for, for closing the connection.
+    pub fn closed(&self, reason: Option<CloseReason>, stats: &mut DirectionStats) {
+        if let Some(checks) = &self.checks {
+            checks.closed(self.conn, reason, stats);
+        }
+    }
+
+    /// Forgets the incomplete message of a compositor that went away, for --experimental-reconnect.
+    pub fn parent_lost(&mut self) {
+        self.to_child.bytes.clear();
+        self.to_child.fds.discard();
+    }
+
+    fn inspect(&mut self, dir: Direction, header: Header, msg: &mut [u8], opts: &Options, stats: &mut DirectionStats) -> Result<Action, (CloseReason, String)> {
+        let (interface, version) = self.objects.get(&header.object).map(|object| (object.interface.clone(), object.version)).unzip();
+        if opts.count_messages {
+            *stats.messages.entry((interface.clone(), header.opcode)).or_default() += 1;
+        }
+        if let Some(checks) = &mut self.checks {
+            checks.inspect(self.conn, dir, header, interface.as_deref(), msg, stats);
+        }
+        let Some(interface) = interface else {
+            if opts.trace && trace::wanted(&opts.trace_filter, header.object, None) {
+                trace::log(self.conn, dir, header, None, msg);
+            }
+            return Ok(Action::Forward);
+        };
+        if dir == Direction::ToParent && self.injected.contains(&header.object) {
+            *stats.filtered.entry(Filtered::InjectedRequest).or_default() += 1;
+            return Err((CloseReason::Filtered, format!("request #{} on {}@{}, which is an --inject-global the compositor doesn't know", header.opcode, interface, header.object)));
+        }
+
+        let mut action = Action::Forward;
+        match (dir, &*interface, header.opcode) {
+            (Direction::ToChild, "wl_display", DISPLAY_DELETE_ID) => {
+                if let Some(id) = wire::arg_u32(msg, HEADER_SIZE) {
+                    self.objects.remove(&id);
+                }
+            }
+            (Direction::ToChild, "wl_registry", REGISTRY_GLOBAL_REMOVE) if opts.single_seat || !opts.allow_globals.is_empty() => {
+                let name = wire::arg_u32(msg, HEADER_SIZE);
+                let registry = self.registries.entry(header.object).or_default();
+                if name.is_some_and(|name| registry.hidden.remove(&name).is_some()) {
+                    action = Action::Drop;
+                } else if name.is_some() && registry.seat == name {
+                    registry.seat = None;
+                }
+            }
+            (Direction::ToChild, "wl_registry", REGISTRY_GLOBAL) => {
+                if wire::arg_u32(msg, HEADER_SIZE).and_then(|name| injected_global(name, opts)).is_some() {
+                    log!("warning: conn {}: the compositor advertised a global with the same name as an --inject-global", self.conn);
+                }
+                let hidden = self.hide_global(header.object, msg, opts);
+                if let Some(filtered) = hidden {
+                    *stats.filtered.entry(filtered).or_default() += 1;
+                } else if let Some(rule) = cap_global(msg, opts) {
+                    *stats.filtered.entry(Filtered::CappedVersion(rule)).or_default() += 1;
+                }
+                let mut injected = None;
+                if self.uninjected.remove(&header.object) {
+                    for i in 0..opts.inject_globals.len() {
+                        *stats.filtered.entry(Filtered::InjectedGlobal(i)).or_default() += 1;
+                    }
+                    let events = opts.inject_globals.iter().enumerate().flat_map(|(i, (name, version))| wire::global_event(header.object, INJECTED_NAMES_END - i as u32, name, *version));
+                    injected = Some(events.collect());
+                }
+                action = match (hidden, injected) {
+                    (None, None) => Action::Forward,
+                    (None, Some(events)) => Action::Append(events),
+                    (Some(_), None) => Action::Drop,
+                    (Some(_), Some(events)) => Action::Replace(events),
+                };
+            }
+            (Direction::ToParent, "wl_registry", REGISTRY_BIND) => {
+                let name = wire::arg_u32(msg, HEADER_SIZE);
+                if let Some((name, hidden)) = name.and_then(|name| Some((name, self.registries.get(&header.object)?.hidden.get(&name)?))) {
+                    return Err((CloseReason::Filtered, format!("bind to {} global {}, which isn't advertised (--single-seat, --allow-global)", hidden, name)));
+                }
+                if let Some((rule, interface, version)) = wire::arg_u32(msg, HEADER_SIZE).and_then(|name| injected_global(name, opts)) {
+                    *stats.filtered.entry(Filtered::DroppedBind(rule)).or_default() += 1;
+                    // the new_id follows the string and version the client passed
+                    let id = wire::arg_string(msg, HEADER_SIZE + 4).and_then(|(_, offset)| wire::arg_u32(msg, offset + 4)).unwrap_or(0);
+                    log!("conn {}: client bound injected global {} version {} as object {}, not forwarded", self.conn, interface, version, id);
+                    self.injected.insert(id);
+                    action = Action::Drop;
+                }
+            }
+            _ => {}
+        }
+        if opts.trace && trace::wanted(&opts.trace_filter, header.object, Some(&interface)) {
+            trace::log(self.conn, dir, header, Some(&interface), msg);
+        }
+
+        let message = protocol::interface(&interface).and_then(|interface| interface.message(dir, header.opcode));
+        if let Some((id, created, bound_version)) = message.and_then(|message| message.created_object(msg)) {
+            let created: Rc<str> = Rc::from(String::from_utf8_lossy(created));
+            let version = bound_version.or(version).unwrap_or(1);
+            if &*created == "wl_registry" && !opts.inject_globals.is_empty() {
+                self.uninjected.insert(id);
+            }
+            self.objects.insert(id, Object { interface: created, version });
+        }
+        Ok(action)
+    }
+
+    /// Whether a wl_registry.global event has to be dropped for --allow-global or --single-seat
+    /// and which one, keeps track of what the registry was shown.
+    fn hide_global(&mut self, registry: u32, msg: &[u8], opts: &Options) -> Option<Filtered> {
+        if opts.allow_globals.is_empty() && !opts.single_seat {
+            return None;
+        }
+        let name = wire::arg_u32(msg, HEADER_SIZE)?;
+        let (interface, _) = wire::arg_string(msg, HEADER_SIZE + 4)?;
+        let registry = self.registries.entry(registry).or_default();
+        let filtered = if !opts.allow_globals.is_empty() && !opts.allow_globals.iter().any(|allowed| allowed.as_bytes() == interface) {
+            Filtered::DisallowedGlobal
+        } else if opts.single_seat && interface == b"wl_seat" && registry.seat.is_some() {
+            Filtered::HiddenSeat
+        } else {
+            if opts.single_seat && interface == b"wl_seat" {
+                registry.seat = Some(name);
+            }
+            return None;
+        };
+        registry.hidden.insert(name, Rc::from(String::from_utf8_lossy(interface)));
+        Some(filtered)
+    }
+}
+
+// name of the first --inject-global, the others count down from here
+const INJECTED_NAMES_END: u32 = u32::MAX;
+
+/// The index and --inject-global advertised under `name`, if it's one of ours.
+fn injected_global(name: u32, opts: &Options) -> Option<(usize, &str, u32)> {
+    let index = (INJECTED_NAMES_END - name) as usize;
+    opts.inject_globals.get(index).map(|(interface, version)| (index, interface.as_str(), *version))
+}
+
+/// Lowers the version of a `wl_registry.global` event to the --cap-global limit for its interface,
+/// returns the index of the rule if it did.
+fn cap_global(msg: &mut [u8], opts: &Options) -> Option<usize> {
+    let (interface, version_offset) = wire::arg_string(msg, HEADER_SIZE + 4)?;
+    let rule = opts.cap_globals.iter().position(|(name, _)| name.as_bytes() == interface)?;
+    let cap = opts.cap_globals[rule].1;
+    match wire::arg_u32(msg, version_offset) {
+        Some(version) if version > cap => {
+            msg[version_offset..][..4].copy_from_slice(&cap.to_ne_bytes());
+            Some(rule)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::wire::DISPLAY_GET_REGISTRY;
+
+    const REGISTRY: u32 = 2;
+
+    fn message(object: u32, opcode: u16, args: &[u8]) -> Vec<u8> {
+        let size = (HEADER_SIZE + args.len()) as u32;
+        [&object.to_ne_bytes()[..], &(size << 16 | opcode as u32).to_ne_bytes(), args].concat()
+    }
+
+    fn bind(name: u32, interface: &str, version: u32, id: u32) -> Vec<u8> {
+        let mut string = interface.as_bytes().to_vec();
+        string.resize((interface.len() + 1).next_multiple_of(4), 0);
+        let args = [&name.to_ne_bytes()[..], &(interface.len() as u32 + 1).to_ne_bytes(), &string, &version.to_ne_bytes(), &id.to_ne_bytes()].concat();
+        message(REGISTRY, REGISTRY_BIND, &args)
+    }
+
+    /// A tracker for a client that just got its registry.
+    fn tracker(opts: &Options) -> Tracker {
+        let mut tracker = Tracker::new(1, opts).expect("tracker");
+        let get_registry = message(DISPLAY_ID, DISPLAY_GET_REGISTRY, &REGISTRY.to_ne_bytes());
+        assert_eq!(process(&mut tracker, Direction::ToParent, &get_registry, opts, &mut DirectionStats::default()), Ok(get_registry));
+        tracker
+    }
+
+    fn process(tracker: &mut Tracker, dir: Direction, bytes: &[u8], opts: &Options, stats: &mut DirectionStats) -> Result<Vec<u8>, CloseReason> {
+        tracker.process(dir, bytes, &mut InFlightFds::default(), opts, stats).map_err(|(reason, _)| reason)
+    }
+
+    #[test]
+    fn allow_global() {
+        let opts = Options { allow_globals: vec!["wl_compositor".to_owned()], ..Options::default() };
+        let mut tracker = tracker(&opts);
+        let mut stats = DirectionStats::default();
+
+        let allowed = wire::global_event(REGISTRY, 1, "wl_compositor", 6);
+        assert_eq!(process(&mut tracker, Direction::ToChild, &allowed, &opts, &mut stats), Ok(allowed.clone()));
+        let disallowed = wire::global_event(REGISTRY, 2, "wl_shm", 1);
+        assert_eq!(process(&mut tracker, Direction::ToChild, &disallowed, &opts, &mut stats), Ok(Vec::new()));
+        // only the hidden one is left out of a chunk with both
+        let both = [&disallowed[..], &allowed].concat();
+        assert_eq!(process(&mut tracker, Direction::ToChild, &both, &opts, &mut stats), Ok(allowed));
+        assert_eq!(stats.filtered.get(&Filtered::DisallowedGlobal), Some(&2));
+
+        let bind_allowed = bind(1, "wl_compositor", 6, 3);
+        assert_eq!(process(&mut tracker, Direction::ToParent, &bind_allowed, &opts, &mut stats), Ok(bind_allowed));
+        assert_eq!(tracker.interface(3), Some("wl_compositor"));
+        assert_eq!(process(&mut tracker, Direction::ToParent, &bind(2, "wl_shm", 1, 4), &opts, &mut stats), Err(CloseReason::Filtered));
+        assert_eq!(tracker.interface(4), None);
+    }
+
+    #[test]
+    fn allow_global_removal() {
+        let opts = Options { allow_globals: vec!["wl_compositor".to_owned()], ..Options::default() };
+        let mut tracker = tracker(&opts);
+        let mut stats = DirectionStats::default();
+        assert_eq!(process(&mut tracker, Direction::ToChild, &wire::global_event(REGISTRY, 2, "wl_shm", 1), &opts, &mut stats), Ok(Vec::new()));
+        // the client never saw it, so it doesn't see it go either
+        let remove = message(REGISTRY, REGISTRY_GLOBAL_REMOVE, &2u32.to_ne_bytes());
+        assert_eq!(process(&mut tracker, Direction::ToChild, &remove, &opts, &mut stats), Ok(Vec::new()));
+        let remove = message(REGISTRY, REGISTRY_GLOBAL_REMOVE, &1u32.to_ne_bytes());
+        assert_eq!(process(&mut tracker, Direction::ToChild, &remove, &opts, &mut stats), Ok(remove));
+    }
+}
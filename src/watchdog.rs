@@ -0,0 +1,93 @@
+// --watchdog: a thread that notices when the main loop stops making progress.
+//
+// The main loop reports when it goes to sleep in poll and when it wakes up again. If it spends
+// longer than the timeout between waking up and the next poll the watchdog prints what the loop
+// was doing and the connection states from just before it got stuck.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Clock;
+
+pub struct Watchdog {
+    state: Arc<Mutex<State>>,
+    clock: Arc<dyn Clock>,
+}
+
+struct State {
+    /// when the main loop last woke up from poll, `None` while it's waiting in poll
+    awake_since: Option<Instant>,
+    /// what the main loop is busy with, and on which connection
+    stage: &'static str,
+    conn: Option<u64>,
+    /// connection states as of the last poll
+    snapshot: Vec<String>,
+    reported: bool,
+}
+
+impl Watchdog {
+    pub fn spawn(timeout: Duration, abort: bool, clock: Arc<dyn Clock>) -> Watchdog {
+        let state = Arc::new(Mutex::new(State { awake_since: Some(clock.now()), stage: "startup", conn: None, snapshot: Vec::new(), reported: false }));
+        let (shared, watch_clock) = (Arc::clone(&state), Arc::clone(&clock));
+        thread::Builder::new()
+            .name("watchdog".to_owned())
+            .spawn(move || watch(&shared, timeout, abort, &*watch_clock))
+            .expect("failed to spawn watchdog thread");
+        Watchdog { state, clock }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // a panic on the main thread ends the process anyway, the state is still fine for a dump
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn sleeping(&self, snapshot: Vec<String>) {
+        let mut state = self.lock();
+        state.awake_since = None;
+        state.snapshot = snapshot;
+    }
+
+    pub fn woke_up(&self) {
+        let mut state = self.lock();
+        state.awake_since = Some(self.clock.now());
+        state.stage = "woke up";
+        state.conn = None;
+        state.reported = false;
+    }
+
+    pub fn stage(&self, stage: &'static str, conn: Option<u64>) {
+        let mut state = self.lock();
+        state.stage = stage;
+        state.conn = conn;
+    }
+}
+
+fn watch(state: &Mutex<State>, timeout: Duration, abort: bool, clock: &dyn Clock) {
+    loop {
+        // not the clock's sleep, a MockClock would move along with the watchdog
+        thread::sleep((timeout / 4).max(Duration::from_millis(10)));
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(awake_since) = state.awake_since else {
+            continue;
+        };
+        let stuck_for = clock.now().duration_since(awake_since);
+        if stuck_for < timeout || state.reported {
+            continue;
+        }
+
+        // only once per iteration, otherwise a long hang floods the log
+        state.reported = true;
+        match state.conn {
+            Some(id) => log!("watchdog: main loop stuck for {:.1?} in {} on conn {}", stuck_for, state.stage, id),
+            None => log!("watchdog: main loop stuck for {:.1?} in {}", stuck_for, state.stage),
+        }
+        log!("watchdog: {} connections before the last poll:", state.snapshot.len());
+        for line in &state.snapshot {
+            log!("  {}", line);
+        }
+        if abort {
+            std::process::abort();
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Minimal parsing of the wayland wire format.
+//
+// Every message starts with an 8 byte header: the target object id, followed by a word
+// holding the total message size (including the header) in the upper 16 bits and the opcode
+// in the lower 16 bits. Both are in host byte order.
+
+pub const HEADER_SIZE: usize = 8;
+
+// wl_display is always object 1, its requests are sync (0) and get_registry (1)
+pub const DISPLAY_ID: u32 = 1;
+pub const DISPLAY_SYNC: u16 = 0;
+pub const DISPLAY_GET_REGISTRY: u16 = 1;
+// wl_display.delete_id event, tells the client an object id can be reused
+pub const DISPLAY_DELETE_ID: u16 = 1;
+// ids from here on are allocated by the compositor, for objects it creates in events
+pub const SERVER_ID_START: u32 = 0xff00_0000;
+// wl_registry.global(name: uint, interface: string, version: uint) event
+pub const REGISTRY_GLOBAL: u16 = 0;
+// wl_registry.global_remove(name: uint) event
+pub const REGISTRY_GLOBAL_REMOVE: u16 = 1;
+// wl_registry.bind(name: uint, id: new_id) request, the untyped new_id is sent as interface
+// string, version and id
+pub const REGISTRY_BIND: u16 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub object: u32,
+    pub opcode: u16,
+    pub size: u16,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> Option<Header> {
+        let bytes = bytes.get(..HEADER_SIZE)?;
+        let object = u32::from_ne_bytes(bytes[0..4].try_into().unwrap());
+        let word = u32::from_ne_bytes(bytes[4..8].try_into().unwrap());
+        Some(Header { object, opcode: word as u16, size: (word >> 16) as u16 })
+    }
+}
+
+/// Length of the longest prefix of `bytes` that consists of whole messages, given that `bytes`
+/// starts at a message boundary. Fails with the header of a message with an impossible size or
+/// one larger than `max_size`, even if it hasn't fully arrived yet.
+pub fn complete_prefix(bytes: &[u8], max_size: usize) -> Result<usize, Header> {
+    let mut offset = 0;
+    while let Some(header) = Header::parse(&bytes[offset..]) {
+        let size = header.size as usize;
+        if size < HEADER_SIZE || !size.is_multiple_of(4) || size > max_size {
+            return Err(header);
+        }
+        if offset + size > bytes.len() {
+            break;
+        }
+        offset += size;
+    }
+    Ok(offset)
+}
+
+/// Number of messages in `bytes`, which consists of whole messages, that haven't been completely
+/// sent with the first `sent` bytes.
+pub fn unsent_messages(bytes: &[u8], sent: usize) -> usize {
+    let mut offset = 0;
+    let mut unsent = 0;
+    while let Some(header) = Header::parse(&bytes[offset..]) {
+        offset += (header.size as usize).max(HEADER_SIZE);
+        if offset > sent {
+            unsent += 1;
+        }
+    }
+    unsent
+}
+
+/// Reads the `uint`/`object`/`new_id` argument at byte `offset` of a message.
+pub fn arg_u32(msg: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(msg.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+/// Reads the `string` argument at byte `offset` of a message, returns it without the trailing
+/// NUL and the offset of the next argument.
+pub fn arg_string(msg: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    // the length includes the NUL, the contents are padded to 32 bits
+    let len = arg_u32(msg, offset)? as usize;
+    let start = offset + 4;
+    let string = msg.get(start..start + len)?;
+    Some((string.strip_suffix(&[0]).unwrap_or(string), start + len.next_multiple_of(4)))
+}
+
+/// Encodes a `wl_registry.global` event for `registry`.
+pub fn global_event(registry: u32, name: u32, interface: &str, version: u32) -> Vec<u8> {
+    let string_len = interface.len() + 1;
+    let size = HEADER_SIZE + 4 + 4 + string_len.next_multiple_of(4) + 4;
+    let mut msg = Vec::with_capacity(size);
+    msg.extend_from_slice(&registry.to_ne_bytes());
+    msg.extend_from_slice(&((size as u32) << 16 | REGISTRY_GLOBAL as u32).to_ne_bytes());
+    msg.extend_from_slice(&name.to_ne_bytes());
+    msg.extend_from_slice(&(string_len as u32).to_ne_bytes());
+    msg.extend_from_slice(interface.as_bytes());
+    // NUL and padding
+    msg.resize(size - 4, 0);
+    msg.extend_from_slice(&version.to_ne_bytes());
+    msg
+}
+
+pub enum Handshake {
+    Incomplete,
+    Valid,
+    Invalid(Header),
+}
+
+/// Checks that the first message a client sends is a `wl_display.sync` or `wl_display.get_registry`
+/// request, which is what every libwayland client starts with.
+#[derive(Default)]
+pub struct HandshakeCheck {
+    header: [u8; HEADER_SIZE],
+    len: usize,
+}
+
+impl HandshakeCheck {
+    /// Feed the next chunk of client->server bytes. The header may be split across several chunks.
+    pub fn feed(&mut self, bytes: &[u8]) -> Handshake {
+        let take = bytes.len().min(HEADER_SIZE - self.len);
+        self.header[self.len..][..take].copy_from_slice(&bytes[..take]);
+        self.len += take;
+
+        let Some(header) = Header::parse(&self.header[..self.len]) else {
+            return Handshake::Incomplete;
+        };
+
+        // both requests carry a single new_id argument
+        let valid = header.object == DISPLAY_ID
+            && (header.opcode == DISPLAY_SYNC || header.opcode == DISPLAY_GET_REGISTRY)
+            && header.size as usize == HEADER_SIZE + 4;
+
+        if valid {
+            Handshake::Valid
+        } else {
+            Handshake::Invalid(header)
+        }
+    }
+}